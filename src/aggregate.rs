@@ -0,0 +1,278 @@
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+// GROUP BY is done in userspace: bpftrace sends every event and the rows are
+// folded into one per group here. Only the groups that changed get sent on.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+impl Aggregate {
+    pub fn from_name(name: &str) -> Option<Aggregate> {
+        match name.to_lowercase().as_str() {
+            "count" => Some(Aggregate::Count),
+            "sum" => Some(Aggregate::Sum),
+            "min" => Some(Aggregate::Min),
+            "max" => Some(Aggregate::Max),
+            "avg" => Some(Aggregate::Avg),
+            _ => None,
+        }
+    }
+}
+
+/// A column of an aggregated result.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AggregateColumn {
+    /// One of the GROUP BY keys, by position.
+    Key(usize),
+    /// An aggregate of one of the columns bpftrace sends back, count(*)
+    /// doesn't need one.
+    Aggregate(Aggregate, Option<usize>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupBy {
+    /// The columns bpftrace sends back that make up the key.
+    pub keys: Vec<usize>,
+    pub columns: Vec<AggregateColumn>,
+}
+
+#[derive(Debug, Clone)]
+enum State {
+    Count(u64),
+    Sum(i128, f64),
+    Min(Option<f64>, Value),
+    Max(Option<f64>, Value),
+    Avg(f64, u64),
+}
+
+impl State {
+    fn new(aggregate: Aggregate) -> State {
+        match aggregate {
+            Aggregate::Count => State::Count(0),
+            Aggregate::Sum => State::Sum(0, 0.0),
+            Aggregate::Min => State::Min(None, Value::Null),
+            Aggregate::Max => State::Max(None, Value::Null),
+            Aggregate::Avg => State::Avg(0.0, 0),
+        }
+    }
+
+    // nulls don't count towards anything, same as sql
+    fn add(&mut self, v: Option<&Value>) {
+        let v = match v {
+            Some(Value::Null) => return,
+            Some(v) => v,
+            None => {
+                // count(*)
+                if let State::Count(n) = self {
+                    *n += 1;
+                }
+                return;
+            }
+        };
+        let f = v.as_f64();
+        match self {
+            State::Count(n) => *n += 1,
+            State::Sum(int, float) => match (v.as_i64(), v.as_u64()) {
+                (Some(i), _) => *int += i as i128,
+                (_, Some(u)) => *int += u as i128,
+                _ => *float += f.unwrap_or(0.0),
+            },
+            State::Min(min, value) => {
+                if let Some(f) = f {
+                    if min.is_none_or(|m| f < m) {
+                        *min = Some(f);
+                        *value = v.clone();
+                    }
+                }
+            }
+            State::Max(max, value) => {
+                if let Some(f) = f {
+                    if max.is_none_or(|m| f > m) {
+                        *max = Some(f);
+                        *value = v.clone();
+                    }
+                }
+            }
+            State::Avg(sum, n) => {
+                if let Some(f) = f {
+                    *sum += f;
+                    *n += 1;
+                }
+            }
+        }
+    }
+
+    fn value(&self) -> Value {
+        match self {
+            State::Count(n) => Value::from(*n),
+            State::Sum(int, float) if *float == 0.0 => match i64::try_from(*int) {
+                Ok(i) => Value::from(i),
+                Err(_) => Value::from(*int as f64),
+            },
+            State::Sum(int, float) => Value::from(*int as f64 + float),
+            State::Min(_, v) | State::Max(_, v) => v.clone(),
+            State::Avg(_, 0) => Value::Null,
+            State::Avg(sum, n) => Value::from(sum / *n as f64),
+        }
+    }
+}
+
+struct Group {
+    id: u64,
+    keys: Vec<Value>,
+    states: Vec<Option<State>>,
+}
+
+/// The groups seen so far for a GROUP BY query.
+pub struct Groups {
+    group_by: GroupBy,
+    groups: HashMap<String, Group>,
+    changed: HashSet<String>,
+}
+
+impl Groups {
+    pub fn new(group_by: GroupBy) -> Groups {
+        Groups {
+            group_by,
+            groups: HashMap::new(),
+            changed: HashSet::new(),
+        }
+    }
+
+    /// Folds a row from bpftrace into its group.
+    pub fn add(&mut self, row: &[Value]) {
+        let keys = self
+            .group_by
+            .keys
+            .iter()
+            .map(|i| row.get(*i).cloned().unwrap_or(Value::Null))
+            .collect::<Vec<Value>>();
+        let k = Value::Array(keys.clone()).to_string();
+        let next_id = self.groups.len() as u64 + 1;
+        let columns = &self.group_by.columns;
+        let group = self.groups.entry(k.clone()).or_insert_with(|| Group {
+            id: next_id,
+            keys,
+            states: columns
+                .iter()
+                .map(|c| match c {
+                    AggregateColumn::Key(_) => None,
+                    AggregateColumn::Aggregate(a, _) => Some(State::new(*a)),
+                })
+                .collect(),
+        });
+        for (column, state) in columns.iter().zip(group.states.iter_mut()) {
+            if let (AggregateColumn::Aggregate(_, i), Some(state)) = (column, state) {
+                state.add(i.map(|i| row.get(i).unwrap_or(&Value::Null)));
+            }
+        }
+        self.changed.insert(k);
+    }
+
+    /// The rows for the groups that changed since last time, id first.
+    pub fn changed_rows(&mut self) -> Vec<Vec<Value>> {
+        let mut rows = vec![];
+        for k in self.changed.drain() {
+            let group = &self.groups[&k];
+            let mut row = vec![Value::from(group.id)];
+            for (column, state) in self.group_by.columns.iter().zip(&group.states) {
+                row.push(match (column, state) {
+                    (AggregateColumn::Key(i), _) => group.keys[*i].clone(),
+                    (_, Some(state)) => state.value(),
+                    _ => Value::Null,
+                });
+            }
+            rows.push(row);
+        }
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sorted(mut rows: Vec<Vec<Value>>) -> Vec<Vec<Value>> {
+        rows.sort_by_key(|r| r[0].as_u64());
+        rows
+    }
+
+    #[test]
+    fn aggregates_by_name() {
+        assert_eq!(Aggregate::from_name("COUNT"), Some(Aggregate::Count));
+        assert_eq!(Aggregate::from_name("avg"), Some(Aggregate::Avg));
+        assert_eq!(Aggregate::from_name("median"), None);
+    }
+
+    #[test]
+    fn folds_rows_into_groups() {
+        // SELECT comm, count(*), sum(n), min(n), max(n), avg(n) ... GROUP BY comm
+        let mut groups = Groups::new(GroupBy {
+            keys: vec![0],
+            columns: vec![
+                AggregateColumn::Key(0),
+                AggregateColumn::Aggregate(Aggregate::Count, None),
+                AggregateColumn::Aggregate(Aggregate::Sum, Some(1)),
+                AggregateColumn::Aggregate(Aggregate::Min, Some(1)),
+                AggregateColumn::Aggregate(Aggregate::Max, Some(1)),
+                AggregateColumn::Aggregate(Aggregate::Avg, Some(1)),
+            ],
+        });
+        groups.add(&[json!("bash"), json!(4)]);
+        groups.add(&[json!("sshd"), json!(1)]);
+        groups.add(&[json!("bash"), json!(2)]);
+        assert_eq!(
+            sorted(groups.changed_rows()),
+            vec![
+                vec![json!(1), json!("bash"), json!(2), json!(6), json!(2), json!(4), json!(3.0)],
+                vec![json!(2), json!("sshd"), json!(1), json!(1), json!(1), json!(1), json!(1.0)],
+            ]
+        );
+
+        // only what changed is sent again, keeping its id
+        groups.add(&[json!("sshd"), json!(5)]);
+        assert_eq!(
+            groups.changed_rows(),
+            vec![vec![json!(2), json!("sshd"), json!(2), json!(6), json!(1), json!(5), json!(3.0)]]
+        );
+        assert!(groups.changed_rows().is_empty());
+    }
+
+    #[test]
+    fn nulls_are_left_out() {
+        let mut groups = Groups::new(GroupBy {
+            keys: vec![],
+            columns: vec![
+                AggregateColumn::Aggregate(Aggregate::Count, None),
+                AggregateColumn::Aggregate(Aggregate::Count, Some(0)),
+                AggregateColumn::Aggregate(Aggregate::Avg, Some(0)),
+                AggregateColumn::Aggregate(Aggregate::Max, Some(0)),
+            ],
+        });
+        groups.add(&[Value::Null]);
+        assert_eq!(groups.changed_rows(), vec![vec![json!(1), json!(1), json!(0), Value::Null, Value::Null]]);
+        groups.add(&[json!(1.5)]);
+        assert_eq!(groups.changed_rows(), vec![vec![json!(1), json!(2), json!(1), json!(1.5), json!(1.5)]]);
+    }
+
+    #[test]
+    fn sums_mix_integers_and_floats() {
+        let mut groups = Groups::new(GroupBy {
+            keys: vec![],
+            columns: vec![AggregateColumn::Aggregate(Aggregate::Sum, Some(0))],
+        });
+        groups.add(&[json!(u64::MAX)]);
+        groups.add(&[json!(1)]);
+        assert_eq!(groups.changed_rows(), vec![vec![json!(1), json!(u64::MAX as f64 + 1.0)]]);
+        groups.add(&[json!(0.5)]);
+        assert_eq!(groups.changed_rows(), vec![vec![json!(1), json!(u64::MAX as f64 + 1.5)]]);
+    }
+}
@@ -1,199 +1,2252 @@
-use rusqlite::{params, Connection, Result};
+use crate::aggregate::{Aggregate, AggregateColumn, GroupBy};
+use crate::catalog;
+use crate::constants;
+use crate::functions::{self, UserFunction};
+use crate::probe::{Probe, ProbeType};
+use crate::parser::{parse_bpfquery_expr, parse_bpfquery_sql};
+use crate::system;
+use crate::transform::Transform;
+use crate::views;
 use sqlparser::ast::*;
+use std::fmt;
+use std::ops::ControlFlow;
 
-fn get_struct_for_arg(function_name: &String, arg_name: &String) -> String {
-    let conn = Connection::open("linux_kernel_definitions.db").unwrap();
+/// What actually gets run to answer a query.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Program {
+    /// Run with bpftrace on the host.
+    Bpftrace(String),
+    /// Answered from the system tables, sqlite sql.
+    Catalog(String),
+    /// Changes what bpfquery knows about, only run when asked to explicitly
+    /// rather than as it's typed.
+    Define(Definition),
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Program::Bpftrace(s) | Program::Catalog(s) => write!(f, "{}", s),
+            Program::Define(d) => write!(f, "{}\n-- ctrl+enter to run", d),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Definition {
+    CreateView {
+        name: String,
+        sql: String,
+        or_replace: bool,
+    },
+    DropView {
+        name: String,
+        if_exists: bool,
+    },
+    CreateFunction {
+        function: UserFunction,
+        or_replace: bool,
+    },
+    DropFunction {
+        name: String,
+        if_exists: bool,
+    },
+}
+
+impl Definition {
+    /// Does it, returning what happened.
+    pub fn run(&self) -> Result<String, String> {
+        match self {
+            Definition::CreateView {
+                name,
+                sql,
+                or_replace,
+            } => views::create_view(name, sql, *or_replace),
+            Definition::DropView { name, if_exists } => views::drop_view(name, *if_exists),
+            Definition::CreateFunction {
+                function,
+                or_replace,
+            } => functions::create_function(function, *or_replace),
+            Definition::DropFunction { name, if_exists } => {
+                functions::drop_function(name, *if_exists)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Definition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Definition::CreateView {
+                name,
+                sql,
+                or_replace,
+            } => {
+                let or_replace = if *or_replace { "OR REPLACE " } else { "" };
+                write!(f, "CREATE {}VIEW {} AS {}", or_replace, name, sql)
+            }
+            Definition::DropView { name, if_exists } => {
+                let if_exists = if *if_exists { "IF EXISTS " } else { "" };
+                write!(f, "DROP VIEW {}{}", if_exists, name)
+            }
+            Definition::CreateFunction {
+                function,
+                or_replace,
+            } => {
+                let or_replace = if *or_replace { "OR REPLACE " } else { "" };
+                write!(
+                    f,
+                    "CREATE {}FUNCTION {}({}) AS {}",
+                    or_replace,
+                    function.name,
+                    function.args.join(", "),
+                    function.body
+                )
+            }
+            Definition::DropFunction { name, if_exists } => {
+                let if_exists = if *if_exists { "IF EXISTS " } else { "" };
+                write!(f, "DROP FUNCTION {}{}", if_exists, name)
+            }
+        }
+    }
+}
+
+/// A query turned into a program, along with how to turn what the program
+/// prints back into rows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledQuery {
+    pub program: Program,
+    pub headers: Vec<String>,
+    /// One per column the program prints, done to the value in userspace
+    /// before it's sent on.
+    pub transforms: Vec<Option<Transform>>,
+    /// How the printed columns are folded into the headers for GROUP BY
+    /// queries, otherwise there's one header per printed column.
+    pub group_by: Option<GroupBy>,
+}
+
+/// A bpftrace release, e.g. 0.20.2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u32, pub u32, pub u32);
+
+impl Version {
+    /// Reads what `bpftrace --version` prints, e.g. "bpftrace v0.20.2" or
+    /// "bpftrace v0.21.0-83-g1f1a7e2".
+    pub fn parse(s: &str) -> Option<Version> {
+        let v = s.split_whitespace().find_map(|w| w.strip_prefix('v'))?;
+        let mut parts = v
+            .split(|c: char| !c.is_ascii_digit())
+            .take(3)
+            .map(|p| p.parse::<u32>().ok());
+        Some(Version(parts.next()??, parts.next()??, parts.next().flatten().unwrap_or(0)))
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// The oldest bpftrace bpfquery writes programs for, json output and the
+/// builtins it leans on are all there by then.
+pub const MIN_VERSION: Version = Version(0, 16, 0);
+
+// before this, struct arguments were args->name
+const ARGS_DOT: Version = Version(0, 19, 0);
+
+// builtins that are newer than MIN_VERSION and when they turned up
+const NEWER_BUILTINS: [(&str, Version); 3] = [
+    ("strcontains", Version(0, 20, 0)),
+    ("offsetof", Version(0, 20, 0)),
+    ("bswap", Version(0, 21, 0)),
+];
+
+/// The machine a host is, as far as syscall numbers go.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arch {
+    X86_64,
+    /// Anything else, e.g. aarch64, whose syscalls are numbered differently.
+    Other,
+}
+
+impl Arch {
+    /// From what `uname -m` prints.
+    pub fn from_uname(machine: &str) -> Arch {
+        match machine.trim() {
+            "x86_64" => Arch::X86_64,
+            _ => Arch::Other,
+        }
+    }
+}
+
+/// What we know about the machine a query is going to run on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Target {
+    /// The kernel has BTF, so fentry/fexit can stand in for kprobes.
+    pub btf: bool,
+    /// fentry/fexit probes work there, going by `bpftrace --info`.
+    pub fentry: bool,
+    /// bpftrace there only knows fentry/fexit as kfunc/kretfunc.
+    pub kfunc: bool,
+    /// None when bpftrace couldn't say, then nothing is ruled out.
+    pub version: Option<Version>,
+    /// None when uname couldn't say, taken to be x86_64.
+    pub arch: Option<Arch>,
+    /// bpftrace has strlen() and substr(), so length() and substr() can be
+    /// worked out before the row is printed, e.g. in WHERE.
+    pub strlen: bool,
+    pub substr: bool,
+}
+
+impl Default for Target {
+    fn default() -> Target {
+        Target {
+            btf: false,
+            fentry: true,
+            kfunc: false,
+            version: None,
+            arch: None,
+            strlen: false,
+            substr: false,
+        }
+    }
+}
+
+impl Target {
+    /// What both targets can run.
+    pub fn common(self, other: Target) -> Target {
+        Target {
+            btf: self.btf && other.btf,
+            fentry: self.fentry && other.fentry,
+            // kfunc is understood by both, fentry maybe not
+            kfunc: self.kfunc || other.kfunc,
+            version: match (self.version, other.version) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+            arch: match (self.arch, other.arch) {
+                (Some(a), Some(b)) if a != b => Some(Arch::Other),
+                (a, b) => a.or(b),
+            },
+            strlen: self.strlen && other.strlen,
+            substr: self.substr && other.substr,
+        }
+    }
+
+    pub fn supports(&self, since: Version) -> bool {
+        self.version.is_none_or(|v| v >= since)
+    }
+}
+
+// says why program won't run on target, if it won't
+fn check_target(program: &str, probes: &[Probe], target: Target) -> Result<(), String> {
+    if let Some(version) = target.version {
+        if version < MIN_VERSION {
+            return Err(format!(
+                "bpftrace {} is too old, bpfquery needs {} or newer",
+                version, MIN_VERSION
+            ));
+        }
+    }
+    if !target.fentry {
+        if let Some(p) = probes
+            .iter()
+            .find(|p| matches!(p.probe_type, ProbeType::Fentry | ProbeType::Fexit))
+        {
+            return Err(format!(
+                "{} probes aren't supported by bpftrace or the kernel here, try kprobe or kretprobe",
+                p.probe_type.name()
+            ));
+        }
+    }
+    for (builtin, since) in NEWER_BUILTINS {
+        if !target.supports(since) && calls(program, builtin) {
+            return Err(format!(
+                "{} needs bpftrace {} or newer, this is {}",
+                builtin,
+                since,
+                target.version.unwrap()
+            ));
+        }
+    }
+    Ok(())
+}
+
+// whether program calls the builtin
+fn calls(program: &str, builtin: &str) -> bool {
+    program.match_indices(&format!("{}(", builtin)).any(|(i, _)| {
+        !program[..i]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_')
+    })
+}
+
+// rewrites args.x as args->x for bpftrace before ARGS_DOT, going word by
+// word and leaving string literals, $args and @args alone
+fn old_args(program: &str) -> String {
+    let mut out = String::with_capacity(program.len());
+    let mut chars = program.chars().peekable();
+    while let Some(c) = chars.next() {
+        out.push(c);
+        if c == '"' {
+            while let Some(c) = chars.next() {
+                out.push(c);
+                match c {
+                    '\\' => out.extend(chars.next()),
+                    '"' => break,
+                    _ => {}
+                }
+            }
+        } else if c.is_alphanumeric() || matches!(c, '_' | '$' | '@') {
+            let mut word = c.to_string();
+            while let Some(&c) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_') {
+                word.push(c);
+                out.push(c);
+                chars.next();
+            }
+            if word == "args" && chars.next_if_eq(&'.').is_some() {
+                out.push_str("->");
+            }
+        }
+    }
+    out
+}
+
+fn kprobe_arg(index: usize, arg: &catalog::Arg) -> String {
+    if arg.c_type.is_empty() {
+        format!("arg{}", index)
+    } else {
+        format!("(({})arg{})", arg.c_type, index)
+    }
+}
+
+// the bpftrace cast for a c return type, None when retval is fine as is
+fn retval_cast(c_type: &str) -> Option<String> {
+    let t = c_type.replace("const ", "").trim().to_string();
+    if t.ends_with('*') {
+        // bpftrace can't do anything useful with a void *
+        return if t.starts_with("void") { None } else { Some(t) };
+    }
+    let cast = match t.as_str() {
+        "char" | "s8" | "__s8" | "int8_t" => "int8",
+        "short" | "s16" | "__s16" | "int16_t" => "int16",
+        "int" | "s32" | "__s32" | "int32_t" => "int32",
+        "long" | "long int" | "long long" | "ssize_t" | "loff_t" | "s64" | "__s64"
+        | "int64_t" => "int64",
+        "bool" | "_Bool" | "unsigned char" | "u8" | "__u8" | "uint8_t" => "uint8",
+        "unsigned short" | "u16" | "__u16" | "uint16_t" => "uint16",
+        "unsigned" | "unsigned int" | "u32" | "__u32" | "uint32_t" => "uint32",
+        "unsigned long" | "unsigned long long" | "size_t" | "u64" | "__u64" | "uint64_t" => {
+            "uint64"
+        }
+        _ => return None,
+    };
+    Some(cast.to_string())
+}
+
+// what the probed function returns according to the catalog
+fn return_type(probe: &Probe) -> Option<String> {
+    match probe.probe_type {
+        ProbeType::Kretprobe | ProbeType::Fexit => probe
+            .function_name()
+            .and_then(catalog::function_return_type),
+        ProbeType::Uretprobe => probe
+            .user_function()
+            .and_then(|(binary, function)| catalog::binary_function_return_type(binary, function)),
+        _ => None,
+    }
+}
+
+/// retval cast to what the function actually returns, so that comparisons
+/// are signed when they should be and struct pointers can be followed.
+fn typed_retval(probe: &Probe) -> String {
+    let cast = match probe.probe_type {
+        // fexit already knows the type from BTF
+        ProbeType::Kretprobe => return_type(probe).and_then(|t| retval_cast(&t)),
+        ProbeType::Uretprobe => return_type(probe)
+            .and_then(|t| retval_cast(&t))
+            // bpftrace only knows the kernel's structs, not the binary's
+            .filter(|c| !c.ends_with('*') || c.replace(' ', "").ends_with("char*")),
+        _ => None,
+    };
+    match cast {
+        Some(c) => format!("(({})retval)", c),
+        None => "retval".to_string(),
+    }
+}
+
+// O_CREAT, AF_INET, TCP_ESTABLISHED... columns are never all upper case so
+// there's no ambiguity
+fn is_constant_name(name: &str) -> bool {
+    name.chars().any(|c| c.is_ascii_uppercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn resolve_identifier(i: &Ident, probe: &Probe) -> String {
+    match i.value.as_str() {
+        // everything in a syscall row comes from its own column
+        name if probe.probe_type == ProbeType::Syscalls => probe_columns(probe)
+            .into_iter()
+            .find(|c| c.name == name && c.transform.is_none())
+            .map(|c| c.expr)
+            .unwrap_or(name.to_string()),
+        "retval" => typed_retval(probe),
+        name if is_constant_name(name) => match constants::constant(name) {
+            Some(v) => v.to_string(),
+            None => name.to_string(),
+        },
+        _ => i.value.clone(),
+    }
+}
+
+// sql doesn't care about the case of function names, bpftrace does
+fn fn_name(f: &Function) -> String {
+    f.name.to_string().to_lowercase()
+}
+
+fn fn_args(f: &Function) -> Vec<&Expr> {
+    match &f.args {
+        FunctionArguments::List(fl) => fl
+            .args
+            .iter()
+            .filter_map(|a| match a {
+                FunctionArg::Unnamed(FunctionArgExpr::Expr(e)) => Some(e),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+// columns that are worked out in userspace from what bpftrace sends back
+fn userspace_column(e: &Expr, probe: &Probe, target: Target) -> Result<Option<(String, Transform)>, String> {
+    match e {
+        Expr::Identifier(i)
+            if i.value == "errno"
+                && matches!(probe.probe_type, ProbeType::Kretprobe | ProbeType::Fexit) =>
+        {
+            // pointers come back as ERR_PTR(-errno), so look at everything signed
+            Ok(Some(("(int64)retval".to_string(), Transform::ErrnoName)))
+        }
+        // bpftrace doesn't know which host it's on, it prints a placeholder
+        Expr::Identifier(i) if i.value == "host" => Ok(Some(("0".to_string(), Transform::Host))),
+        // stacks come back as text, kstack(10) for only the innermost frames
+        Expr::Identifier(i) if i.value == "kstack" || i.value == "ustack" => {
+            Ok(Some((i.value.clone(), Transform::Stack)))
+        }
+        Expr::Function(f) if matches!(fn_name(f).as_str(), "kstack" | "ustack") => {
+            Ok(Some((parse_expr(e, probe), Transform::Stack)))
+        }
+        Expr::Identifier(i) if probe.probe_type == ProbeType::Syscalls => Ok(probe_columns(probe)
+            .into_iter()
+            .find(|c| c.name == i.value)
+            .and_then(|c| Some((c.expr, c.transform?)))),
+        Expr::Function(f) if functions::is_userspace(&fn_name(f)) => {
+            userspace_function(f, probe, target).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+fn integer(e: &Expr) -> Option<i64> {
+    match e {
+        Expr::Value(Value::Number(n, _)) => n.parse().ok(),
+        Expr::UnaryOp {
+            op: UnaryOperator::Minus,
+            expr,
+        } => integer(expr).map(|n| -n),
+        _ => None,
+    }
+}
+
+// bpftrace prints the arguments and a Transform does the rest
+fn userspace_function(f: &Function, probe: &Probe, target: Target) -> Result<(String, Transform), String> {
+    let name = fn_name(f);
+    let args = fn_args(f);
+    if let Some(inner) = args.iter().find_map(|a| misplaced_userspace_function(a, target)) {
+        return Err(format!(
+            "{} can't be used inside {}, both are worked out after bpftrace",
+            inner, name
+        ));
+    }
+    match (name.as_str(), &args[..]) {
+        ("errno_name", [arg]) => Ok((
+            format!("(int64)({})", parse_expr(arg, probe)),
+            Transform::ErrnoName,
+        )),
+        ("decode_flags", [arg, Expr::Value(Value::SingleQuotedString(set))]) => {
+            if constants::flag_set(set).is_none() {
+                return Err(format!(
+                    "Unknown flags {}, try one of {}",
+                    set,
+                    constants::FLAG_SETS
+                        .iter()
+                        .map(|s| s.name)
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                ));
+            }
+            Ok((parse_expr(arg, probe), Transform::DecodeFlags(set.clone())))
+        }
+        ("lower", [arg]) => Ok((parse_expr(arg, probe), Transform::Lower)),
+        ("upper", [arg]) => Ok((parse_expr(arg, probe), Transform::Upper)),
+        ("length", [arg]) => Ok((parse_expr(arg, probe), Transform::Length)),
+        ("substr", [arg, start]) if integer(start).is_some() => Ok((
+            parse_expr(arg, probe),
+            Transform::Substr(integer(start).unwrap(), None),
+        )),
+        ("substr", [arg, start, length]) if integer(start).is_some() && integer(length).is_some() => {
+            Ok((
+                parse_expr(arg, probe),
+                Transform::Substr(integer(start).unwrap(), integer(length)),
+            ))
+        }
+        // a tuple, which comes back as a list to join up
+        ("concat", [_, ..]) => Ok((
+            format!(
+                "({})",
+                args.iter()
+                    .map(|a| parse_expr(a, probe))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Transform::Concat,
+        )),
+        _ => Err(match functions::builtin_function(&name) {
+            Some(b) => format!("Expected {}({})", name, b.args),
+            None => format!("Unknown function {}", name),
+        }),
+    }
+}
+
+// what bpftrace should print for a column, and what to do to it afterwards
+fn column_output(e: &Expr, probe: &Probe, target: Target) -> Result<(String, Option<Transform>), String> {
+    match userspace_column(e, probe, target)? {
+        Some((output, transform)) => Ok((output, Some(transform))),
+        None => {
+            if let Some(f) = misplaced_userspace_function(e, target) {
+                return Err(format!("{} can only be used on its own in the select list", f));
+            }
+            Ok((parse_expr(e, probe), None))
+        }
+    }
+}
+
+// the parts of a select the compiler doesn't do anything with, so they're an
+// error rather than quietly left out
+fn check_clauses(select: &Select) -> Result<(), String> {
+    if select.having.is_some() {
+        return Err("HAVING isn't supported yet".to_string());
+    }
+    if select.distinct.is_some() {
+        return Err("DISTINCT isn't supported yet".to_string());
+    }
+    Ok(())
+}
+
+// Works out which columns bpftrace has to print for a GROUP BY query and how
+// they fold into the selected columns, returns (headers, printed, group by).
+fn plan_group_by(select: &Select) -> Result<(Vec<String>, Vec<Expr>, GroupBy), String> {
+    let keys = match &select.group_by {
+        GroupByExpr::Expressions(es, _) => es.clone(),
+        GroupByExpr::All(_) => return Err("GROUP BY ALL isn't supported".to_string()),
+    };
+    // GROUP BY can use a column's alias
+    let keys = keys
+        .into_iter()
+        .map(|k| {
+            let aliased = select.projection.iter().find_map(|p| match (p, &k) {
+                (SelectItem::ExprWithAlias { expr, alias }, Expr::Identifier(i)) if alias == i => {
+                    Some(expr.clone())
+                }
+                _ => None,
+            });
+            aliased.unwrap_or(k)
+        })
+        .collect::<Vec<Expr>>();
+
+    let mut printed = keys.clone();
+    let mut headers = vec![];
+    let mut columns = vec![];
+    for projection in &select.projection {
+        let (header, e) = match projection {
+            SelectItem::UnnamedExpr(e) => (e.to_string(), e),
+            SelectItem::ExprWithAlias { expr, alias } => (alias.value.clone(), expr),
+            _ => return Err("* can't be used with GROUP BY".to_string()),
+        };
+        headers.push(header);
+
+        if let Some(k) = keys.iter().position(|k| k == e) {
+            columns.push(AggregateColumn::Key(k));
+            continue;
+        }
+        let (aggregate, args) = match e {
+            Expr::Function(f) => match Aggregate::from_name(&f.name.to_string()) {
+                Some(a) => (a, fn_args(f)),
+                None => return Err(format!("{} needs to be in the GROUP BY or inside an aggregate like count()", e)),
+            },
+            _ => return Err(format!("{} needs to be in the GROUP BY or inside an aggregate like count()", e)),
+        };
+        let column = match args[..] {
+            // count(*)
+            [] if aggregate == Aggregate::Count => None,
+            [arg] => match printed.iter().position(|p| p == arg) {
+                Some(i) => Some(i),
+                None => {
+                    printed.push(arg.clone());
+                    Some(printed.len() - 1)
+                }
+            },
+            _ => return Err(format!("{} takes one column", e)),
+        };
+        columns.push(AggregateColumn::Aggregate(aggregate, column));
+    }
+    // bpftrace needs at least one column to print, even for a lone count(*)
+    if printed.is_empty() {
+        printed.push(Expr::Value(Value::Number("1".to_string(), false)));
+    }
+    let group_by = GroupBy {
+        keys: (0..keys.len()).collect(),
+        columns,
+    };
+    Ok((headers, printed, group_by))
+}
+
+fn is_aggregate(select: &Select) -> bool {
+    let grouped = match &select.group_by {
+        GroupByExpr::Expressions(es, _) => !es.is_empty(),
+        GroupByExpr::All(_) => true,
+    };
+    grouped
+        || select.projection.iter().any(|p| match p {
+            SelectItem::UnnamedExpr(Expr::Function(f))
+            | SelectItem::ExprWithAlias {
+                expr: Expr::Function(f),
+                ..
+            } => Aggregate::from_name(&f.name.to_string()).is_some(),
+            _ => false,
+        })
+}
+
+// the userspace functions only work on what's sent back, not in filters or
+// inside other expressions
+fn misplaced_userspace_function(e: &Expr, target: Target) -> Option<String> {
+    let mut found = None;
+    let _ = visit_expressions(e, |e| match e {
+        Expr::Function(f) if functions::is_userspace(&fn_name(f)) && !in_bpftrace(f, target) => {
+            found = Some(fn_name(f));
+            ControlFlow::Break(())
+        }
+        // host is only known once the row is back from bpftrace too
+        Expr::Identifier(i) if i.value == "host" => {
+            found = Some(i.value.clone());
+            ControlFlow::Break(())
+        }
+        _ => ControlFlow::Continue(()),
+    });
+    found
+}
+
+fn resolve_compound_identifier(cs: &[Ident], probe: &Probe) -> String {
+    // if the first ident is args, then we do a lookup in the catalog for the
+    // type of the argument so nobody has to cast things by hand
+
+    //only do this for kprobes for now
+    if cs[0].value == "args" && probe.probe_type == ProbeType::Kprobe {
+        let arg_name = &cs[1].value;
+        let args = probe.function_name().and_then(catalog::function_args);
+        if let Some((index, arg)) = args
+            .iter()
+            .flatten()
+            .enumerate()
+            .find(|(_, a)| &a.name == arg_name)
+        {
+            return kprobe_arg(index, arg);
+        }
+        //still need to figure out what happens if you have a struct of a struct
+    }
+    cs.iter()
+        .map(|i| i.value.clone())
+        .collect::<Vec<String>>()
+        .join(".")
+}
+
+/// A column a probe has without the query having to compute anything.
+struct ProbeColumn {
+    name: String,
+    c_type: String,
+    expr: String,
+    transform: Option<Transform>,
+}
+
+impl ProbeColumn {
+    fn new(name: &str, c_type: &str, expr: &str) -> ProbeColumn {
+        ProbeColumn {
+            name: name.to_string(),
+            c_type: c_type.to_string(),
+            expr: expr.to_string(),
+            transform: None,
+        }
+    }
+
+    fn with_transform(self, transform: Transform) -> ProbeColumn {
+        ProbeColumn {
+            transform: Some(transform),
+            ..self
+        }
+    }
+}
+
+// builtins that make sense no matter what the probe is, (name, type)
+const BUILTIN_COLUMNS: [(&str, &str); 4] = [
+    ("comm", "char[16]"),
+    ("pid", "uint32"),
+    ("cpu", "uint32"),
+    ("elapsed", "uint64"),
+];
+
+const PROFILE_COLUMNS: [(&str, &str); 5] = [
+    ("comm", "char[16]"),
+    ("pid", "uint32"),
+    ("tid", "uint32"),
+    ("cpu", "uint32"),
+    ("elapsed", "uint64"),
+];
+
+const INTERVAL_COLUMNS: [(&str, &str); 2] = [("cpu", "uint32"), ("elapsed", "uint64")];
+
+// a column per arg, strings get read with str() so they come back readable
+fn arg_columns(
+    args: &[catalog::Arg],
+    expr: impl Fn(usize, &catalog::Arg) -> String,
+) -> Vec<ProbeColumn> {
+    args.iter()
+        .enumerate()
+        .map(|(i, arg)| {
+            let e = expr(i, arg);
+            let e = if arg.is_string() {
+                format!("str({})", e)
+            } else {
+                e
+            };
+            ProbeColumn::new(&arg.name, &arg.c_type, &e)
+        })
+        .collect()
+}
+
+fn args_dot(_: usize, arg: &catalog::Arg) -> String {
+    format!("args.{}", arg.name)
+}
+
+// the map a syscall's entry keeps an argument in until the exit
+fn saved_arg(name: &str) -> String {
+    format!("@q1_arg_{}", name)
+}
+
+// The arguments a syscall is called with, (name, c_type, expr on entry). All
+// syscalls only get the raw registers, a single syscall gets its tracepoint's
+// typed fields.
+fn syscall_args(probe: &Probe) -> Vec<(String, String, String)> {
+    match probe.syscall() {
+        Some(name) => catalog::tracepoint_fields("syscalls", &format!("sys_enter_{}", name))
+            .into_iter()
+            .filter(|f| !f.name.starts_with("common_") && f.name != "__syscall_nr")
+            .map(|f| {
+                let e = args_dot(0, &f);
+                let e = if f.is_string() { format!("str({})", e) } else { e };
+                (f.name, f.c_type, e)
+            })
+            .collect(),
+        None => (0..6)
+            .map(|i| {
+                (
+                    format!("arg{}", i),
+                    "unsigned long".to_string(),
+                    format!("args.args[{}]", i),
+                )
+            })
+            .collect(),
+    }
+}
+
+/// Every column a probe has, which is what `*` expands to.
+fn probe_columns(probe: &Probe) -> Vec<ProbeColumn> {
+    let builtins = match probe.probe_type {
+        // interval probes fire on one cpu in whatever happened to be running,
+        // so there's no process to speak of
+        ProbeType::Interval => &INTERVAL_COLUMNS[..],
+        ProbeType::Profile => &PROFILE_COLUMNS[..],
+        _ => &BUILTIN_COLUMNS[..],
+    };
+    let mut columns = builtins
+        .iter()
+        .map(|(name, c_type)| ProbeColumn::new(name, c_type, name))
+        .collect::<Vec<ProbeColumn>>();
+
+    let retval = || {
+        let c_type = return_type(probe).unwrap_or("uint64".to_string());
+        ProbeColumn::new("retval", &c_type, &typed_retval(probe))
+    };
+
+    match probe.probe_type {
+        ProbeType::Kprobe => {
+            let args = probe.function_name().and_then(catalog::function_args);
+            columns.extend(arg_columns(&args.unwrap_or_default(), kprobe_arg));
+        }
+        ProbeType::Kretprobe | ProbeType::Uretprobe => {
+            columns.push(retval());
+        }
+        ProbeType::Uprobe => {
+            // bpftrace reads args for uprobes from the binary's DWARF
+            let args = probe
+                .user_function()
+                .and_then(|(binary, function)| catalog::binary_function_args(binary, function));
+            columns.extend(arg_columns(&args.unwrap_or_default(), args_dot));
+        }
+        ProbeType::Usdt => {
+            // usdt args don't have names, just arg0, arg1, ...
+            if let Some((binary, provider, name)) = probe.usdt() {
+                let found = catalog::binary_usdt_probes(binary)
+                    .into_iter()
+                    .find(|p| p.name == name && provider.is_none_or(|pr| p.provider == pr));
+                if let Some(p) = found {
+                    for i in 0..p.arg_count() {
+                        let arg = format!("arg{}", i);
+                        columns.push(ProbeColumn::new(&arg, "uint64", &arg));
+                    }
+                }
+            }
+        }
+        ProbeType::Fentry | ProbeType::Fexit => {
+            // BTF already knows the types, no casting needed
+            let args = probe.function_name().and_then(catalog::function_args);
+            columns.extend(arg_columns(&args.unwrap_or_default(), args_dot));
+            if probe.probe_type == ProbeType::Fexit {
+                columns.push(retval());
+            }
+        }
+        ProbeType::Tracepoint => {
+            if let Some((category, event)) = probe.tracepoint() {
+                // bpftrace doesn't expose the common_ fields through args
+                let fields = catalog::tracepoint_fields(category, event)
+                    .into_iter()
+                    .filter(|f| !f.name.starts_with("common_"))
+                    .collect::<Vec<catalog::Arg>>();
+                columns.extend(arg_columns(&fields, args_dot));
+            }
+        }
+        ProbeType::Profile => {
+            // profile probes interrupt whatever is running, so the stacks are
+            // the interesting part. interval probes only fire on one cpu so
+            // their stacks don't mean much.
+            columns.push(
+                ProbeColumn::new("kstack", "kstack", "kstack").with_transform(Transform::Stack),
+            );
+            columns.push(
+                ProbeColumn::new("ustack", "ustack", "ustack").with_transform(Transform::Stack),
+            );
+        }
+        ProbeType::Syscalls => {
+            // rows are made on the exit, anything from the entry was saved
+            // in a map keyed by tid
+            let nr = match probe.syscall() {
+                Some(_) => "args.__syscall_nr",
+                None => "args.id",
+            };
+            columns.push(ProbeColumn::new("tid", "uint32", "tid"));
+            columns.push(ProbeColumn::new("id", "long", nr));
+            columns.push(
+                ProbeColumn::new("name", "const char *", nr).with_transform(Transform::SyscallName),
+            );
+            for (name, c_type, _) in syscall_args(probe) {
+                let saved = format!("{}[tid]", saved_arg(&name));
+                columns.push(ProbeColumn::new(&name, &c_type, &saved));
+            }
+            columns.push(ProbeColumn::new("retval", "long", "args.ret"));
+            columns.push(
+                ProbeColumn::new("errno", "const char *", "args.ret")
+                    .with_transform(Transform::ErrnoName),
+            );
+            columns.push(ProbeColumn::new("duration", "uint64", "$duration"));
+        }
+        _ => {}
+    }
+    columns
+}
+
+fn expand_wildcard(
+    options: &WildcardAdditionalOptions,
+    probe: &Probe,
+    target: Target,
+) -> Result<Vec<(String, String, Option<Transform>)>, String> {
+    if options.opt_ilike.is_some() || options.opt_except.is_some() || options.opt_rename.is_some()
+    {
+        return Err("Only EXCLUDE and REPLACE are supported with *".to_string());
+    }
+
+    let mut columns = probe_columns(probe)
+        .into_iter()
+        .map(|c| (c.name, c.expr, c.transform))
+        .collect::<Vec<(String, String, Option<Transform>)>>();
+
+    if let Some(exclude) = &options.opt_exclude {
+        let excluded = match exclude {
+            ExcludeSelectItem::Single(i) => vec![i.clone()],
+            ExcludeSelectItem::Multiple(is) => is.clone(),
+        };
+        for e in excluded {
+            if !columns.iter().any(|(h, _, _)| *h == e.value) {
+                return Err(format!("Cannot exclude unknown column {}", e.value));
+            }
+            columns.retain(|(h, _, _)| *h != e.value);
+        }
+    }
+
+    if let Some(replace) = &options.opt_replace {
+        for item in &replace.items {
+            let name = &item.column_name.value;
+            match columns.iter_mut().find(|(h, _, _)| h == name) {
+                Some(column) => {
+                    let (output, transform) = column_output(&item.expr, probe, target)?;
+                    column.1 = output;
+                    column.2 = transform;
+                }
+                None => return Err(format!("Cannot replace unknown column {}", name)),
+            }
+        }
+    }
+
+    Ok(columns)
+}
+
+fn parse_value(v: &Value) -> String {
+    match v {
+        Value::SingleQuotedString(s) => bpftrace_string(s),
+        _ => v.to_string(),
+    }
+}
+
+// a bpftrace string literal with the same contents as s
+fn bpftrace_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn parse_fn_arg_expr(arg: &FunctionArgExpr, probe: &Probe ) -> String {
+    match arg {
+        FunctionArgExpr::Expr(e) => parse_expr(e, probe),
+        FunctionArgExpr::Wildcard => "*".to_string(),
+        FunctionArgExpr::QualifiedWildcard(_o) => "QualifiedWildcard not supported".to_string(),
+    }
+}
+
+fn parse_fn_arg(arg: &FunctionArg, probe: &Probe) -> String {
+    match arg {
+        FunctionArg::Named {
+            name,
+            arg,
+            operator: _,
+        } => format!("{}={}", name, parse_fn_arg_expr(arg, probe)), // no idea what operator is
+        FunctionArg::Unnamed(e) => parse_fn_arg_expr(e, probe),
+    }
+}
+
+// Userspace functions that bpftrace can do too on hosts that have them, they
+// stay userspace when they're a whole column since that works everywhere.
+// substr only when its start and length are numbers, sql counts from 1.
+fn in_bpftrace(f: &Function, target: Target) -> bool {
+    match (fn_name(f).as_str(), &fn_args(f)[..]) {
+        ("length", [_]) => target.strlen,
+        ("substr", [_, start, length]) => {
+            target.substr && integer(start).is_some_and(|s| s >= 1) && integer(length).is_some_and(|l| l >= 0)
+        }
+        _ => false,
+    }
+}
+
+// sql functions that bpftrace has under another name or shape, check_functions
+// or in_bpftrace has made sure the arguments fit
+fn rewritten_function(name: &str, args: &[&Expr], probe: &Probe) -> Option<String> {
+    match (name, args) {
+        ("length", [s]) => Some(format!("strlen({})", parse_expr(s, probe))),
+        ("substr", [s, start, length]) => Some(format!(
+            "substr({}, {}, {})",
+            parse_expr(s, probe),
+            integer(start)? - 1,
+            parse_expr(length, probe)
+        )),
+        ("starts_with", [s, Expr::Value(Value::SingleQuotedString(prefix))]) => Some(format!(
+            "(strncmp({}, {}, {}) == 0)",
+            parse_expr(s, probe),
+            bpftrace_string(prefix),
+            prefix.len()
+        )),
+        ("contains", [s, needle]) => Some(format!(
+            "strcontains({}, {})",
+            parse_expr(s, probe),
+            parse_expr(needle, probe)
+        )),
+        _ => None,
+    }
+}
+
+// Catches typos and functions used where they can't work before bpftrace
+// gets a chance to complain about them.
+fn check_functions(select: &Select) -> Result<(), String> {
+    let result = visit_expressions(select, |e| {
+        let f = match e {
+            Expr::Function(f) => f,
+            _ => return ControlFlow::Continue(()),
+        };
+        let name = fn_name(f);
+        let builtin = functions::builtin_function(&name);
+        if builtin.is_none() && Aggregate::from_name(&name).is_none() {
+            return ControlFlow::Break(match functions::suggestion(&name) {
+                Some(s) => format!("Unknown function {}, did you mean {}?", name, s),
+                None => format!("Unknown function {}, see system.functions", name),
+            });
+        }
+        let fits = match (name.as_str(), &fn_args(f)[..]) {
+            ("starts_with", [_, Expr::Value(Value::SingleQuotedString(_))]) => true,
+            ("starts_with", _) => false,
+            ("contains", args) => args.len() == 2,
+            _ => true,
+        };
+        match (fits, builtin) {
+            (false, Some(b)) => ControlFlow::Break(format!("Expected {}({})", name, b.args)),
+            _ => ControlFlow::Continue(()),
+        }
+    });
+    if let ControlFlow::Break(e) = result {
+        return Err(e);
+    }
+
+    let mut aggregate = None;
+    if let Some(selection) = &select.selection {
+        let _ = visit_expressions(selection, |e| match e {
+            Expr::Function(f) if Aggregate::from_name(&fn_name(f)).is_some() => {
+                aggregate = Some(fn_name(f));
+                ControlFlow::Break(())
+            }
+            _ => ControlFlow::Continue(()),
+        });
+    }
+    match aggregate {
+        Some(a) => Err(format!("{} can't be used in WHERE", a)),
+        None => Ok(()),
+    }
+}
+
+fn parse_expr(e: &Expr, probe: &Probe) -> String {
+    match e {
+        Expr::Identifier(i) => resolve_identifier(i, probe),
+        Expr::Wildcard => "*".to_string(),
+        Expr::Value(v) => parse_value(v),
+        Expr::BinaryOp { left, op, right } => {
+            if let Some(nr) = syscall_comparison(left, op, right, probe) {
+                return nr;
+            }
+            let mut ooop = op.to_string();
+            // if it's an ==, we need to convert it to a =
+            if ooop == "=" {
+                ooop = "==".to_string();
+            }
+            // and AND/OR are && and ||
+            match op {
+                BinaryOperator::And => ooop = "&&".to_string(),
+                BinaryOperator::Or => ooop = "||".to_string(),
+                _ => {}
+            }
+
+            format!(
+                "{} {} {}",
+                parse_expr(left, probe),
+                ooop,
+                parse_expr(right, probe)
+            )
+        }
+        Expr::Function(f) => {
+            let fns = fn_name(f);
+            if let Some(rewritten) = rewritten_function(&fns, &fn_args(f), probe) {
+                return rewritten;
+            }
+            match &f.args {
+                FunctionArguments::List(fl) => {
+                    let fargs = fl
+                        .args
+                        .iter()
+                        .map(|x| parse_fn_arg(x, probe))
+                        .collect::<Vec<String>>()
+                        .join(",");
+                    format!("{}({})", fns, fargs)
+                }
+                FunctionArguments::None => format!("{}()", fns),
+                FunctionArguments::Subquery(_) => "Subquery not supported".to_string(),
+            }
+        }
+        Expr::CompoundIdentifier(c) => resolve_compound_identifier(c, probe),
+        Expr::Nested(e) => format!("({})", parse_expr(e, probe)),
+        Expr::UnaryOp { op, expr } => {
+            let op = match op {
+                UnaryOperator::Not => "!".to_string(),
+                op => op.to_string(),
+            };
+            format!("{}{}", op, parse_expr(expr, probe))
+        }
+        // bpftrace has ?: instead, nested for every WHEN
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            let mut case = match else_result {
+                Some(e) => parse_expr(e, probe),
+                None => "0".to_string(),
+            };
+            for (condition, result) in conditions.iter().zip(results).rev() {
+                let condition = match operand {
+                    Some(o) => format!("{} == {}", parse_expr(o, probe), parse_expr(condition, probe)),
+                    None => parse_expr(condition, probe),
+                };
+                case = format!("({} ? {} : {})", condition, parse_expr(result, probe), case);
+            }
+            case
+        }
+        v => format!("Unsupported expression: {:?}", v),
+    }
+}
+
+// syscalls are named in sql but numbered in bpf, so `name = 'openat'` has to
+// become a comparison of the number
+fn syscall_comparison(left: &Expr, op: &BinaryOperator, right: &Expr, probe: &Probe) -> Option<String> {
+    if probe.probe_type != ProbeType::Syscalls
+        || !matches!(op, BinaryOperator::Eq | BinaryOperator::NotEq)
+    {
+        return None;
+    }
+    let name = match (left, right) {
+        (Expr::Identifier(i), Expr::Value(Value::SingleQuotedString(s)))
+        | (Expr::Value(Value::SingleQuotedString(s)), Expr::Identifier(i))
+            if i.value == "name" =>
+        {
+            s
+        }
+        _ => return None,
+    };
+    let op = match op {
+        BinaryOperator::Eq => "==",
+        _ => "!=",
+    };
+    // unknown names are caught before getting here, see unknown_syscall
+    let nr = constants::syscall_number(name).unwrap_or(-1);
+    Some(format!("{} {} {}", resolve_identifier(&Ident::new("id"), probe), op, nr))
+}
+
+fn unknown_syscall(e: &Expr) -> Option<String> {
+    let mut found = None;
+    let _ = visit_expressions(e, |e| match e {
+        Expr::BinaryOp { left, right, .. } => match (left.as_ref(), right.as_ref()) {
+            (Expr::Identifier(i), Expr::Value(Value::SingleQuotedString(s)))
+            | (Expr::Value(Value::SingleQuotedString(s)), Expr::Identifier(i))
+                if i.value == "name" && constants::syscall_number(s).is_none() =>
+            {
+                found = Some(s.clone());
+                ControlFlow::Break(())
+            }
+            _ => ControlFlow::Continue(()),
+        },
+        _ => ControlFlow::Continue(()),
+    });
+    found
+}
+
+// fentry/fexit don't have arg0, arg1, ... so a query that uses them has to
+// stay a kprobe
+fn uses_positional_args(select: &Select) -> bool {
+    let found = visit_expressions(select, |e| match e {
+        Expr::Identifier(i)
+            if i.value.len() > 3
+                && i.value.starts_with("arg")
+                && i.value[3..].chars().all(|c| c.is_ascii_digit()) =>
+        {
+            ControlFlow::Break(())
+        }
+        _ => ControlFlow::Continue(()),
+    });
+    found.is_break()
+}
+
+/// The first half of a relation made of two events, like a syscall's entry
+/// and exit.
+struct PairedStart {
+    probe: Probe,
+    /// What ties the two events together, as seen from the start...
+    key: String,
+    /// ...and from the end.
+    end_key: String,
+    /// (map, expr) for the values the end needs from the start.
+    saved: Vec<(String, String)>,
+}
+
+// a syscall's entry, only saving the arguments the query uses
+fn syscall_start(select: &Select, probe: &Probe, start: Probe) -> PairedStart {
+    let mut used = vec![];
+    let _ = visit_expressions(select, |e| {
+        if let Expr::Identifier(i) = e {
+            used.push(i.value.clone());
+        }
+        ControlFlow::<()>::Continue(())
+    });
+    let wildcard = select
+        .projection
+        .iter()
+        .any(|p| matches!(p, SelectItem::Wildcard(_)));
+    PairedStart {
+        probe: start,
+        key: "tid".to_string(),
+        end_key: "tid".to_string(),
+        saved: syscall_args(probe)
+            .into_iter()
+            .filter(|(name, _, _)| wildcard || used.contains(name))
+            .map(|(name, _, e)| (saved_arg(&name), e))
+            .collect(),
+    }
+}
+
+// The start remembers when it happened and the values the query uses, and
+// the end makes the row. The filter is checked on the end so that it can use
+// everything, the saved values are cleaned up either way.
+fn compile_paired(
+    start: &PairedStart,
+    end: &Probe,
+    filter: &str,
+    body: &str,
+    target: Target,
+) -> String {
+    let mut bpftrace = format!(
+        "{} {{\n@q1_start[{}] = nsecs;\n",
+        start.probe.bpftrace(target.kfunc),
+        start.key
+    );
+    for (map, e) in &start.saved {
+        bpftrace.push_str(&format!("{}[{}] = {};\n", map, start.key, e));
+    }
+    bpftrace.push_str("}\n");
+
+    bpftrace.push_str(&format!(
+        "{} /@q1_start[{}]/ {{\n",
+        end.bpftrace(target.kfunc),
+        start.end_key
+    ));
+    bpftrace.push_str(&format!("$duration = nsecs - @q1_start[{}];\n", start.end_key));
+    if filter.is_empty() {
+        bpftrace.push_str(body);
+    } else {
+        bpftrace.push_str(&format!("if ({}) {{\n{}}}\n", filter, body));
+    }
+    bpftrace.push_str(&format!("delete(@q1_start[{}]);\n", start.end_key));
+    for (map, _) in &start.saved {
+        bpftrace.push_str(&format!("delete({}[{}]);\n", map, start.end_key));
+    }
+    bpftrace.push('}');
+    bpftrace
+}
+
+// system.probes -> probes, information_schema.columns ->
+// information_schema_columns, None if it isn't a system table
+fn system_table(name: &ObjectName) -> Option<String> {
+    match name.0.as_slice() {
+        [schema, table] if schema.value == "system" => Some(table.value.clone()),
+        [schema, table] if schema.value == "information_schema" => {
+            Some(format!("information_schema_{}", table.value))
+        }
+        _ => None,
+    }
+}
+
+// Queries that only touch the system tables are handed to sqlite as is, with
+// the system. taken off the table names.
+fn compile_system_query(statement: &Statement) -> Result<Option<CompiledQuery>, String> {
+    let mut tables = vec![];
+    let _ = visit_relations(statement, |name| {
+        tables.push(name.clone());
+        ControlFlow::<()>::Continue(())
+    });
+    let system_tables = tables.iter().filter_map(system_table).collect::<Vec<String>>();
+    if system_tables.is_empty() {
+        return Ok(None);
+    }
+    if system_tables.len() != tables.len() {
+        return Err("System tables can't be queried alongside probes".to_string());
+    }
+    // everyone shares them and queries run as they're typed
+    if !matches!(statement, Statement::Query(_)) {
+        return Err("System tables are read only, only SELECT works on them".to_string());
+    }
+    if let Some(t) = system_tables.iter().find(|t| !system::TABLES.contains(&t.as_str())) {
+        return Err(format!(
+            "Unknown system table {}, try one of {}",
+            t.replace("information_schema_", "information_schema."),
+            system::TABLES
+                .map(|t| t.replace("information_schema_", "information_schema."))
+                .join(", ")
+        ));
+    }
+
+    let mut statement = statement.clone();
+    let _ = visit_relations_mut(&mut statement, |name| {
+        if let Some(table) = system_table(name) {
+            *name = ObjectName(vec![Ident::new(table)]);
+        }
+        ControlFlow::<()>::Continue(())
+    });
+    let sql = statement.to_string();
+    let headers = system::headers(&sql)?;
+    Ok(Some(CompiledQuery {
+        program: Program::Catalog(sql),
+        transforms: vec![None; headers.len()],
+        headers,
+        group_by: None,
+    }))
+}
+
+fn sql_string(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+// DESCRIBE lists a table's columns with their C and SQL types. Probes are
+// described from the same columns * expands to, system tables by sqlite.
+fn compile_describe(name: &ObjectName, target: Target) -> Result<CompiledQuery, String> {
+    let sql = match system_table(name) {
+        Some(table) if system::TABLES.contains(&table.as_str()) => format!(
+            "SELECT name, NULL AS c_type, type AS sql_type FROM pragma_table_info({})",
+            sql_string(&table)
+        ),
+        Some(_) => return Err(format!("Unknown system table {}", name)),
+        None => {
+            let rows = match view_query(name) {
+                Some(view) => describe_view(&view?)?,
+                None => {
+                    let probe = Probe::from_object_name(name);
+                    let probe = if target.btf { probe.with_btf() } else { probe };
+                    probe_columns(&probe)
+                        .into_iter()
+                        .map(|c| (c.name, Some(c.c_type)))
+                        .collect()
+                }
+            };
+            let rows = rows
+                .iter()
+                .map(|(name, c_type)| match c_type {
+                    Some(c_type) => format!(
+                        "({}, {}, {})",
+                        sql_string(name),
+                        sql_string(c_type),
+                        sql_string(catalog::sql_type(c_type))
+                    ),
+                    None => format!("({}, NULL, NULL)", sql_string(name)),
+                })
+                .collect::<Vec<String>>();
+            format!(
+                "SELECT column1 AS name, column2 AS c_type, column3 AS sql_type FROM (VALUES {})",
+                rows.join(", ")
+            )
+        }
+    };
+    let headers = system::headers(&sql)?;
+    Ok(CompiledQuery {
+        program: Program::Catalog(sql),
+        transforms: vec![None; headers.len()],
+        headers,
+        group_by: None,
+    })
+}
+
+// A view's columns, typed when they come straight out of a probe column and
+// left NULL when they're worked out.
+fn describe_view(view: &Select) -> Result<Vec<(String, Option<String>)>, String> {
+    let (inlined, _) = split_join(&inline_views(view, &[])?)?;
+    let probe = match inlined.from.first() {
+        Some(TableWithJoins {
+            relation: TableFactor::Table { name, .. },
+            ..
+        }) => Probe::from_object_name(name),
+        _ => return Err("Expected a table".to_string()),
+    };
+    let columns = probe_columns(&probe);
+    Ok(view_columns(&inlined)
+        .into_iter()
+        .map(|(name, expr)| {
+            let c_type = match expr {
+                Expr::Identifier(_) | Expr::CompoundIdentifier(_) => columns
+                    .iter()
+                    .find(|c| c.name == expr.to_string())
+                    .map(|c| c.c_type.clone()),
+                _ => None,
+            };
+            (name, c_type)
+        })
+        .collect())
+}
+
+fn view_name(name: &ObjectName) -> Result<String, String> {
+    match name.0.as_slice() {
+        [i] if Probe::from_object_name(name).probe_type == ProbeType::Other(i.value.clone()) => {
+            Ok(i.value.clone())
+        }
+        [i] => Err(format!("{} is a kind of probe, views need another name", i.value)),
+        _ => Err(format!("View names can't have dots in them, {}", name)),
+    }
+}
+
+// sql function names don't care about case
+fn function_name(name: &ObjectName) -> Result<String, String> {
+    match name.0.as_slice() {
+        [i] => Ok(i.value.to_lowercase()),
+        _ => Err(format!("Function names can't have dots in them, {}", name)),
+    }
+}
+
+// how far functions can call other functions, stops cycles too
+const MAX_FUNCTION_DEPTH: usize = 16;
+
+// Swaps every call of a function made with CREATE FUNCTION for its body with
+// the arguments filled in.
+fn expand_functions<T: VisitMut>(ast: &mut T, user_functions: &[UserFunction]) -> Result<(), String> {
+    if user_functions.is_empty() {
+        return Ok(());
+    }
+    for _ in 0..MAX_FUNCTION_DEPTH {
+        let mut expanded = false;
+        let result = visit_expressions_mut(ast, |e| {
+            let f = match e {
+                Expr::Function(f) => f,
+                _ => return ControlFlow::Continue(()),
+            };
+            let name = fn_name(f);
+            let function = match user_functions.iter().find(|u| u.name == name) {
+                Some(u) => u,
+                None => return ControlFlow::Continue(()),
+            };
+            let args = fn_args(f);
+            if args.len() != function.args.len() {
+                return ControlFlow::Break(format!(
+                    "{} takes {} argument{}, {}({})",
+                    name,
+                    function.args.len(),
+                    if function.args.len() == 1 { "" } else { "s" },
+                    name,
+                    function.args.join(", ")
+                ));
+            }
+            let body = match parse_bpfquery_expr(&function.body) {
+                Ok(b) => b,
+                Err(e) => return ControlFlow::Break(format!("Function {} doesn't parse: {}", name, e)),
+            };
+            let params = function
+                .args
+                .iter()
+                .cloned()
+                .zip(args.into_iter().cloned())
+                .collect::<Vec<(String, Expr)>>();
+            *e = parenthesized(substitute_columns(&body, &params));
+            expanded = true;
+            ControlFlow::Continue(())
+        });
+        if let ControlFlow::Break(e) = result {
+            return Err(e);
+        }
+        if !expanded {
+            return Ok(());
+        }
+    }
+    Err("Functions call each other too deeply".to_string())
+}
+
+// kb(retval) is a better header than what it expands to
+fn name_function_columns(select: &mut Select, user_functions: &[UserFunction]) {
+    for item in select.projection.iter_mut() {
+        let e = match item {
+            SelectItem::UnnamedExpr(e) => e,
+            _ => continue,
+        };
+        let calls_user_function = visit_expressions(e, |e| match e {
+            Expr::Function(f)
+                if user_functions
+                    .iter()
+                    .any(|u| u.name == fn_name(f)) =>
+            {
+                ControlFlow::Break(())
+            }
+            _ => ControlFlow::Continue(()),
+        })
+        .is_break();
+        if calls_user_function {
+            *item = SelectItem::ExprWithAlias {
+                alias: Ident::new(e.to_string()),
+                expr: e.clone(),
+            };
+        }
+    }
+}
+
+// CREATE/DROP VIEW and FUNCTION. A view's query gets compiled so that
+// mistakes show up while it's being written rather than when it's used.
+fn compile_definition(statement: &Statement, target: Target) -> Result<Option<Definition>, String> {
+    match statement {
+        Statement::CreateView {
+            or_replace,
+            materialized,
+            name,
+            columns,
+            query,
+            ..
+        } => {
+            if *materialized {
+                return Err("Views can't be materialized".to_string());
+            }
+            if !columns.is_empty() {
+                return Err("Name a view's columns with AS in its select instead".to_string());
+            }
+            let name = view_name(name)?;
+            views::check_new_view(&name, *or_replace)?;
+            match query.body.as_ref() {
+                SetExpr::Select(s) if is_aggregate(s) => {
+                    return Err("Views can't have GROUP BY or aggregates".to_string())
+                }
+                SetExpr::Select(_) => {}
+                _ => return Err("A view has to be a select".to_string()),
+            }
+            compile_ast_to_bpftrace(vec![Statement::Query(query.clone())], target)?;
+            Ok(Some(Definition::CreateView {
+                name,
+                sql: query.to_string(),
+                or_replace: *or_replace,
+            }))
+        }
+        Statement::CreateMacro {
+            or_replace,
+            temporary,
+            name,
+            args,
+            definition,
+        } => {
+            if *temporary {
+                return Err("Functions are always saved, leave out TEMPORARY".to_string());
+            }
+            let name = function_name(name)?;
+            functions::check_new_function(&name, *or_replace)?;
+            let args = args
+                .iter()
+                .flatten()
+                .map(|a| match a.default_expr {
+                    Some(_) => Err("Function arguments can't have defaults".to_string()),
+                    None => Ok(a.name.value.clone()),
+                })
+                .collect::<Result<Vec<String>, String>>()?;
+            let body = match definition {
+                MacroDefinition::Expr(e) => e.to_string(),
+                MacroDefinition::Table(_) => {
+                    return Err("A function has to be an expression, use CREATE VIEW for queries".to_string())
+                }
+            };
+            Ok(Some(Definition::CreateFunction {
+                function: UserFunction { name, args, body },
+                or_replace: *or_replace,
+            }))
+        }
+        Statement::DropFunction {
+            if_exists,
+            func_desc,
+            ..
+        } => match func_desc.as_slice() {
+            [f] => Ok(Some(Definition::DropFunction {
+                name: function_name(&f.name)?,
+                if_exists: *if_exists,
+            })),
+            _ => Err("Drop one function at a time".to_string()),
+        },
+        Statement::Drop {
+            object_type: ObjectType::View,
+            if_exists,
+            names,
+            ..
+        } => match names.as_slice() {
+            [name] => Ok(Some(Definition::DropView {
+                name: view_name(name)?,
+                if_exists: *if_exists,
+            })),
+            _ => Err("Drop one view at a time".to_string()),
+        },
+        _ => Ok(None),
+    }
+}
 
-    let mut stmt = conn
-        .prepare("SELECT signature from function where function_name = ?")
-        .unwrap();
+// nobody should need views of views of views... this deep, stops cycles too
+const MAX_VIEW_DEPTH: usize = 16;
 
-    // Iterate over each string and check if it exists in the database
-    let sig: String = stmt.query_row(params![function_name], |row| row.get(0)).unwrap();
-    //((const struct path * path,struct file * file)
-    // break this up by comma, then by space, find the one that matches the arg_name with the last element, and save the rest of the string
-    let cleaned = sig.replace("(", "").replace(")", "").replace("const", "");
-    let sigs = cleaned.split(",").collect::<Vec<&str>>();
-    let mut structs = "".to_string();
-    let mut index = 0;
-    for (i,s) in sigs.into_iter().enumerate() {
-        let ss = s.trim().split(" ").collect::<Vec<&str>>();
-        if ss[ss.len() - 1] == arg_name {
-            //everyhting but the last element
-            structs = ss.iter().take(ss.len() - 1).map(|x| x.to_string()).collect::<Vec<String>>().join(" ");
-            index = i;
-            break;
-        }
+fn view_columns(view: &Select) -> Vec<(String, Expr)> {
+    view.projection
+        .iter()
+        .filter_map(|p| match p {
+            SelectItem::UnnamedExpr(Expr::Identifier(i)) => {
+                Some((i.value.clone(), Expr::Identifier(i.clone())))
+            }
+            SelectItem::UnnamedExpr(e) => Some((e.to_string(), e.clone())),
+            SelectItem::ExprWithAlias { expr, alias } => Some((alias.value.clone(), expr.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+// anything that isn't a plain name or value, so it can go anywhere
+fn parenthesized(e: Expr) -> Expr {
+    match e {
+        Expr::Identifier(_)
+        | Expr::CompoundIdentifier(_)
+        | Expr::Function(_)
+        | Expr::Value(_)
+        | Expr::Nested(_) => e,
+        e => Expr::Nested(Box::new(e)),
     }
-    format!("(({})arg{})", structs, index)
 }
 
-fn resolve_compound_identifier(cs: &[Ident], relation: &str) -> String {
-    // if the first ident is args, then we do a lookup in the database for 
-    
-    //only do this for kprobes for now
-    let rs = relation.split(":").collect::<Vec<&str>>();
-    let probe_type = rs[0];
-    let probe_name = rs[1];
+// Swaps a view's columns or a function's arguments into an expression,
+// parenthesized so that `latency / 1000` still means that.
+fn substitute_columns(e: &Expr, columns: &[(String, Expr)]) -> Expr {
+    let mut e = e.clone();
+    let _ = visit_expressions_mut(&mut e, |e| {
+        if let Expr::Identifier(i) = e {
+            if let Some((_, column)) = columns.iter().find(|(name, _)| *name == i.value) {
+                *e = parenthesized(column.clone());
+            }
+        }
+        ControlFlow::<()>::Continue(())
+    });
+    e
+}
 
-    if cs[0].value == "args" && probe_type == "kprobe" {
-        let arg_name = cs[1].value.clone();
-        get_struct_for_arg(&probe_name.to_string(), &arg_name)
-        //still need to figure out what happens if you have a struct of a struct
+// Selecting from a view is the view's query with the outer query's columns,
+// filters and grouping put on top.
+fn inline_view(outer: &Select, view: &Select) -> Result<Select, String> {
+    if is_aggregate(view) {
+        return Err("Views and subqueries with GROUP BY or aggregates can't be selected from".to_string());
     }
-    else {
-    cs.iter()
-        .map(|i| (i.value.clone()))
-        .collect::<Vec<String>>()
-        .join(".")
+    check_clauses(view)?;
+    let columns = view_columns(view);
+    let substitute = |e: &Expr| substitute_columns(e, &columns);
+
+    let mut projection = vec![];
+    for item in &outer.projection {
+        match item {
+            SelectItem::UnnamedExpr(Expr::Identifier(i))
+                if columns.iter().any(|(name, _)| *name == i.value) =>
+            {
+                projection.push(SelectItem::ExprWithAlias {
+                    expr: substitute(&Expr::Identifier(i.clone())),
+                    alias: i.clone(),
+                });
+            }
+            SelectItem::UnnamedExpr(e) => {
+                let substituted = substitute(e);
+                projection.push(if substituted == *e {
+                    SelectItem::UnnamedExpr(substituted)
+                } else {
+                    // keep the header the way it was written
+                    SelectItem::ExprWithAlias {
+                        expr: substituted,
+                        alias: Ident::new(e.to_string()),
+                    }
+                });
+            }
+            SelectItem::ExprWithAlias { expr, alias } => {
+                projection.push(SelectItem::ExprWithAlias {
+                    expr: substitute(expr),
+                    alias: alias.clone(),
+                });
+            }
+            SelectItem::Wildcard(options) => {
+                if options.opt_ilike.is_some()
+                    || options.opt_except.is_some()
+                    || options.opt_rename.is_some()
+                {
+                    return Err("Only EXCLUDE and REPLACE are supported with *".to_string());
+                }
+                let excluded = match &options.opt_exclude {
+                    Some(ExcludeSelectItem::Single(i)) => vec![i.value.clone()],
+                    Some(ExcludeSelectItem::Multiple(is)) => {
+                        is.iter().map(|i| i.value.clone()).collect()
+                    }
+                    None => vec![],
+                };
+                let replaced = options
+                    .opt_replace
+                    .iter()
+                    .flat_map(|r| &r.items)
+                    .map(|i| (i.column_name.value.clone(), substitute(&i.expr)))
+                    .collect::<Vec<(String, Expr)>>();
+                for name in excluded.iter().chain(replaced.iter().map(|(n, _)| n)) {
+                    if !columns.iter().any(|(c, _)| c == name) {
+                        return Err(format!("Unknown column {}", name));
+                    }
+                }
+                for view_item in &view.projection {
+                    // a view that's just * passes the options on to the probe
+                    if let SelectItem::Wildcard(_) = view_item {
+                        projection.push(item.clone());
+                        continue;
+                    }
+                    let (name, expr) = &view_columns(&Select {
+                        projection: vec![view_item.clone()],
+                        ..view.clone()
+                    })[0];
+                    if excluded.contains(name) {
+                        continue;
+                    }
+                    let expr = match replaced.iter().find(|(n, _)| n == name) {
+                        Some((_, e)) => e.clone(),
+                        None => expr.clone(),
+                    };
+                    projection.push(SelectItem::ExprWithAlias {
+                        expr,
+                        alias: Ident::new(name),
+                    });
+                }
+            }
+            SelectItem::QualifiedWildcard(..) => {
+                return Err("table.* isn't supported".to_string());
+            }
+        }
+    }
+
+    let nested = |e: Expr| Expr::Nested(Box::new(e));
+    let selection = match (&view.selection, &outer.selection) {
+        (Some(v), Some(o)) => Some(Expr::BinaryOp {
+            left: Box::new(nested(v.clone())),
+            op: BinaryOperator::And,
+            right: Box::new(nested(substitute(o))),
+        }),
+        (Some(v), None) => Some(v.clone()),
+        (None, Some(o)) => Some(substitute(o)),
+        (None, None) => None,
+    };
+
+    let group_by = match &outer.group_by {
+        GroupByExpr::Expressions(es, modifiers) => {
+            GroupByExpr::Expressions(es.iter().map(substitute).collect(), modifiers.clone())
+        }
+        g => g.clone(),
+    };
 
-    } 
+    Ok(Select {
+        projection,
+        from: view.from.clone(),
+        selection,
+        group_by,
+        ..outer.clone()
+    })
 }
 
-fn parse_value(v: &Value) -> String {
-    match v {
-        Value::SingleQuotedString(s) => format!("\"{}\"", s.clone()),
-        _ => v.to_string(),
+// A query that's used as a table, which can't do anything a view can't.
+fn subquery_select(q: &Query, what: &str, ctes: &[(String, Select)]) -> Result<Select, String> {
+    if q.order_by.is_some() || q.limit.is_some() || q.offset.is_some() {
+        return Err(format!("{} can't have ORDER BY or LIMIT", what));
+    }
+    match q.body.as_ref() {
+        SetExpr::Select(s) => inline_views(s, &with_tables(q, ctes)?),
+        _ => Err(format!("{} has to be a select", what)),
     }
 }
 
-fn parse_fn_arg_expr(arg: &FunctionArgExpr, relation: &String ) -> String {
-    match arg {
-        FunctionArgExpr::Expr(e) => parse_expr(e, relation),
-        FunctionArgExpr::Wildcard => "*".to_string(),
-        FunctionArgExpr::QualifiedWildcard(_o) => "QualifiedWildcard not supported".to_string(),
+/// The tables a query can select from by name, what it makes with WITH on
+/// top of the ones it was given, as (name, select).
+fn with_tables(
+    q: &Query,
+    ctes: &[(String, Select)],
+) -> Result<Vec<(String, Select)>, String> {
+    let mut ctes = ctes.to_vec();
+    let with = match &q.with {
+        Some(w) => w,
+        None => return Ok(ctes),
+    };
+    if with.recursive {
+        return Err("WITH RECURSIVE isn't supported".to_string());
+    }
+    for cte in &with.cte_tables {
+        let name = &cte.alias.name.value;
+        if !cte.alias.columns.is_empty() {
+            return Err(format!(
+                "Name the columns of {} with AS in its select instead",
+                name
+            ));
+        }
+        // later tables can select from earlier ones
+        let select = subquery_select(&cte.query, name, &ctes)?;
+        ctes.push((name.clone(), select));
     }
+    Ok(ctes)
 }
 
-fn parse_fn_arg(arg: &FunctionArg, relation: &String) -> String {
-    match arg {
-        FunctionArg::Named {
-            name,
-            arg,
-            operator: _,
-        } => format!("{}={}", name, parse_fn_arg_expr(arg, relation)), // no idea what operator is
-        FunctionArg::Unnamed(e) => parse_fn_arg_expr(e, relation),
+// the query behind a view, if that's what the table is
+fn view_query(name: &ObjectName) -> Option<Result<Select, String>> {
+    let (name, sql) = match name.0.as_slice() {
+        [name] => (&name.value, views::view_sql(&name.value)?),
+        _ => return None,
+    };
+    let mut parsed = match parse_bpfquery_sql(&sql) {
+        Ok(ast) => ast,
+        Err(e) => return Some(Err(format!("View {} doesn't parse: {}", name, e))),
+    };
+    if let Err(e) = expand_functions(&mut parsed, &functions::user_functions()) {
+        return Some(Err(e));
+    }
+    match parsed.first() {
+        Some(Statement::Query(q)) => Some(subquery_select(q, &format!("View {}", name), &[])),
+        _ => Some(Err(format!("View {} isn't a query", name))),
     }
 }
 
-fn parse_expr(e: &Expr, relation: &String) -> String {
-    match e {
-        Expr::Identifier(i) => i.value.clone(),
-        Expr::Wildcard => "*".to_string(),
-        Expr::Value(v) => parse_value(v),
-        Expr::BinaryOp { left, op, right } => {
-            let mut ooop = op.to_string();
-            // if it's an ==, we need to convert it to a =
-            if ooop == "=" {
-                ooop = "==".to_string();
+// Views, WITH tables and subqueries in FROM all get inlined into the query
+// that selects from them, their columns are swapped in wherever they're used.
+fn inline_views(select: &Select, ctes: &[(String, Select)]) -> Result<Select, String> {
+    let mut select = select.clone();
+    for _ in 0..MAX_VIEW_DEPTH {
+        let relation = match select.from.as_slice() {
+            [TableWithJoins { relation, joins }] if joins.is_empty() => relation.clone(),
+            _ => return Ok(select),
+        };
+        let (inner, alias) = match &relation {
+            TableFactor::Table { name, alias, .. } => {
+                let cte = match name.0.as_slice() {
+                    [n] => ctes.iter().rev().find(|(c, _)| *c == n.value),
+                    _ => None,
+                };
+                let inner = match cte {
+                    Some((_, s)) => Ok(s.clone()),
+                    None => match view_query(name) {
+                        Some(view) => view,
+                        None => return Ok(select),
+                    },
+                };
+                let alias = match alias {
+                    Some(a) => a.name.value.clone(),
+                    None => name.to_string(),
+                };
+                (inner?, alias)
             }
+            TableFactor::Derived {
+                lateral: false,
+                subquery,
+                alias,
+            } => (
+                subquery_select(subquery, "A subquery in FROM", ctes)?,
+                alias.as_ref().map(|a| a.name.value.clone()).unwrap_or_default(),
+            ),
+            _ => return Ok(select),
+        };
+        // opens.comm is just comm once opens is inlined
+        let outer = strip_alias(
+            &Select {
+                from: vec![],
+                ..select
+            },
+            &alias,
+        );
+        select = inline_view(&outer, &inner)?;
+    }
+    Err("Views are nested too deeply".to_string())
+}
 
-            format!(
-                "{} {} {}",
-                parse_expr(left, relation),
-                ooop,
-                parse_expr(right, relation)
-            )
+// subqueries only work as tables, not as values
+fn expression_subquery<V: Visit>(v: &V) -> bool {
+    let mut found = false;
+    let _ = visit_expressions(v, |e| {
+        found = match e {
+            Expr::Subquery(_) | Expr::Exists { .. } | Expr::InSubquery { .. } => true,
+            Expr::Function(f) => matches!(f.args, FunctionArguments::Subquery(_)),
+            _ => false,
+        };
+        if found {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
         }
-        Expr::Function(f) => {
-            let fns = f.name.to_string();
-            match &f.args {
-                FunctionArguments::List(fl) => {
-                    let fargs = fl
-                        .args
-                        .iter()
-                        .map(|x| parse_fn_arg(x, relation))
-                        .collect::<Vec<String>>()
-                        .join(",");
-                    format!("{}({})", fns, fargs)
+    });
+    found
+}
+
+#[derive(Debug, PartialEq)]
+enum Side {
+    Neither,
+    Start,
+    End,
+    Both,
+}
+
+// which probe of a JOIN an expression reads from, anything that isn't
+// qualified with the start's alias is read from the end
+fn side(e: &Expr, start_alias: &str) -> Side {
+    let (mut start, mut end) = (false, false);
+    let _ = visit_expressions(e, |e| {
+        match e {
+            Expr::CompoundIdentifier(cs) if cs[0].value == start_alias => start = true,
+            Expr::Identifier(_) | Expr::CompoundIdentifier(_) => end = true,
+            _ => {}
+        }
+        ControlFlow::<()>::Continue(())
+    });
+    match (start, end) {
+        (false, false) => Side::Neither,
+        (true, false) => Side::Start,
+        (false, true) => Side::End,
+        (true, true) => Side::Both,
+    }
+}
+
+// issue.args.dev -> args.dev
+fn strip_alias<T: VisitMut + Clone>(e: &T, alias: &str) -> T {
+    let mut e = e.clone();
+    let _ = visit_expressions_mut(&mut e, |e| {
+        if let Expr::CompoundIdentifier(cs) = e {
+            if cs.len() > 1 && cs[0].value == alias {
+                *e = match &cs[1..] {
+                    [i] => Expr::Identifier(i.clone()),
+                    rest => Expr::CompoundIdentifier(rest.to_vec()),
+                };
+            }
+        }
+        ControlFlow::<()>::Continue(())
+    });
+    e
+}
+
+// Replaces everything that can only be worked out on the start of a JOIN
+// with a lookup of the map it gets saved in.
+struct SaveStart<'a> {
+    alias: &'a str,
+    probe: &'a Probe,
+    end_key: &'a str,
+    saved: Vec<(String, String)>,
+}
+
+impl VisitorMut for SaveStart<'_> {
+    type Break = ();
+
+    fn pre_visit_expr(&mut self, e: &mut Expr) -> ControlFlow<()> {
+        if side(e, self.alias) == Side::Start {
+            let saved = parse_expr(&strip_alias(e, self.alias), self.probe);
+            let map = match self.saved.iter().find(|(_, s)| *s == saved) {
+                Some((map, _)) => map.clone(),
+                None => {
+                    let map = format!("@q1_s{}", self.saved.len());
+                    self.saved.push((map.clone(), saved));
+                    map
                 }
-                FunctionArguments::None => format!("{}()", fns),
-                FunctionArguments::Subquery(_) => "Subquery not supported".to_string(),
+            };
+            *e = Expr::Identifier(Ident::new(format!("{}[{}]", map, self.end_key)));
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+fn table_alias(relation: &TableFactor) -> Result<(&ObjectName, &str), String> {
+    match relation {
+        TableFactor::Table {
+            name,
+            alias: Some(alias),
+            ..
+        } => Ok((name, &alias.name.value)),
+        _ => Err("Both sides of a JOIN need an alias, e.g. FROM a AS s JOIN b AS e ON s.x = e.x".to_string()),
+    }
+}
+
+// `a AND b AND c` -> [a, b, c]
+fn conjuncts(e: &Expr) -> Vec<&Expr> {
+    match e {
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            let mut all = conjuncts(left);
+            all.extend(conjuncts(right));
+            all
+        }
+        Expr::Nested(e) => conjuncts(e),
+        e => vec![e],
+    }
+}
+
+// A JOIN of two probes becomes a paired relation: the ON clause is what ties
+// an event on the start to one on the end, anything the query needs from the
+// start is saved until the end happens. Returns the query as if it only read
+// from the end.
+fn split_join(select: &Select) -> Result<(Select, Option<PairedStart>), String> {
+    let from = match select.from.as_slice() {
+        [from] if !from.joins.is_empty() => from,
+        _ => return Ok((select.clone(), None)),
+    };
+    if select.from.len() > 1 || from.joins.len() > 1 {
+        return Err("Only two probes can be joined".to_string());
+    }
+    let join = &from.joins[0];
+    let (start_name, start_alias) = table_alias(&from.relation)?;
+    let (end_name, end_alias) = table_alias(&join.relation)?;
+    let on = match &join.join_operator {
+        JoinOperator::Inner(JoinConstraint::On(on)) => on,
+        _ => return Err("Only JOIN ... ON is supported".to_string()),
+    };
+    let start = Probe::from_object_name(start_name);
+    let end = Probe::from_object_name(end_name);
+
+    let mut keys = vec![];
+    let mut end_keys = vec![];
+    for c in conjuncts(on) {
+        let pair = match c {
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::Eq,
+                right,
+            } => match (side(left, start_alias), side(right, start_alias)) {
+                (Side::Start, Side::End) => Some((left, right)),
+                (Side::End, Side::Start) => Some((right, left)),
+                _ => None,
+            },
+            _ => None,
+        };
+        let (s, e) = match pair {
+            Some(p) => p,
+            None => {
+                return Err(format!(
+                    "JOIN ... ON can only match values from {} with values from {}, not {}",
+                    start_alias, end_alias, c
+                ))
             }
+        };
+        keys.push(parse_expr(&strip_alias(s.as_ref(), start_alias), &start));
+        end_keys.push(parse_expr(&strip_alias(e.as_ref(), end_alias), &end));
+    }
+    let end_key = end_keys.join(", ");
+
+    let mut relation = join.relation.clone();
+    if let TableFactor::Table { alias, .. } = &mut relation {
+        *alias = None;
+    }
+    let mut select = Select {
+        from: vec![TableWithJoins {
+            relation,
+            joins: vec![],
+        }],
+        ..select.clone()
+    };
+    // named before the start's columns become map lookups, s.pid is pid
+    for item in select.projection.iter_mut() {
+        if let SelectItem::UnnamedExpr(e) = item {
+            let name = strip_alias(&strip_alias(e, start_alias), end_alias).to_string();
+            *item = SelectItem::ExprWithAlias {
+                expr: e.clone(),
+                alias: Ident::new(name),
+            };
         }
-        Expr::CompoundIdentifier(c) => resolve_compound_identifier(c, relation),
-        v => format!("Unsupported expression: {:?}", v),
     }
+    let mut saver = SaveStart {
+        alias: start_alias,
+        probe: &start,
+        end_key: &end_key,
+        saved: vec![],
+    };
+    let _ = VisitMut::visit(&mut select, &mut saver);
+    let saved = saver.saved;
+    let select = strip_alias(&select, end_alias);
+
+    Ok((
+        select,
+        Some(PairedStart {
+            probe: start,
+            key: keys.join(", "),
+            end_key,
+            saved,
+        }),
+    ))
 }
 
-pub fn compile_ast_to_bpftrace(ast: Vec<Statement>) -> Result<(String, Vec<String>), &'static str> {
+/// Whether the statement becomes a bpftrace program, which is written for
+/// what the hosts can run. The rest compiles the same without them.
+pub fn needs_target(statement: &Statement) -> bool {
+    let mut system_tables = false;
+    let _ = visit_relations(statement, |name| {
+        system_tables |= system_table(name).is_some();
+        ControlFlow::<()>::Continue(())
+    });
+    !system_tables
+        && !matches!(
+            statement,
+            Statement::ExplainTable { .. }
+                | Statement::CreateView { .. }
+                | Statement::CreateMacro { .. }
+                | Statement::DropFunction { .. }
+                | Statement::Drop { .. }
+        )
+}
+
+pub fn compile_ast_to_bpftrace(
+    ast: Vec<Statement>,
+    target: Target,
+) -> Result<CompiledQuery, String> {
+    if let Statement::ExplainTable { table_name, .. } = &ast[0] {
+        return compile_describe(table_name, target);
+    }
+
+    if let Some(query) = compile_system_query(&ast[0])? {
+        return Ok(query);
+    }
+
+    if let Some(definition) = compile_definition(&ast[0], target)? {
+        return Ok(CompiledQuery {
+            program: Program::Define(definition),
+            headers: vec!["result".to_string()],
+            transforms: vec![None],
+            group_by: None,
+        });
+    }
+
+    let mut ast = ast;
+    let user_functions = functions::user_functions();
+    if let Some(Statement::Query(q)) = ast.first_mut() {
+        if let SetExpr::Select(s) = q.body.as_mut() {
+            name_function_columns(s, &user_functions);
+        }
+    }
+    expand_functions(&mut ast, &user_functions)?;
+
     let q = match &ast[0] {
         Statement::Query(q) => q,
-        _ => return Err("Expected a query"),
+        _ => return Err("Expected a query".to_string()),
     };
 
     let b = q.body.as_ref();
 
     let select = match b {
         SetExpr::Select(s) => s,
-        _ => return Err("Expected a select"),
+        _ => return Err("Expected a select".to_string()),
     };
 
+    if expression_subquery(q.as_ref()) {
+        return Err("Subqueries only work in FROM, not as values".to_string());
+    }
+    if q.order_by.is_some() || q.limit.is_some() || q.offset.is_some() {
+        return Err("ORDER BY and LIMIT aren't supported yet, rows come as they happen".to_string());
+    }
+    let select = inline_views(select, &with_tables(q, &[])?)?;
+    check_clauses(&select)?;
+    check_functions(&select)?;
+    let (select, paired) = split_join(&select)?;
+    let select = &select;
+
     let projections = &select.projection;
     let relations = &select.from;
 
     let mut quick_exit = false;
-    let probe_name = if relations.is_empty() {
+    let probe = if relations.is_empty() {
         quick_exit = true;
-        "BEGIN".to_string()
+        Probe::begin()
     } else {
         let probes = relations[0].clone().relation;
         let name = match &probes {
             TableFactor::Table { name, .. } => name,
-            _ => return Err("Expected a table"),
+            _ => return Err("Expected a table".to_string()),
         };
-        //convert table name to probe name
-        name.to_string().replace("STAR", "*").replace(".",":")
+        //convert table name to probe
+        let probe = Probe::from_object_name(name);
+        // the keys of a JOIN were worked out for the probes as written
+        if target.btf && target.fentry && !uses_positional_args(select) && paired.is_none() {
+            probe.with_btf()
+        } else {
+            probe
+        }
     };
 
     // compile the query into bpftrace
 
-    let mut bpftrace = String::new();
-
-    //convert from into bpftrace probe
-    bpftrace.push_str(&probe_name);
-
     //add where/filter in, optional might not be there so we need to check
     let filters = match &select.selection {
         Some(e) => vec![e],
         None => vec![],
     };
 
-    if !filters.is_empty() {
-        bpftrace.push_str(" /");
-        for filter in filters {
-            bpftrace.push_str(&parse_expr(filter, &probe_name));
+    match filters.iter().find_map(|e| misplaced_userspace_function(e, target)) {
+        Some(f) if f == "host" => return Err("host can't be used in a filter, pick hosts with USE".to_string()),
+        Some(f) => return Err(format!("{} can't be used in a filter", f)),
+        None => {}
+    }
+    if matches!(probe.probe_type, ProbeType::Profile | ProbeType::Interval) && probe.rate().is_none() {
+        let example = match probe.probe_type {
+            ProbeType::Profile => "profile.hz.99",
+            _ => "interval.ms.100",
+        };
+        return Err(format!(
+            "Expected {}.<hz|s|ms|us>.<count>, e.g. {}",
+            probe.probe_type.name(),
+            example
+        ));
+    }
+    if probe.probe_type == ProbeType::Syscalls {
+        // constants::SYSCALLS only has x86_64's numbers
+        if target.arch == Some(Arch::Other) {
+            return Err("syscalls only works on x86_64 hosts for now, use tracepoint.syscalls instead".to_string());
+        }
+        if let Some(name) = filters.iter().find_map(|e| unknown_syscall(e)) {
+            return Err(format!("Unknown syscall {}", name));
         }
-        bpftrace.push_str("/ ");
     }
 
-    bpftrace.push_str("\n {\n");
+    let filter = filters
+        .iter()
+        .map(|f| parse_expr(f, &probe))
+        .collect::<Vec<String>>()
+        .join("");
 
     // print out the projections
 
     let mut headers = Vec::new();
     let mut outputs = Vec::new();
+    let mut transforms = Vec::new();
+    let mut group_by = None;
 
-    for projection in projections {
-        match projection {
-            SelectItem::UnnamedExpr(e) => {
-                headers.push(e.to_string());
-                outputs.push(parse_expr(e, &probe_name));
-            }
-            SelectItem::ExprWithAlias { expr, alias } => {
-                headers.push(alias.value.clone());
-                outputs.push(parse_expr(expr, &probe_name));
-            }
-            SelectItem::Wildcard(_) => {
-                let opts = ["comm", "pid", "cpu", "elapsed"];
-                for opt in opts.iter() {
-                    headers.push(opt.to_string());
-                    outputs.push(opt.to_string());
+    if is_aggregate(select) {
+        let (h, printed, g) = plan_group_by(select)?;
+        for e in printed {
+            let (output, transform) = column_output(&e, &probe, target)?;
+            outputs.push(output);
+            transforms.push(transform);
+        }
+        headers = h;
+        group_by = Some(g);
+    }
+
+    for projection in projections.iter().filter(|_| group_by.is_none()) {
+        let (header, e) = match projection {
+            SelectItem::UnnamedExpr(e) => (e.to_string(), e),
+            SelectItem::ExprWithAlias { expr, alias } => (alias.value.clone(), expr),
+            SelectItem::Wildcard(options) => {
+                for (header, output, transform) in expand_wildcard(options, &probe, target)? {
+                    headers.push(header);
+                    outputs.push(output);
+                    transforms.push(transform);
                 }
+                continue;
             }
 
-            _ => return Err("Expected an expression"),
-        }
+            _ => return Err("Expected an expression".to_string()),
+        };
+        headers.push(header);
+        let (output, transform) = column_output(e, &probe, target)?;
+        outputs.push(output);
+        transforms.push(transform);
     }
 
     let mut results_update = String::new();
@@ -204,8 +2257,6 @@ pub fn compile_ast_to_bpftrace(ast: Vec<Statement>) -> Result<(String, Vec<Strin
         results_update.push_str(&format!("$q1_{} = {};\n", i, e));
     }
 
-    bpftrace.push_str(&results_update);
-
     let mut print_str = String::new();
 
     print_str.push_str("print((");
@@ -216,12 +2267,435 @@ pub fn compile_ast_to_bpftrace(ast: Vec<Statement>) -> Result<(String, Vec<Strin
     print_str.pop();
     print_str.push_str("));\n");
 
-    bpftrace.push_str(&print_str);
+    let paired = match (paired, probe.paired()) {
+        (Some(start), _) => Some((start, probe.clone())),
+        (None, Some((start, end))) => Some((syscall_start(select, &probe, start), end)),
+        (None, None) => None,
+    };
+
+    let probes = match &paired {
+        Some((start, end)) => vec![start.probe.clone(), end.clone()],
+        None => vec![probe.clone()],
+    };
+
+    let bpftrace = match paired {
+        Some((start, end)) => {
+            let body = format!("{}{}", results_update, print_str);
+            compile_paired(&start, &end, &filter, &body, target)
+        }
+        None => {
+            let mut bpftrace = String::new();
+
+            //convert from into bpftrace probe
+            bpftrace.push_str(&probe.bpftrace(target.kfunc));
+
+            if !filter.is_empty() {
+                bpftrace.push_str(&format!(" /{}/ ", filter));
+            }
+
+            bpftrace.push_str("\n {\n");
+            bpftrace.push_str(&results_update);
+            bpftrace.push_str(&print_str);
+
+            if quick_exit {
+                bpftrace.push_str("exit();\n");
+            }
+
+            bpftrace.push_str(" }");
+            bpftrace
+        }
+    };
+
+    check_target(&bpftrace, &probes, target)?;
+    let bpftrace = if target.supports(ARGS_DOT) {
+        bpftrace
+    } else {
+        old_args(&bpftrace)
+    };
+
+    Ok(CompiledQuery {
+        program: Program::Bpftrace(bpftrace),
+        headers,
+        transforms,
+        group_by,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_bpfquery_sql;
+
+    fn compile_for(sql: &str, target: Target) -> Result<CompiledQuery, String> {
+        catalog::kernel_definitions_for_tests();
+        compile_ast_to_bpftrace(parse_bpfquery_sql(sql).map_err(|e| e.to_string())?, target)
+    }
+
+    fn compile(sql: &str) -> CompiledQuery {
+        compile_for(sql, Target::default()).unwrap()
+    }
+
+    fn bpftrace(sql: &str) -> String {
+        match compile(sql).program {
+            Program::Bpftrace(p) => p,
+            p => panic!("expected bpftrace, got {}", p),
+        }
+    }
+
+    fn error(sql: &str, target: Target) -> String {
+        compile_for(sql, target).err().unwrap_or_else(|| panic!("{} compiled", sql))
+    }
+
+    #[test]
+    fn selects_and_filters() {
+        let query = compile("select pid, comm as name from kprobe.vfs_read where pid > 10");
+        assert_eq!(query.headers, vec!["pid", "name"]);
+        let program = bpftrace("select pid, comm as name from kprobe.vfs_read where pid > 10");
+        assert!(program.starts_with("kprobe:vfs_read /pid > 10/"), "{}", program);
+        assert!(program.contains("print(((\"id\",@q1_id[\"id\"]),(0,$q1_0),(1,$q1_1)));"), "{}", program);
+    }
+
+    #[test]
+    fn select_star_has_the_builtins() {
+        let headers = compile("select * from kprobe.vfs_read").headers;
+        assert_eq!(headers, vec!["comm", "pid", "cpu", "elapsed", "file", "buf", "count", "pos"]);
+    }
+
+    #[test]
+    fn kretprobe_retval_is_typed() {
+        let sql = "select retval, errno from kretprobe.vfs_read where retval < 0";
+        let query = compile(sql);
+        assert_eq!(query.headers, vec!["retval", "errno"]);
+        assert_eq!(query.transforms, vec![None, Some(Transform::ErrnoName)]);
+        let program = bpftrace(sql);
+        assert!(program.starts_with("kretprobe:vfs_read /((int64)retval) < 0/"), "{}", program);
+        assert!(program.contains("$q1_0 = ((int64)retval);\n$q1_1 = (int64)retval;"), "{}", program);
+
+        let program = bpftrace("select retval from kretprobe.do_filp_open");
+        assert!(program.contains("$q1_0 = ((struct file *)retval);"), "{}", program);
+        // not in the catalog, so nothing to cast to
+        assert!(bpftrace("select retval from kretprobe.nope").contains("$q1_0 = retval;"));
+    }
+
+    #[test]
+    fn retval_casts() {
+        assert_eq!(retval_cast("int").as_deref(), Some("int32"));
+        assert_eq!(retval_cast("unsigned long").as_deref(), Some("uint64"));
+        assert_eq!(retval_cast("const struct file *").as_deref(), Some("struct file *"));
+        assert_eq!(retval_cast("void *"), None);
+        assert_eq!(retval_cast("struct file"), None);
+    }
+
+    #[test]
+    fn only_bpftrace_needs_a_target() {
+        let needs = |sql: &str| needs_target(&parse_bpfquery_sql(sql).unwrap()[0]);
+        assert!(needs("select pid from kprobe.vfs_read"));
+        assert!(needs("select pid from uprobe.\"/bin/bash\".readline"));
+        assert!(!needs("select name from system.probes"));
+        assert!(!needs("describe kprobe.vfs_read"));
+        assert!(!needs("create view v as select pid from kprobe.vfs_read"));
+        assert!(!needs("drop view v"));
+        assert!(!needs("create function kb(x) as x / 1024"));
+    }
+
+    #[test]
+    fn system_queries() {
+        let query = compile("select name from system.views where source = 'builtin'");
+        assert_eq!(query.program, Program::Catalog("SELECT name FROM views WHERE source = 'builtin'".to_string()));
+        assert_eq!(query.headers, vec!["name"]);
+        assert!(matches!(compile("describe kprobe.vfs_read").program, Program::Catalog(_)));
+        assert!(matches!(compile("describe system.views").program, Program::Catalog(_)));
+        for sql in ["delete from system.views", "update system.views set name = 'x'"] {
+            assert_eq!(error(sql, Target::default()), "System tables are read only, only SELECT works on them");
+        }
+        assert!(error("select * from information_schema.nope", Target::default())
+            .starts_with("Unknown system table information_schema.nope, try one of"));
+        assert_eq!(error("describe information_schema.nope", Target::default()), "Unknown system table information_schema.nope");
+        assert_eq!(error("describe system.nope", Target::default()), "Unknown system table system.nope");
+    }
+
+    #[test]
+    fn describe_probes() {
+        let query = compile("describe kprobe.vfs_read");
+        assert_eq!(query.headers, vec!["name", "c_type", "sql_type"]);
+        let Program::Catalog(sql) = query.program else { panic!("describe isn't a catalog query") };
+        assert!(sql.contains("('file', 'struct file *', 'POINTER'), ('buf', 'char __user *', 'TEXT')"));
+        let Program::Catalog(sql) = compile("describe tracepoint.syscalls.sys_enter_openat").program else {
+            panic!("describe isn't a catalog query")
+        };
+        // common_ fields are the tracepoint's bookkeeping, not arguments
+        assert!(sql.contains("('dfd', 'int', 'INTEGER'), ('filename', 'const char *', 'TEXT')"));
+        assert!(!sql.contains("common_type"));
+    }
+
+    #[test]
+    fn constants_and_flags() {
+        let query = compile(
+            "select decode_flags(args.flags, 'open_flags') from tracepoint.syscalls.sys_enter_openat where args.flags & O_CREAT and args.dfd = AT_FDCWD",
+        );
+        // constants are filled in by value, the flags are named after bpftrace is done
+        let Program::Bpftrace(program) = query.program else { panic!("expected bpftrace") };
+        assert!(program.starts_with("tracepoint:syscalls:sys_enter_openat /args.flags & 64 && args.dfd == -100/"), "{}", program);
+        assert!(program.contains("$q1_0 = args.flags;"), "{}", program);
+        assert_eq!(query.transforms, vec![Some(Transform::DecodeFlags("open_flags".to_string()))]);
+        assert!(error("select decode_flags(pid, 'nope') from kprobe.vfs_read", Target::default()).starts_with("Unknown flags nope, try one of"));
+    }
+
+    #[test]
+    fn group_by() {
+        let query = compile("select comm, count(*), avg(pid) from kprobe.vfs_read group by comm");
+        assert_eq!(query.headers, vec!["comm", "count(*)", "avg(pid)"]);
+        assert_eq!(
+            query.group_by,
+            Some(GroupBy {
+                keys: vec![0],
+                columns: vec![
+                    AggregateColumn::Key(0),
+                    AggregateColumn::Aggregate(Aggregate::Count, None),
+                    AggregateColumn::Aggregate(Aggregate::Avg, Some(1)),
+                ],
+            })
+        );
+        let program = bpftrace("select comm, count(*), avg(pid) from kprobe.vfs_read group by comm");
+        assert!(program.contains("$q1_0 = comm;\n$q1_1 = pid;"), "{}", program);
+
+        let having = "select comm, count(*) from kprobe.vfs_read group by comm having count(*) > 1";
+        assert_eq!(error(having, Target::default()), "HAVING isn't supported yet");
+        for sql in ["select comm from kprobe.vfs_read order by comm", "select comm from kprobe.vfs_read limit 10"] {
+            assert!(error(sql, Target::default()).starts_with("ORDER BY and LIMIT aren't supported yet"));
+        }
+    }
+
+    #[test]
+    fn syscalls() {
+        let query = compile("select comm, name, count(*) from syscalls group by comm, name");
+        assert_eq!(query.transforms[1], Some(Transform::SyscallName));
+        let program = bpftrace("select comm, name, count(*) from syscalls group by comm, name");
+        // entry and exit are paired by tid, the number is only named once it's back
+        assert!(program.starts_with("tracepoint:raw_syscalls:sys_enter {\n@q1_start[tid] = nsecs;\n}\n"), "{}", program);
+        assert!(program.contains("tracepoint:raw_syscalls:sys_exit /@q1_start[tid]/ {"), "{}", program);
+        assert!(program.contains("$q1_1 = args.id;"), "{}", program);
+        assert!(program.contains("delete(@q1_start[tid]);"), "{}", program);
+        let program = bpftrace("select duration from syscalls where name = 'openat'");
+        assert!(program.contains("if (args.id == 257) {"), "{}", program);
+
+        let aarch64 = Target {
+            arch: Some(Arch::Other),
+            ..Target::default()
+        };
+        assert!(error("select * from syscalls where name = 'openat'", aarch64).starts_with("syscalls only works on x86_64"));
+    }
+
+    #[test]
+    fn views_are_inlined() {
+        let query = compile("select * from tcp_connect where dport = 443");
+        assert_eq!(query.headers, vec!["comm", "pid", "family", "saddr", "daddr", "sport", "dport"]);
+        let program = bpftrace("select * from tcp_connect where dport = 443");
+        assert!(program.starts_with("tracepoint:sock:inet_sock_set_state /"), "{}", program);
+        assert!(program.contains("(args.dport == 443)"), "{}", program);
+    }
+
+    #[test]
+    fn joins_are_paired() {
+        let sql = "select s.pid, e.retval from kprobe.vfs_read s join kretprobe.vfs_read e on s.tid = e.tid";
+        let program = bpftrace(sql);
+        assert!(program.starts_with("kprobe:vfs_read {\n@q1_start[tid] = nsecs;\n@q1_s0[tid] = pid;\n}"), "{}", program);
+        assert!(program.contains("kretprobe:vfs_read /@q1_start[tid]/ {"), "{}", program);
+        assert!(program.contains("$q1_0 = @q1_s0[tid];\n$q1_1 = ((int64)retval);"), "{}", program);
+        assert!(program.contains("delete(@q1_start[tid]);\ndelete(@q1_s0[tid]);"), "{}", program);
+        assert_eq!(compile(sql).headers, vec!["pid", "retval"]);
+
+        let program = bpftrace("select * from block_io");
+        assert!(program.contains("@q1_start[args.dev, args.sector] = nsecs;"), "{}", program);
+
+        let no_alias = "select pid from kprobe.vfs_read join kretprobe.vfs_read e on tid = e.tid";
+        assert!(error(no_alias, Target::default()).starts_with("Both sides of a JOIN need an alias"));
+        let not_equal = "select s.pid from kprobe.vfs_read s join kretprobe.vfs_read e on s.tid > e.tid";
+        assert!(error(not_equal, Target::default()).starts_with("JOIN ... ON can only match"));
+    }
+
+    // compiles a CREATE or DROP and does it
+    fn define(sql: &str) -> Result<String, String> {
+        match compile_for(sql, Target::default())?.program {
+            Program::Define(d) => d.run(),
+            p => panic!("expected a definition, got {}", p),
+        }
+    }
+
+    #[test]
+    fn user_views() {
+        define("create view bash_reads as select pid, comm from kprobe.vfs_read where comm = 'bash'").unwrap();
+        let program = bpftrace("select pid from bash_reads where pid > 1");
+        assert!(program.starts_with("kprobe:vfs_read /"), "{}", program);
+        assert!(program.contains("comm == \"bash\"") && program.contains("pid > 1"), "{}", program);
+
+        let again = "create view bash_reads as select pid from kprobe.vfs_read";
+        assert!(error(again, Target::default()).starts_with("View bash_reads already exists"));
+        assert_eq!(
+            define("create or replace view bash_reads as select pid, comm from kprobe.vfs_write where comm = 'bash'"),
+            Ok("Created view bash_reads".to_string())
+        );
+        assert!(bpftrace("select pid from bash_reads").starts_with("kprobe:vfs_write /"));
+        assert_eq!(compile("describe bash_reads").headers, vec!["name", "c_type", "sql_type"]);
+
+        assert_eq!(define("drop view bash_reads"), Ok("Dropped view bash_reads".to_string()));
+        // it's only a name bpftrace won't know now
+        assert!(bpftrace("select pid from bash_reads").starts_with("bash_reads\n"));
+        assert_eq!(define("drop view bash_reads"), Err("Unknown view bash_reads".to_string()));
+        assert_eq!(define("drop view if exists bash_reads"), Ok("There was no view bash_reads".to_string()));
+        assert_eq!(define("drop view tcp_connect"), Err("tcp_connect is a built-in view and can't be dropped".to_string()));
+        assert!(error("create view tcp_connect as select pid from kprobe.vfs_read", Target::default())
+            .starts_with("tcp_connect is a built-in view"));
+    }
+
+    #[test]
+    fn ctes_and_subqueries_are_inlined() {
+        let cte = "with opens as (select comm, pid from tracepoint.syscalls.sys_enter_openat where pid > 1) \
+                   select comm from opens where comm = 'bash'";
+        let program = bpftrace(cte);
+        assert!(program.starts_with("tracepoint:syscalls:sys_enter_openat /"), "{}", program);
+        assert!(program.contains("pid > 1") && program.contains("comm == \"bash\""), "{}", program);
+        assert_eq!(compile(cte).headers, vec!["comm"]);
+
+        let program = bpftrace("select pid from (select pid, comm from kprobe.vfs_read) where comm = 'bash'");
+        assert!(program.starts_with("kprobe:vfs_read /comm == \"bash\"/"), "{}", program);
+    }
+
+    #[test]
+    fn user_functions() {
+        assert_eq!(define("create or replace function kb(x) as x / 1024"), Ok("Created function kb".to_string()));
+        let program = bpftrace("select kb(retval) from kretprobe.vfs_read where kb(retval) > 1");
+        assert!(program.starts_with("kretprobe:vfs_read /(((int64)retval) / 1024) > 1/"), "{}", program);
+        assert!(program.contains("$q1_0 = (((int64)retval) / 1024);"), "{}", program);
+
+        // quotes survive being saved, and backslashes and quotes are escaped for bpftrace
+        define("create or replace function quoted(s) as starts_with(s, 'it''s \"hi\" \\')").unwrap();
+        let program = bpftrace("select comm from kprobe.vfs_read where quoted(comm)");
+        assert!(program.starts_with(r#"kprobe:vfs_read /(strncmp(comm, "it's \"hi\" \\", 11) == 0)/"#), "{}", program);
+
+        assert_eq!(define("drop function quoted"), Ok("Dropped function quoted".to_string()));
+        assert!(error("select comm from kprobe.vfs_read where quoted(comm)", Target::default()).starts_with("Unknown function quoted"));
+    }
+
+    #[test]
+    fn string_functions() {
+        // whole columns are done once the row is back
+        let query = compile("select lower(comm), length(comm), concat(comm, '!') from kprobe.vfs_read");
+        assert_eq!(query.transforms, vec![Some(Transform::Lower), Some(Transform::Length), Some(Transform::Concat)]);
+        let Program::Bpftrace(program) = query.program else { panic!("expected bpftrace") };
+        assert!(program.contains("$q1_0 = comm;\n$q1_1 = comm;\n$q1_2 = (comm, \"!\");"), "{}", program);
+
+        // filters need bpftrace to have them
+        let newer = Target {
+            strlen: true,
+            substr: true,
+            ..Target::default()
+        };
+        let sql = "select comm from kprobe.vfs_read where length(comm) > 3 and substr(comm, 1, 2) = 'ba'";
+        let Program::Bpftrace(program) = compile_for(sql, newer).unwrap().program else { panic!("expected bpftrace") };
+        assert!(program.starts_with("kprobe:vfs_read /strlen(comm) > 3 && substr(comm, 0, 2) == \"ba\"/"), "{}", program);
+        assert_eq!(error(sql, Target::default()), "length can't be used in a filter");
+        let sql = "select comm from kprobe.vfs_read where substr(comm, 0, 2) = 'b'";
+        assert_eq!(error(sql, newer), "substr can't be used in a filter");
+        let sql = "select comm from kprobe.vfs_read where lower(comm) = 'ba'";
+        assert_eq!(error(sql, newer), "lower can't be used in a filter");
+
+        assert_eq!(error("select upper(comm, 1) from kprobe.vfs_read", newer), "Expected upper(s)");
+        assert_eq!(error("select lowr(comm) from kprobe.vfs_read", newer), "Unknown function lowr, did you mean lower?");
+    }
+
+    #[test]
+    fn stack_columns() {
+        let query = compile("select kstack, ustack(5) from kprobe.vfs_read");
+        assert_eq!(query.headers, vec!["kstack", "ustack(5)"]);
+        assert_eq!(query.transforms, vec![Some(Transform::Stack), Some(Transform::Stack)]);
+        let Program::Bpftrace(program) = query.program else { panic!("expected bpftrace") };
+        assert!(program.contains("$q1_0 = kstack;\n$q1_1 = ustack(5);"), "{}", program);
+        assert!(compile("select * from kprobe.vfs_read").headers.iter().all(|h| h != "kstack"));
+    }
+
+    #[test]
+    fn sampling() {
+        let program = bpftrace("select comm, kstack from profile.hz.99 where pid > 0");
+        assert!(program.starts_with("profile:hz:99 /pid > 0/"), "{}", program);
+        assert!(program.contains("$q1_0 = comm;\n$q1_1 = kstack;"), "{}", program);
+        assert_eq!(
+            compile("select * from profile.hz.99").headers,
+            vec!["comm", "pid", "tid", "cpu", "elapsed", "kstack", "ustack"]
+        );
+
+        let query = compile("select count(*) from interval.s.1");
+        assert_eq!(query.headers, vec!["count(*)"]);
+        let Program::Bpftrace(program) = query.program else { panic!("expected bpftrace") };
+        assert!(program.starts_with("interval:s:1\n"), "{}", program);
+        assert_eq!(compile("select * from interval.s.1").headers, vec!["cpu", "elapsed"]);
+    }
+
+    #[test]
+    fn kfunc_or_fentry() {
+        let btf = Target {
+            btf: true,
+            ..Target::default()
+        };
+        let old = Target { kfunc: true, ..btf };
+        let program = |sql: &str, target: Target| match compile_for(sql, target).unwrap().program {
+            Program::Bpftrace(p) => p,
+            p => panic!("expected bpftrace, got {}", p),
+        };
+        assert!(program("select pid from kprobe.vfs_read", btf).starts_with("fentry:vfs_read\n"));
+        assert!(program("select pid from kprobe.vfs_read", old).starts_with("kfunc:vfs_read\n"));
+        assert!(program("select retval from kretprobe.vfs_read", btf).starts_with("fexit:vfs_read\n"));
+        assert!(program("select retval from kretprobe.vfs_read", old).starts_with("kretfunc:vfs_read\n"));
+        // either spelling in sql, the host's in bpftrace
+        assert!(program("select pid from kfunc.vfs_read", btf).starts_with("fentry:vfs_read\n"));
+        assert!(program("select pid from fentry.vfs_read", old).starts_with("kfunc:vfs_read\n"));
+        let join = "select s.pid from fentry.vfs_read s join fexit.vfs_read e on s.tid = e.tid";
+        let program = program(join, old);
+        assert!(program.starts_with("kfunc:vfs_read {"), "{}", program);
+        assert!(program.contains("kretfunc:vfs_read /@q1_start[tid]/ {"), "{}", program);
+    }
+
+    #[test]
+    fn args_for_old_bpftrace() {
+        assert_eq!(old_args("$x = args.filename; @a[args.fd]"), "$x = args->filename; @a[args->fd]");
+        // only the builtin, not in strings or other names
+        assert_eq!(
+            old_args(r#"if (comm == "args.x \"args.y\"") { $args.z = myargs.w; }"#),
+            r#"if (comm == "args.x \"args.y\"") { $args.z = myargs.w; }"#
+        );
+        let old = Target {
+            version: Some(Version(0, 18, 0)),
+            ..Target::default()
+        };
+        let sql = "select args.filename from tracepoint.syscalls.sys_enter_openat where comm = 'args.x'";
+        let program = match compile_for(sql, old).unwrap().program {
+            Program::Bpftrace(p) => p,
+            p => panic!("expected bpftrace, got {}", p),
+        };
+        assert!(program.contains("comm == \"args.x\""), "{}", program);
+        assert!(program.contains("args->filename"), "{}", program);
+    }
 
-    if quick_exit {
-        bpftrace.push_str("exit();\n");
+    #[test]
+    fn versions() {
+        assert_eq!(Version::parse("bpftrace v0.20.2"), Some(Version(0, 20, 2)));
+        assert_eq!(Version::parse("bpftrace v0.21.0-83-g1f1a7e2"), Some(Version(0, 21, 0)));
+        assert_eq!(Version::parse("bpftrace"), None);
     }
 
-    bpftrace.push_str(" }");
-    Ok((bpftrace, headers))
+    #[test]
+    fn targets() {
+        let old = Target {
+            version: Some(Version(0, 18, 0)),
+            ..Target::default()
+        };
+        let sql = "select comm from kprobe.vfs_read where contains(comm, 'ba')";
+        assert_eq!(error(sql, old), "strcontains needs bpftrace 0.20.0 or newer, this is 0.18.0");
+        assert!(compile_for(sql, Target::default()).is_ok());
+        let too_old = Target {
+            version: Some(Version(0, 15, 0)),
+            ..Target::default()
+        };
+        assert!(error("select pid from kprobe.vfs_read", too_old).contains("is too old"));
+    }
 }
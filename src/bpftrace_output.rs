@@ -0,0 +1,226 @@
+// What `bpftrace -f json` prints, one json object a line, e.g.
+//
+//   {"type": "attached_probes", "data": {"probes": 1}}
+//   {"type": "value", "data": [["id", 1], [0, "bash"]]}
+//   {"type": "map", "data": {"@q1_id": {"id": 1}}}
+//
+// Lines that don't look like that are an error rather than a panic, bpftrace
+// can print things we haven't seen yet.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// One of a histogram's buckets, the first and last are open ended.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Bucket {
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+    pub count: u64,
+}
+
+/// A hist() or lhist() map, with or without keys.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Hist {
+    Buckets(Vec<Bucket>),
+    Keyed(BTreeMap<String, Vec<Bucket>>),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Stat {
+    pub count: u64,
+    pub average: i64,
+    pub total: i64,
+}
+
+/// A stats() map, with or without keys.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Stats {
+    Stat(Stat),
+    Keyed(BTreeMap<String, Stat>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// One of our print((("id", n), (0, a), (1, b), ...)) rows, the columns
+    /// by their number.
+    Row(Vec<(usize, Value)>),
+    Printf(String),
+    /// A map, when it's printed or when bpftrace exits, by its name.
+    Map(BTreeMap<String, Value>),
+    Hist(BTreeMap<String, Hist>),
+    Stats(BTreeMap<String, Stats>),
+    /// Events the kernel dropped because they came faster than they were
+    /// read.
+    LostEvents(u64),
+    /// How many probes bpftrace attached, once it's ready.
+    AttachedProbes(u64),
+    Time(String),
+    /// The program ran into something as it ran, e.g. a helper failing.
+    Error(String),
+    /// Something else bpftrace prints that queries don't use, e.g. join.
+    Other(String),
+}
+
+#[derive(Deserialize)]
+struct Line {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    data: Value,
+    #[serde(default)]
+    msg: Option<String>,
+}
+
+impl Event {
+    /// Reads one line, saying what's wrong with it if it isn't something
+    /// bpftrace would print.
+    pub fn parse(line: &str) -> Result<Event, String> {
+        let line: Line = serde_json::from_str(line)
+            .map_err(|e| format!("Unexpected output from bpftrace, {}: {}", e, line))?;
+        let data = line.data;
+        let bad = |e: serde_json::Error| format!("Unexpected {} output from bpftrace: {}", line.kind, e);
+        Ok(match line.kind.as_str() {
+            "value" => Event::Row(row(&data).ok_or_else(|| format!("Unexpected row from bpftrace: {}", data))?),
+            "printf" => Event::Printf(text(data)),
+            "map" => Event::Map(serde_json::from_value(data).map_err(bad)?),
+            "hist" => Event::Hist(serde_json::from_value(data).map_err(bad)?),
+            "stats" => Event::Stats(serde_json::from_value(data).map_err(bad)?),
+            "lost_events" => Event::LostEvents(count(&data, "events").ok_or_else(|| bad_count(&line.kind, &data))?),
+            "attached_probes" => Event::AttachedProbes(count(&data, "probes").ok_or_else(|| bad_count(&line.kind, &data))?),
+            "time" => Event::Time(text(data)),
+            kind if kind == "error" || kind.ends_with("_error") => Event::Error(line.msg.unwrap_or_else(|| text(data))),
+            kind => Event::Other(kind.to_string()),
+        })
+    }
+}
+
+// e.g. {"events": 3}
+fn count(data: &Value, key: &str) -> Option<u64> {
+    data.get(key)?.as_u64()
+}
+
+fn bad_count(kind: &str, data: &Value) -> String {
+    format!("Unexpected {} output from bpftrace: {}", kind, data)
+}
+
+fn text(v: Value) -> String {
+    match v {
+        Value::String(s) => s,
+        v => v.to_string(),
+    }
+}
+
+// the numbered columns of a print((("id", n), (0, a), ...)), the id is left
+// out since rows get numbered again once every host's are merged
+fn row(data: &Value) -> Option<Vec<(usize, Value)>> {
+    let mut columns = vec![];
+    for kv in data.as_array()? {
+        match kv.as_array()?.as_slice() {
+            [Value::Number(i), v] => columns.push((i.as_u64()? as usize, v.clone())),
+            [Value::String(_), _] => {}
+            _ => return None,
+        }
+    }
+    Some(columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn value_rows() {
+        let line = r#"{"type": "value", "data": [["id", 7], [0, "bash"], [1, 42]]}"#;
+        assert_eq!(
+            Event::parse(line),
+            Ok(Event::Row(vec![(0, json!("bash")), (1, json!(42))]))
+        );
+        assert!(Event::parse(r#"{"type": "value", "data": [[0]]}"#).is_err());
+        assert!(Event::parse(r#"{"type": "value", "data": 3}"#).is_err());
+    }
+
+    #[test]
+    fn maps() {
+        let line = r#"{"type": "map", "data": {"@q1_id": {"id": 1}}}"#;
+        let map = BTreeMap::from([("@q1_id".to_string(), json!({"id": 1}))]);
+        assert_eq!(Event::parse(line), Ok(Event::Map(map)));
+        assert!(Event::parse(r#"{"type": "map", "data": [1, 2]}"#).is_err());
+    }
+
+    #[test]
+    fn printf_and_time() {
+        let line = r#"{"type": "printf", "data": "opened /etc/passwd\n"}"#;
+        assert_eq!(Event::parse(line), Ok(Event::Printf("opened /etc/passwd\n".to_string())));
+        let line = r#"{"type": "time", "data": "10:04:05\n"}"#;
+        assert_eq!(Event::parse(line), Ok(Event::Time("10:04:05\n".to_string())));
+    }
+
+    #[test]
+    fn hists() {
+        let line = r#"{"type": "hist", "data": {"@h": [{"max": -1, "count": 1}, {"min": 0, "max": 1, "count": 2}, {"min": 2, "count": 1}]}}"#;
+        let buckets = vec![
+            Bucket { min: None, max: Some(-1), count: 1 },
+            Bucket { min: Some(0), max: Some(1), count: 2 },
+            Bucket { min: Some(2), max: None, count: 1 },
+        ];
+        let hist = BTreeMap::from([("@h".to_string(), Hist::Buckets(buckets.clone()))]);
+        assert_eq!(Event::parse(line), Ok(Event::Hist(hist)));
+
+        let line = r#"{"type": "hist", "data": {"@h": {"bash": [{"max": -1, "count": 1}, {"min": 0, "max": 1, "count": 2}, {"min": 2, "count": 1}]}}}"#;
+        let keyed = Hist::Keyed(BTreeMap::from([("bash".to_string(), buckets)]));
+        assert_eq!(Event::parse(line), Ok(Event::Hist(BTreeMap::from([("@h".to_string(), keyed)]))));
+        assert!(Event::parse(r#"{"type": "hist", "data": {"@h": [{"min": 0}]}}"#).is_err());
+    }
+
+    #[test]
+    fn stats() {
+        let stat = Stat { count: 2, average: 3, total: 6 };
+        let line = r#"{"type": "stats", "data": {"@s": {"count": 2, "average": 3, "total": 6}}}"#;
+        let stats = BTreeMap::from([("@s".to_string(), Stats::Stat(stat.clone()))]);
+        assert_eq!(Event::parse(line), Ok(Event::Stats(stats)));
+
+        let line = r#"{"type": "stats", "data": {"@s": {"bash": {"count": 2, "average": 3, "total": 6}}}}"#;
+        let keyed = Stats::Keyed(BTreeMap::from([("bash".to_string(), stat)]));
+        assert_eq!(Event::parse(line), Ok(Event::Stats(BTreeMap::from([("@s".to_string(), keyed)]))));
+        assert!(Event::parse(r#"{"type": "stats", "data": {"@s": 3}}"#).is_err());
+    }
+
+    #[test]
+    fn attached_probes() {
+        let line = r#"{"type": "attached_probes", "data": {"probes": 2}}"#;
+        assert_eq!(Event::parse(line), Ok(Event::AttachedProbes(2)));
+        assert!(Event::parse(r#"{"type": "attached_probes", "data": {}}"#).is_err());
+    }
+
+    #[test]
+    fn lost_events() {
+        let line = r#"{"type": "lost_events", "data": {"events": 3}}"#;
+        assert_eq!(Event::parse(line), Ok(Event::LostEvents(3)));
+        assert!(Event::parse(r#"{"type": "lost_events", "data": {"events": "lots"}}"#).is_err());
+        assert!(Event::parse(r#"{"type": "lost_events"}"#).is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let line = r#"{"type": "helper_error", "msg": "Failed to probe_read", "data": {}}"#;
+        assert_eq!(Event::parse(line), Ok(Event::Error("Failed to probe_read".to_string())));
+        let line = r#"{"type": "error", "data": "oops"}"#;
+        assert_eq!(Event::parse(line), Ok(Event::Error("oops".to_string())));
+    }
+
+    #[test]
+    fn unknown_types() {
+        let line = r#"{"type": "something_new", "data": [1, 2, 3]}"#;
+        assert_eq!(Event::parse(line), Ok(Event::Other("something_new".to_string())));
+    }
+
+    #[test]
+    fn malformed_lines() {
+        for line in ["", "Attaching 1 probe...", "{", "[1, 2]", r#"{"data": 1}"#, r#"{"type": 5}"#] {
+            assert!(Event::parse(line).is_err(), "{}", line);
+        }
+    }
+}
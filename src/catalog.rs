@@ -0,0 +1,584 @@
+use crate::elf::{self, UsdtProbe};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::UNIX_EPOCH;
+
+// Built ahead of time by running ctags across the linux kernel source code.
+//
+// function(function_name, signature, return_type)
+//   signature looks like `(const struct path * path,struct file * file)`
+//   return_type looks like `struct file *`
+// tracepoint_field(category, event, field_name, field_type)
+//   the fields from /sys/kernel/tracing/events/<category>/<event>/format
+#[cfg(not(test))]
+const KERNEL_DEFINITIONS: &str = "linux_kernel_definitions.db";
+// tests see the few definitions in kernel_definitions_for_tests, or none
+#[cfg(test)]
+const KERNEL_DEFINITIONS: &str = "file:kernel_definitions?mode=memory&cache=shared";
+
+// Written by bpfquery itself as it learns about things, lives next to the
+// kernel definitions.
+//
+// binary(path, modified)
+//   binaries that have been read for uprobes, modified is the mtime in seconds
+// binary_function(path, function_name, signature, return_type)
+//   same as function, signature and return_type are null without DWARF
+// binary_usdt(path, provider, probe_name, arguments, semaphore)
+//   the USDT probes in the binary, arguments is the raw spec like `-4@%edi 8@%rsi`
+//   and semaphore is null for probes that aren't guarded by one
+// user_view(name, sql)
+//   views made with CREATE VIEW, sql is the select they stand for
+// user_function(name, args, body)
+//   functions made with CREATE FUNCTION, args is comma separated and body is
+//   the expression they stand for
+#[cfg(not(test))]
+const LOCAL_DEFINITIONS: &str = "bpfquery.db";
+// tests start from an empty catalog of their own rather than whatever is in
+// the working directory, and leave nothing behind
+#[cfg(test)]
+const LOCAL_DEFINITIONS: &str = ":memory:";
+
+const LOCAL_SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS binary (path TEXT PRIMARY KEY, modified INTEGER);
+CREATE TABLE IF NOT EXISTS binary_function (path TEXT, function_name TEXT, signature TEXT, return_type TEXT);
+CREATE INDEX IF NOT EXISTS binary_function_path ON binary_function (path, function_name);
+CREATE TABLE IF NOT EXISTS binary_usdt (path TEXT, provider TEXT, probe_name TEXT, arguments TEXT, semaphore INTEGER);
+CREATE TABLE IF NOT EXISTS user_view (name TEXT PRIMARY KEY, sql TEXT);
+CREATE TABLE IF NOT EXISTS user_function (name TEXT PRIMARY KEY, args TEXT, body TEXT);
+";
+
+/// A named and typed value, either a function argument or a tracepoint field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arg {
+    pub name: String,
+    pub c_type: String,
+}
+
+impl Arg {
+    pub fn is_string(&self) -> bool {
+        let t = self
+            .c_type
+            .replace("const", "")
+            .replace("__user", "")
+            .replace(' ', "");
+        t == "char*" || t.starts_with("__data_loc char") || t.starts_with("char[")
+    }
+}
+
+/// The bpfquery type a C type comes back as: strings and stacks are TEXT,
+/// pointers stay pointers so they can be followed with ->, everything else
+/// that fits in a register is an INTEGER.
+pub fn sql_type(c_type: &str) -> &'static str {
+    let t = c_type.trim();
+    let arg = Arg {
+        name: String::new(),
+        c_type: t.to_string(),
+    };
+    if arg.is_string() || t == "kstack" || t == "ustack" {
+        "TEXT"
+    } else if t == "bool" || t == "_Bool" {
+        "BOOLEAN"
+    } else if t.ends_with('*') || t.ends_with(')') {
+        "POINTER"
+    } else if (t.starts_with("struct") || t.starts_with("union")) && !t.ends_with(']') {
+        "STRUCT"
+    } else if t.ends_with(']') {
+        "ARRAY"
+    } else {
+        "INTEGER"
+    }
+}
+
+fn open() -> Option<Connection> {
+    // read only so that a missing catalog doesn't leave an empty db behind
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI;
+    Connection::open_with_flags(KERNEL_DEFINITIONS, flags).ok()
+}
+
+/// Fills the kernel catalog tests see with a couple of functions and a
+/// tracepoint. It lives as long as the connection that made it, so that's
+/// kept for good.
+#[cfg(test)]
+pub fn kernel_definitions_for_tests() {
+    static DEFINITIONS: OnceLock<Mutex<Connection>> = OnceLock::new();
+    DEFINITIONS.get_or_init(|| {
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_URI;
+        let conn = Connection::open_with_flags(KERNEL_DEFINITIONS, flags).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE function (function_name TEXT, signature TEXT, return_type TEXT);
+             INSERT INTO function VALUES
+                 ('vfs_read', '(struct file * file,char __user * buf,size_t count,loff_t * pos)', 'ssize_t'),
+                 ('do_filp_open', '(int dfd,struct filename * pathname,const struct open_flags * op)', 'struct file *');
+             CREATE TABLE tracepoint_field (category TEXT, event TEXT, field_name TEXT, field_type TEXT);
+             INSERT INTO tracepoint_field VALUES
+                 ('syscalls', 'sys_enter_openat', 'common_type', 'unsigned short'),
+                 ('syscalls', 'sys_enter_openat', 'dfd', 'int'),
+                 ('syscalls', 'sys_enter_openat', 'filename', 'const char *'),
+                 ('syscalls', 'sys_enter_openat', 'flags', 'int');",
+        )
+        .unwrap();
+        Mutex::new(conn)
+    });
+}
+
+/// What's missing from linux_kernel_definitions.db and what goes without
+/// because of it. Older catalogs only have function(function_name,
+/// signature), lookups of the rest just find nothing.
+pub fn missing_kernel_definitions() -> Vec<String> {
+    let conn = match open() {
+        Some(c) => c,
+        None => {
+            return vec![format!(
+                "{} not found, kprobe arguments, retval types and tracepoint fields are unknown",
+                KERNEL_DEFINITIONS
+            )]
+        }
+    };
+    let has = |sql: &str| conn.prepare(sql).is_ok();
+    let mut missing = vec![];
+    if !has("SELECT function_name, signature from function") {
+        missing.push(format!(
+            "{} has no function table, kprobe arguments are unknown",
+            KERNEL_DEFINITIONS
+        ));
+    } else if !has("SELECT return_type from function") {
+        missing.push(format!(
+            "{} has no function.return_type, kretprobe retval isn't cast to its type",
+            KERNEL_DEFINITIONS
+        ));
+    }
+    if !has("SELECT category, event, field_name, field_type from tracepoint_field") {
+        missing.push(format!(
+            "{} has no tracepoint_field table, select * on tracepoints only has the builtins",
+            KERNEL_DEFINITIONS
+        ));
+    }
+    missing
+}
+
+// opened once and shared, lookups happen on every keystroke
+static LOCAL: OnceLock<Option<Mutex<Connection>>> = OnceLock::new();
+
+fn open_local() -> Option<MutexGuard<'static, Connection>> {
+    let local = LOCAL
+        .get_or_init(|| {
+            let conn = Connection::open(LOCAL_DEFINITIONS)
+                .and_then(|conn| conn.execute_batch(LOCAL_SCHEMA).map(|_| conn))
+                .inspect(|conn| {
+                    // catalogs from before semaphores were indexed, fails once it's there
+                    let _ = conn.execute("ALTER TABLE binary_usdt ADD COLUMN semaphore INTEGER", []);
+                });
+            match conn {
+                Ok(conn) => Some(Mutex::new(conn)),
+                Err(e) => {
+                    println!("Couldn't open {}: {}", LOCAL_DEFINITIONS, e);
+                    None
+                }
+            }
+        })
+        .as_ref()?;
+    // a panic while it was held doesn't leave sqlite in a bad state
+    Some(local.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+fn format_signature(args: &[Arg]) -> String {
+    let args = args
+        .iter()
+        .map(|a| format!("{} {}", a.c_type, a.name))
+        .collect::<Vec<String>>();
+    format!("({})", args.join(","))
+}
+
+// reads the binary into the catalog unless it's already there and unchanged
+fn index_binary(conn: &mut Connection, path: &str) -> Option<()> {
+    let modified = std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs() as i64;
+    let indexed: Option<i64> = conn
+        .query_row(
+            "SELECT modified from binary where path = ?",
+            params![path],
+            |row| row.get(0),
+        )
+        .optional()
+        .ok()?;
+    if indexed == Some(modified) {
+        return Some(());
+    }
+
+    let binary = match elf::read_binary(path) {
+        Ok(b) => b,
+        Err(e) => {
+            println!("{}", e);
+            return None;
+        }
+    };
+    let tx = conn.transaction().ok()?;
+    tx.execute("DELETE from binary_function where path = ?", params![path])
+        .ok()?;
+    tx.execute("DELETE from binary_usdt where path = ?", params![path])
+        .ok()?;
+    tx.execute(
+        "INSERT OR REPLACE into binary (path, modified) values (?, ?)",
+        params![path, modified],
+    )
+    .ok()?;
+    for f in binary.functions {
+        tx.execute(
+            "INSERT into binary_function (path, function_name, signature, return_type) values (?, ?, ?, ?)",
+            params![path, f.name, f.args.as_deref().map(format_signature), f.return_type],
+        )
+        .ok()?;
+    }
+    for p in binary.usdt_probes {
+        tx.execute(
+            "INSERT into binary_usdt (path, provider, probe_name, arguments, semaphore) values (?, ?, ?, ?, ?)",
+            params![path, p.provider, p.name, p.arguments, p.semaphore.map(|s| s as i64)],
+        )
+        .ok()?;
+    }
+    tx.commit().ok()
+}
+
+fn parse_signature(sig: &str) -> Vec<Arg> {
+    let cleaned = sig.replace(['(', ')'], "").replace("const ", "");
+    if cleaned.trim().is_empty() || cleaned.trim() == "void" {
+        return vec![];
+    }
+    cleaned
+        .split(',')
+        .map(|s| {
+            let s = s.trim();
+            // the name is the last word, pointers can be stuck to either side
+            let split = s.rfind([' ', '*']).map(|i| i + 1).unwrap_or(0);
+            Arg {
+                name: s[split..].to_string(),
+                c_type: s[..split].trim().to_string(),
+            }
+        })
+        .collect()
+}
+
+/// The arguments of a kernel function, in order, or None if the function isn't
+/// in the catalog.
+pub fn function_args(function_name: &str) -> Option<Vec<Arg>> {
+    let conn = open()?;
+    let sig: String = conn
+        .query_row(
+            "SELECT signature from function where function_name = ?",
+            params![function_name],
+            |row| row.get(0),
+        )
+        .ok()?;
+    Some(parse_signature(&sig))
+}
+
+/// What a kernel function returns, None if it isn't in the catalog.
+pub fn function_return_type(function_name: &str) -> Option<String> {
+    let conn = open()?;
+    conn.query_row(
+        "SELECT return_type from function where function_name = ?",
+        params![function_name],
+        |row| row.get(0),
+    )
+    .ok()
+}
+
+/// A kernel function from the catalog.
+#[derive(Debug, Clone)]
+pub struct KernelFunction {
+    pub name: String,
+    pub args: Vec<Arg>,
+    pub return_type: Option<String>,
+}
+
+/// Every kernel function in the catalog.
+pub fn functions() -> Vec<KernelFunction> {
+    let conn = match open() {
+        Some(c) => c,
+        None => return vec![],
+    };
+    // older catalogs don't have return types
+    let mut stmt = match conn
+        .prepare("SELECT function_name, signature, return_type from function")
+        .or_else(|_| conn.prepare("SELECT function_name, signature, NULL from function"))
+    {
+        Ok(s) => s,
+        Err(_) => return vec![],
+    };
+    stmt.query_map([], |row| {
+        let sig: String = row.get(1)?;
+        Ok(KernelFunction {
+            name: row.get(0)?,
+            args: parse_signature(&sig),
+            return_type: row.get(2)?,
+        })
+    })
+    .map(|rows| rows.filter_map(|r| r.ok()).collect())
+    .unwrap_or_default()
+}
+
+/// Every tracepoint in the catalog as (category, event, fields).
+pub fn tracepoints() -> Vec<(String, String, Vec<Arg>)> {
+    let conn = match open() {
+        Some(c) => c,
+        None => return vec![],
+    };
+    let mut stmt = match conn.prepare(
+        "SELECT category, event, field_name, field_type from tracepoint_field order by category, event, rowid",
+    ) {
+        Ok(s) => s,
+        Err(_) => return vec![],
+    };
+    let fields = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                Arg {
+                    name: row.get(2)?,
+                    c_type: row.get(3)?,
+                },
+            ))
+        })
+        .map(|rows| rows.filter_map(|r| r.ok()).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let mut tracepoints: Vec<(String, String, Vec<Arg>)> = Vec::new();
+    for (category, event, field) in fields {
+        match tracepoints.last_mut() {
+            Some((c, e, fs)) if *c == category && *e == event => fs.push(field),
+            _ => tracepoints.push((category, event, vec![field])),
+        }
+    }
+    tracepoints
+}
+
+/// The fields of a tracepoint, in the order they appear in its format file.
+pub fn tracepoint_fields(category: &str, event: &str) -> Vec<Arg> {
+    let conn = match open() {
+        Some(c) => c,
+        None => return vec![],
+    };
+    let mut stmt = match conn.prepare(
+        "SELECT field_name, field_type from tracepoint_field where category = ? and event = ? order by rowid",
+    ) {
+        Ok(s) => s,
+        Err(_) => return vec![],
+    };
+    stmt.query_map(params![category, event], |row| {
+        Ok(Arg {
+            name: row.get(0)?,
+            c_type: row.get(1)?,
+        })
+    })
+    .map(|rows| rows.filter_map(|r| r.ok()).collect())
+    .unwrap_or_default()
+}
+
+// (signature, return_type) for a function in a binary on this machine
+fn binary_function(path: &str, function_name: &str) -> Option<(Option<String>, Option<String>)> {
+    let mut conn = open_local()?;
+    index_binary(&mut conn, path)?;
+    conn.query_row(
+        "SELECT signature, return_type from binary_function where path = ? and function_name = ?",
+        params![path, function_name],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .ok()
+}
+
+/// The arguments of a function in a binary on this machine, None if the
+/// binary has no debug info or doesn't have the function.
+pub fn binary_function_args(path: &str, function_name: &str) -> Option<Vec<Arg>> {
+    let (sig, _) = binary_function(path, function_name)?;
+    sig.map(|s| parse_signature(&s))
+}
+
+/// What a function in a binary on this machine returns, None without debug info.
+pub fn binary_function_return_type(path: &str, function_name: &str) -> Option<String> {
+    binary_function(path, function_name)?.1
+}
+
+/// Whether a query has already had the binary read into the catalog.
+pub fn binary_indexed(path: &str) -> bool {
+    open_local()
+        .and_then(|conn| {
+            conn.query_row("SELECT 1 from binary where path = ?", params![path], |_| Ok(()))
+                .ok()
+        })
+        .is_some()
+}
+
+/// The names of all the functions in a binary on this machine.
+pub fn binary_functions(path: &str) -> Vec<String> {
+    let mut conn = match open_local() {
+        Some(c) => c,
+        None => return vec![],
+    };
+    if index_binary(&mut conn, path).is_none() {
+        return vec![];
+    }
+    let mut stmt = match conn.prepare(
+        "SELECT function_name from binary_function where path = ? order by function_name",
+    ) {
+        Ok(s) => s,
+        Err(_) => return vec![],
+    };
+    stmt.query_map(params![path], |row| row.get(0))
+        .map(|rows| rows.filter_map(|r| r.ok()).collect())
+        .unwrap_or_default()
+}
+
+/// The USDT probes in a binary on this machine.
+pub fn binary_usdt_probes(path: &str) -> Vec<UsdtProbe> {
+    let mut conn = match open_local() {
+        Some(c) => c,
+        None => return vec![],
+    };
+    if index_binary(&mut conn, path).is_none() {
+        return vec![];
+    }
+    let mut stmt = match conn.prepare(
+        "SELECT provider, probe_name, arguments, semaphore from binary_usdt where path = ? order by provider, probe_name",
+    ) {
+        Ok(s) => s,
+        Err(_) => return vec![],
+    };
+    stmt.query_map(params![path], |row| {
+        Ok(UsdtProbe {
+            provider: row.get(0)?,
+            name: row.get(1)?,
+            arguments: row.get(2)?,
+            semaphore: row.get::<_, Option<i64>>(3)?.map(|s| s as u64),
+        })
+    })
+    .map(|rows| rows.filter_map(|r| r.ok()).collect())
+    .unwrap_or_default()
+}
+
+fn load_user_views() -> Vec<(String, String)> {
+    let conn = match open_local() {
+        Some(c) => c,
+        None => return vec![],
+    };
+    let mut stmt = match conn.prepare("SELECT name, sql from user_view order by name") {
+        Ok(s) => s,
+        Err(_) => return vec![],
+    };
+    stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map(|rows| rows.filter_map(|r| r.ok()).collect())
+        .unwrap_or_default()
+}
+
+// what CREATE VIEW and CREATE FUNCTION made, read once rather than on every
+// keystroke and again after either changes
+struct UserDefinitions {
+    views: Vec<(String, String)>,
+    functions: Vec<(String, Vec<String>, String)>,
+}
+
+static USER_DEFINITIONS: Mutex<Option<Arc<UserDefinitions>>> = Mutex::new(None);
+
+fn user_definitions() -> Arc<UserDefinitions> {
+    // always taken before open_local's lock, never while holding it
+    let mut cached = USER_DEFINITIONS.lock().unwrap_or_else(|e| e.into_inner());
+    cached
+        .get_or_insert_with(|| {
+            Arc::new(UserDefinitions {
+                views: load_user_views(),
+                functions: load_user_functions(),
+            })
+        })
+        .clone()
+}
+
+fn forget_user_definitions() {
+    *USER_DEFINITIONS.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// The views made with CREATE VIEW as (name, sql).
+pub fn user_views() -> Vec<(String, String)> {
+    user_definitions().views.clone()
+}
+
+/// The sql of a view made with CREATE VIEW.
+pub fn user_view(name: &str) -> Option<String> {
+    user_definitions()
+        .views
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, sql)| sql.clone())
+}
+
+pub fn save_user_view(name: &str, sql: &str, replace: bool) -> Result<(), String> {
+    let conn = open_local().ok_or(format!("Couldn't open {}", LOCAL_DEFINITIONS))?;
+    let insert = if replace { "INSERT OR REPLACE" } else { "INSERT" };
+    let saved = conn.execute(
+        &format!("{} into user_view (name, sql) values (?, ?)", insert),
+        params![name, sql],
+    );
+    drop(conn);
+    forget_user_definitions();
+    saved.map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// Returns whether there was a view to delete.
+pub fn delete_user_view(name: &str) -> Result<bool, String> {
+    let conn = open_local().ok_or(format!("Couldn't open {}", LOCAL_DEFINITIONS))?;
+    let deleted = conn.execute("DELETE from user_view where name = ?", params![name]);
+    drop(conn);
+    forget_user_definitions();
+    deleted.map(|n| n > 0).map_err(|e| e.to_string())
+}
+
+fn load_user_functions() -> Vec<(String, Vec<String>, String)> {
+    let conn = match open_local() {
+        Some(c) => c,
+        None => return vec![],
+    };
+    let mut stmt = match conn.prepare("SELECT name, args, body from user_function order by name") {
+        Ok(s) => s,
+        Err(_) => return vec![],
+    };
+    stmt.query_map([], |row| {
+        let args: String = row.get(1)?;
+        let args = args
+            .split(',')
+            .filter(|a| !a.is_empty())
+            .map(|a| a.to_string())
+            .collect();
+        Ok((row.get(0)?, args, row.get(2)?))
+    })
+    .map(|rows| rows.filter_map(|r| r.ok()).collect())
+    .unwrap_or_default()
+}
+
+/// The functions made with CREATE FUNCTION as (name, args, body).
+pub fn user_functions() -> Vec<(String, Vec<String>, String)> {
+    user_definitions().functions.clone()
+}
+
+pub fn save_user_function(name: &str, args: &[String], body: &str, replace: bool) -> Result<(), String> {
+    let conn = open_local().ok_or(format!("Couldn't open {}", LOCAL_DEFINITIONS))?;
+    let insert = if replace { "INSERT OR REPLACE" } else { "INSERT" };
+    let saved = conn.execute(
+        &format!("{} into user_function (name, args, body) values (?, ?, ?)", insert),
+        params![name, args.join(","), body],
+    );
+    drop(conn);
+    forget_user_definitions();
+    saved.map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// Returns whether there was a function to delete.
+pub fn delete_user_function(name: &str) -> Result<bool, String> {
+    let conn = open_local().ok_or(format!("Couldn't open {}", LOCAL_DEFINITIONS))?;
+    let deleted = conn.execute("DELETE from user_function where name = ?", params![name]);
+    drop(conn);
+    forget_user_definitions();
+    deleted.map(|n| n > 0).map_err(|e| e.to_string())
+}
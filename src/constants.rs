@@ -0,0 +1,845 @@
+use crate::vmlinux;
+
+// The errno values from include/uapi/asm-generic/errno-base.h,
+// include/uapi/asm-generic/errno.h and include/linux/errno.h
+const ERRNOS: &[(&str, i64)] = &[
+    ("EPERM", 1),
+    ("ENOENT", 2),
+    ("ESRCH", 3),
+    ("EINTR", 4),
+    ("EIO", 5),
+    ("ENXIO", 6),
+    ("E2BIG", 7),
+    ("ENOEXEC", 8),
+    ("EBADF", 9),
+    ("ECHILD", 10),
+    ("EAGAIN", 11),
+    ("ENOMEM", 12),
+    ("EACCES", 13),
+    ("EFAULT", 14),
+    ("ENOTBLK", 15),
+    ("EBUSY", 16),
+    ("EEXIST", 17),
+    ("EXDEV", 18),
+    ("ENODEV", 19),
+    ("ENOTDIR", 20),
+    ("EISDIR", 21),
+    ("EINVAL", 22),
+    ("ENFILE", 23),
+    ("EMFILE", 24),
+    ("ENOTTY", 25),
+    ("ETXTBSY", 26),
+    ("EFBIG", 27),
+    ("ENOSPC", 28),
+    ("ESPIPE", 29),
+    ("EROFS", 30),
+    ("EMLINK", 31),
+    ("EPIPE", 32),
+    ("EDOM", 33),
+    ("ERANGE", 34),
+    ("EDEADLK", 35),
+    ("ENAMETOOLONG", 36),
+    ("ENOLCK", 37),
+    ("ENOSYS", 38),
+    ("ENOTEMPTY", 39),
+    ("ELOOP", 40),
+    ("ENOMSG", 42),
+    ("EIDRM", 43),
+    ("ECHRNG", 44),
+    ("EL2NSYNC", 45),
+    ("EL3HLT", 46),
+    ("EL3RST", 47),
+    ("ELNRNG", 48),
+    ("EUNATCH", 49),
+    ("ENOCSI", 50),
+    ("EL2HLT", 51),
+    ("EBADE", 52),
+    ("EBADR", 53),
+    ("EXFULL", 54),
+    ("ENOANO", 55),
+    ("EBADRQC", 56),
+    ("EBADSLT", 57),
+    ("EBFONT", 59),
+    ("ENOSTR", 60),
+    ("ENODATA", 61),
+    ("ETIME", 62),
+    ("ENOSR", 63),
+    ("ENONET", 64),
+    ("ENOPKG", 65),
+    ("EREMOTE", 66),
+    ("ENOLINK", 67),
+    ("EADV", 68),
+    ("ESRMNT", 69),
+    ("ECOMM", 70),
+    ("EPROTO", 71),
+    ("EMULTIHOP", 72),
+    ("EDOTDOT", 73),
+    ("EBADMSG", 74),
+    ("EOVERFLOW", 75),
+    ("ENOTUNIQ", 76),
+    ("EBADFD", 77),
+    ("EREMCHG", 78),
+    ("ELIBACC", 79),
+    ("ELIBBAD", 80),
+    ("ELIBSCN", 81),
+    ("ELIBMAX", 82),
+    ("ELIBEXEC", 83),
+    ("EILSEQ", 84),
+    ("ERESTART", 85),
+    ("ESTRPIPE", 86),
+    ("EUSERS", 87),
+    ("ENOTSOCK", 88),
+    ("EDESTADDRREQ", 89),
+    ("EMSGSIZE", 90),
+    ("EPROTOTYPE", 91),
+    ("ENOPROTOOPT", 92),
+    ("EPROTONOSUPPORT", 93),
+    ("ESOCKTNOSUPPORT", 94),
+    ("EOPNOTSUPP", 95),
+    ("EPFNOSUPPORT", 96),
+    ("EAFNOSUPPORT", 97),
+    ("EADDRINUSE", 98),
+    ("EADDRNOTAVAIL", 99),
+    ("ENETDOWN", 100),
+    ("ENETUNREACH", 101),
+    ("ENETRESET", 102),
+    ("ECONNABORTED", 103),
+    ("ECONNRESET", 104),
+    ("ENOBUFS", 105),
+    ("EISCONN", 106),
+    ("ENOTCONN", 107),
+    ("ESHUTDOWN", 108),
+    ("ETOOMANYREFS", 109),
+    ("ETIMEDOUT", 110),
+    ("ECONNREFUSED", 111),
+    ("EHOSTDOWN", 112),
+    ("EHOSTUNREACH", 113),
+    ("EALREADY", 114),
+    ("EINPROGRESS", 115),
+    ("ESTALE", 116),
+    ("EUCLEAN", 117),
+    ("ENOTNAM", 118),
+    ("ENAVAIL", 119),
+    ("EISNAM", 120),
+    ("EREMOTEIO", 121),
+    ("EDQUOT", 122),
+    ("ENOMEDIUM", 123),
+    ("EMEDIUMTYPE", 124),
+    ("ECANCELED", 125),
+    ("ENOKEY", 126),
+    ("EKEYEXPIRED", 127),
+    ("EKEYREVOKED", 128),
+    ("EKEYREJECTED", 129),
+    ("EOWNERDEAD", 130),
+    ("ENOTRECOVERABLE", 131),
+    ("ERFKILL", 132),
+    ("EHWPOISON", 133),
+    // these never make it to userspace but show up all the time in kretprobes
+    ("ERESTARTSYS", 512),
+    ("ERESTARTNOINTR", 513),
+    ("ERESTARTNOHAND", 514),
+    ("ENOIOCTLCMD", 515),
+    ("ERESTART_RESTARTBLOCK", 516),
+    ("EPROBE_DEFER", 517),
+    ("EOPENSTALLED", 518),
+    ("ENOPARAM", 519),
+    ("EBADHANDLE", 521),
+    ("ENOTSYNC", 522),
+    ("EBADCOOKIE", 523),
+    ("ENOTSUPP", 524),
+    ("ETOOSMALL", 525),
+    ("ESERVERFAULT", 526),
+    ("EBADTYPE", 527),
+    ("EJUKEBOX", 528),
+    ("EIOCBQUEUED", 529),
+    ("ERECALLCONFLICT", 530),
+];
+
+// the kernel never returns errnos bigger than this, see IS_ERR_VALUE
+pub const MAX_ERRNO: i64 = 4095;
+
+pub fn errno_name(errno: i64) -> Option<&'static str> {
+    ERRNOS.iter().find(|(_, v)| *v == errno).map(|(n, _)| *n)
+}
+
+// x86_64 syscall numbers, from arch/x86/entry/syscalls/syscall_64.tbl
+const SYSCALLS: &[(&str, i64)] = &[
+    ("read", 0),
+    ("write", 1),
+    ("open", 2),
+    ("close", 3),
+    ("stat", 4),
+    ("fstat", 5),
+    ("lstat", 6),
+    ("poll", 7),
+    ("lseek", 8),
+    ("mmap", 9),
+    ("mprotect", 10),
+    ("munmap", 11),
+    ("brk", 12),
+    ("rt_sigaction", 13),
+    ("rt_sigprocmask", 14),
+    ("rt_sigreturn", 15),
+    ("ioctl", 16),
+    ("pread64", 17),
+    ("pwrite64", 18),
+    ("readv", 19),
+    ("writev", 20),
+    ("access", 21),
+    ("pipe", 22),
+    ("select", 23),
+    ("sched_yield", 24),
+    ("mremap", 25),
+    ("msync", 26),
+    ("mincore", 27),
+    ("madvise", 28),
+    ("shmget", 29),
+    ("shmat", 30),
+    ("shmctl", 31),
+    ("dup", 32),
+    ("dup2", 33),
+    ("pause", 34),
+    ("nanosleep", 35),
+    ("getitimer", 36),
+    ("alarm", 37),
+    ("setitimer", 38),
+    ("getpid", 39),
+    ("sendfile", 40),
+    ("socket", 41),
+    ("connect", 42),
+    ("accept", 43),
+    ("sendto", 44),
+    ("recvfrom", 45),
+    ("sendmsg", 46),
+    ("recvmsg", 47),
+    ("shutdown", 48),
+    ("bind", 49),
+    ("listen", 50),
+    ("getsockname", 51),
+    ("getpeername", 52),
+    ("socketpair", 53),
+    ("setsockopt", 54),
+    ("getsockopt", 55),
+    ("clone", 56),
+    ("fork", 57),
+    ("vfork", 58),
+    ("execve", 59),
+    ("exit", 60),
+    ("wait4", 61),
+    ("kill", 62),
+    ("uname", 63),
+    ("semget", 64),
+    ("semop", 65),
+    ("semctl", 66),
+    ("shmdt", 67),
+    ("msgget", 68),
+    ("msgsnd", 69),
+    ("msgrcv", 70),
+    ("msgctl", 71),
+    ("fcntl", 72),
+    ("flock", 73),
+    ("fsync", 74),
+    ("fdatasync", 75),
+    ("truncate", 76),
+    ("ftruncate", 77),
+    ("getdents", 78),
+    ("getcwd", 79),
+    ("chdir", 80),
+    ("fchdir", 81),
+    ("rename", 82),
+    ("mkdir", 83),
+    ("rmdir", 84),
+    ("creat", 85),
+    ("link", 86),
+    ("unlink", 87),
+    ("symlink", 88),
+    ("readlink", 89),
+    ("chmod", 90),
+    ("fchmod", 91),
+    ("chown", 92),
+    ("fchown", 93),
+    ("lchown", 94),
+    ("umask", 95),
+    ("gettimeofday", 96),
+    ("getrlimit", 97),
+    ("getrusage", 98),
+    ("sysinfo", 99),
+    ("times", 100),
+    ("ptrace", 101),
+    ("getuid", 102),
+    ("syslog", 103),
+    ("getgid", 104),
+    ("setuid", 105),
+    ("setgid", 106),
+    ("geteuid", 107),
+    ("getegid", 108),
+    ("setpgid", 109),
+    ("getppid", 110),
+    ("getpgrp", 111),
+    ("setsid", 112),
+    ("setreuid", 113),
+    ("setregid", 114),
+    ("getgroups", 115),
+    ("setgroups", 116),
+    ("setresuid", 117),
+    ("getresuid", 118),
+    ("setresgid", 119),
+    ("getresgid", 120),
+    ("getpgid", 121),
+    ("setfsuid", 122),
+    ("setfsgid", 123),
+    ("getsid", 124),
+    ("capget", 125),
+    ("capset", 126),
+    ("rt_sigpending", 127),
+    ("rt_sigtimedwait", 128),
+    ("rt_sigqueueinfo", 129),
+    ("rt_sigsuspend", 130),
+    ("sigaltstack", 131),
+    ("utime", 132),
+    ("mknod", 133),
+    ("uselib", 134),
+    ("personality", 135),
+    ("ustat", 136),
+    ("statfs", 137),
+    ("fstatfs", 138),
+    ("sysfs", 139),
+    ("getpriority", 140),
+    ("setpriority", 141),
+    ("sched_setparam", 142),
+    ("sched_getparam", 143),
+    ("sched_setscheduler", 144),
+    ("sched_getscheduler", 145),
+    ("sched_get_priority_max", 146),
+    ("sched_get_priority_min", 147),
+    ("sched_rr_get_interval", 148),
+    ("mlock", 149),
+    ("munlock", 150),
+    ("mlockall", 151),
+    ("munlockall", 152),
+    ("vhangup", 153),
+    ("modify_ldt", 154),
+    ("pivot_root", 155),
+    ("_sysctl", 156),
+    ("prctl", 157),
+    ("arch_prctl", 158),
+    ("adjtimex", 159),
+    ("setrlimit", 160),
+    ("chroot", 161),
+    ("sync", 162),
+    ("acct", 163),
+    ("settimeofday", 164),
+    ("mount", 165),
+    ("umount2", 166),
+    ("swapon", 167),
+    ("swapoff", 168),
+    ("reboot", 169),
+    ("sethostname", 170),
+    ("setdomainname", 171),
+    ("iopl", 172),
+    ("ioperm", 173),
+    ("create_module", 174),
+    ("init_module", 175),
+    ("delete_module", 176),
+    ("get_kernel_syms", 177),
+    ("query_module", 178),
+    ("quotactl", 179),
+    ("nfsservctl", 180),
+    ("getpmsg", 181),
+    ("putpmsg", 182),
+    ("afs_syscall", 183),
+    ("tuxcall", 184),
+    ("security", 185),
+    ("gettid", 186),
+    ("readahead", 187),
+    ("setxattr", 188),
+    ("lsetxattr", 189),
+    ("fsetxattr", 190),
+    ("getxattr", 191),
+    ("lgetxattr", 192),
+    ("fgetxattr", 193),
+    ("listxattr", 194),
+    ("llistxattr", 195),
+    ("flistxattr", 196),
+    ("removexattr", 197),
+    ("lremovexattr", 198),
+    ("fremovexattr", 199),
+    ("tkill", 200),
+    ("time", 201),
+    ("futex", 202),
+    ("sched_setaffinity", 203),
+    ("sched_getaffinity", 204),
+    ("set_thread_area", 205),
+    ("io_setup", 206),
+    ("io_destroy", 207),
+    ("io_getevents", 208),
+    ("io_submit", 209),
+    ("io_cancel", 210),
+    ("get_thread_area", 211),
+    ("lookup_dcookie", 212),
+    ("epoll_create", 213),
+    ("epoll_ctl_old", 214),
+    ("epoll_wait_old", 215),
+    ("remap_file_pages", 216),
+    ("getdents64", 217),
+    ("set_tid_address", 218),
+    ("restart_syscall", 219),
+    ("semtimedop", 220),
+    ("fadvise64", 221),
+    ("timer_create", 222),
+    ("timer_settime", 223),
+    ("timer_gettime", 224),
+    ("timer_getoverrun", 225),
+    ("timer_delete", 226),
+    ("clock_settime", 227),
+    ("clock_gettime", 228),
+    ("clock_getres", 229),
+    ("clock_nanosleep", 230),
+    ("exit_group", 231),
+    ("epoll_wait", 232),
+    ("epoll_ctl", 233),
+    ("tgkill", 234),
+    ("utimes", 235),
+    ("vserver", 236),
+    ("mbind", 237),
+    ("set_mempolicy", 238),
+    ("get_mempolicy", 239),
+    ("mq_open", 240),
+    ("mq_unlink", 241),
+    ("mq_timedsend", 242),
+    ("mq_timedreceive", 243),
+    ("mq_notify", 244),
+    ("mq_getsetattr", 245),
+    ("kexec_load", 246),
+    ("waitid", 247),
+    ("add_key", 248),
+    ("request_key", 249),
+    ("keyctl", 250),
+    ("ioprio_set", 251),
+    ("ioprio_get", 252),
+    ("inotify_init", 253),
+    ("inotify_add_watch", 254),
+    ("inotify_rm_watch", 255),
+    ("migrate_pages", 256),
+    ("openat", 257),
+    ("mkdirat", 258),
+    ("mknodat", 259),
+    ("fchownat", 260),
+    ("futimesat", 261),
+    ("newfstatat", 262),
+    ("unlinkat", 263),
+    ("renameat", 264),
+    ("linkat", 265),
+    ("symlinkat", 266),
+    ("readlinkat", 267),
+    ("fchmodat", 268),
+    ("faccessat", 269),
+    ("pselect6", 270),
+    ("ppoll", 271),
+    ("unshare", 272),
+    ("set_robust_list", 273),
+    ("get_robust_list", 274),
+    ("splice", 275),
+    ("tee", 276),
+    ("sync_file_range", 277),
+    ("vmsplice", 278),
+    ("move_pages", 279),
+    ("utimensat", 280),
+    ("epoll_pwait", 281),
+    ("signalfd", 282),
+    ("timerfd_create", 283),
+    ("eventfd", 284),
+    ("fallocate", 285),
+    ("timerfd_settime", 286),
+    ("timerfd_gettime", 287),
+    ("accept4", 288),
+    ("signalfd4", 289),
+    ("eventfd2", 290),
+    ("epoll_create1", 291),
+    ("dup3", 292),
+    ("pipe2", 293),
+    ("inotify_init1", 294),
+    ("preadv", 295),
+    ("pwritev", 296),
+    ("rt_tgsigqueueinfo", 297),
+    ("perf_event_open", 298),
+    ("recvmmsg", 299),
+    ("fanotify_init", 300),
+    ("fanotify_mark", 301),
+    ("prlimit64", 302),
+    ("name_to_handle_at", 303),
+    ("open_by_handle_at", 304),
+    ("clock_adjtime", 305),
+    ("syncfs", 306),
+    ("sendmmsg", 307),
+    ("setns", 308),
+    ("getcpu", 309),
+    ("process_vm_readv", 310),
+    ("process_vm_writev", 311),
+    ("kcmp", 312),
+    ("finit_module", 313),
+    ("sched_setattr", 314),
+    ("sched_getattr", 315),
+    ("renameat2", 316),
+    ("seccomp", 317),
+    ("getrandom", 318),
+    ("memfd_create", 319),
+    ("kexec_file_load", 320),
+    ("bpf", 321),
+    ("execveat", 322),
+    ("userfaultfd", 323),
+    ("membarrier", 324),
+    ("mlock2", 325),
+    ("copy_file_range", 326),
+    ("preadv2", 327),
+    ("pwritev2", 328),
+    ("pkey_mprotect", 329),
+    ("pkey_alloc", 330),
+    ("pkey_free", 331),
+    ("statx", 332),
+    ("io_pgetevents", 333),
+    ("rseq", 334),
+    ("pidfd_send_signal", 424),
+    ("io_uring_setup", 425),
+    ("io_uring_enter", 426),
+    ("io_uring_register", 427),
+    ("open_tree", 428),
+    ("move_mount", 429),
+    ("fsopen", 430),
+    ("fsconfig", 431),
+    ("fsmount", 432),
+    ("fspick", 433),
+    ("pidfd_open", 434),
+    ("clone3", 435),
+    ("close_range", 436),
+    ("openat2", 437),
+    ("pidfd_getfd", 438),
+    ("faccessat2", 439),
+    ("process_madvise", 440),
+    ("epoll_pwait2", 441),
+    ("mount_setattr", 442),
+    ("quotactl_fd", 443),
+    ("landlock_create_ruleset", 444),
+    ("landlock_add_rule", 445),
+    ("landlock_restrict_self", 446),
+    ("memfd_secret", 447),
+    ("process_mrelease", 448),
+    ("futex_waitv", 449),
+    ("set_mempolicy_home_node", 450),
+    ("cachestat", 451),
+    ("fchmodat2", 452),
+    ("map_shadow_stack", 453),
+    ("futex_wake", 454),
+    ("futex_wait", 455),
+    ("futex_requeue", 456),
+    ("statmount", 457),
+    ("listmount", 458),
+    ("lsm_get_self_attr", 459),
+    ("lsm_set_self_attr", 460),
+    ("lsm_list_modules", 461),
+    ("mseal", 462),
+    ("setxattrat", 463),
+    ("getxattrat", 464),
+    ("listxattrat", 465),
+    ("removexattrat", 466),
+];
+
+pub fn syscall_name(nr: i64) -> Option<&'static str> {
+    SYSCALLS.iter().find(|(_, v)| *v == nr).map(|(n, _)| *n)
+}
+
+pub fn syscall_number(name: &str) -> Option<i64> {
+    SYSCALLS.iter().find(|(n, _)| *n == name).map(|(_, v)| *v)
+}
+
+/// A set of bits that decode_flags knows how to name. The bits under mask
+/// are a single value rather than flags, like O_ACCMODE for open flags.
+pub struct FlagSet {
+    pub name: &'static str,
+    pub mask: i64,
+    pub values: &'static [(&'static str, i64)],
+}
+
+// From include/uapi/asm-generic/fcntl.h, mman-common.h and sched.h as they are
+// on x86_64. Combined flags like O_SYNC come before the bits they include so
+// they win when decoding.
+pub const FLAG_SETS: &[FlagSet] = &[
+    FlagSet {
+        name: "open_flags",
+        mask: 0o3,
+        values: &[
+            ("O_RDONLY", 0),
+            ("O_WRONLY", 0o1),
+            ("O_RDWR", 0o2),
+            ("O_CREAT", 0o100),
+            ("O_EXCL", 0o200),
+            ("O_NOCTTY", 0o400),
+            ("O_TRUNC", 0o1000),
+            ("O_APPEND", 0o2000),
+            ("O_NONBLOCK", 0o4000),
+            ("O_SYNC", 0o4010000),
+            ("O_DSYNC", 0o10000),
+            ("FASYNC", 0o20000),
+            ("O_DIRECT", 0o40000),
+            ("O_LARGEFILE", 0o100000),
+            ("O_TMPFILE", 0o20200000),
+            ("O_DIRECTORY", 0o200000),
+            ("O_NOFOLLOW", 0o400000),
+            ("O_NOATIME", 0o1000000),
+            ("O_CLOEXEC", 0o2000000),
+            ("O_PATH", 0o10000000),
+        ],
+    },
+    FlagSet {
+        name: "mmap_prot",
+        mask: 0,
+        values: &[
+            ("PROT_NONE", 0),
+            ("PROT_READ", 0x1),
+            ("PROT_WRITE", 0x2),
+            ("PROT_EXEC", 0x4),
+            ("PROT_GROWSDOWN", 0x01000000),
+            ("PROT_GROWSUP", 0x02000000),
+        ],
+    },
+    FlagSet {
+        name: "mmap_flags",
+        mask: 0x0f,
+        values: &[
+            ("MAP_SHARED", 0x01),
+            ("MAP_PRIVATE", 0x02),
+            ("MAP_SHARED_VALIDATE", 0x03),
+            ("MAP_FIXED", 0x10),
+            ("MAP_ANONYMOUS", 0x20),
+            ("MAP_GROWSDOWN", 0x100),
+            ("MAP_DENYWRITE", 0x800),
+            ("MAP_EXECUTABLE", 0x1000),
+            ("MAP_LOCKED", 0x2000),
+            ("MAP_NORESERVE", 0x4000),
+            ("MAP_POPULATE", 0x8000),
+            ("MAP_NONBLOCK", 0x10000),
+            ("MAP_STACK", 0x20000),
+            ("MAP_HUGETLB", 0x40000),
+            ("MAP_SYNC", 0x80000),
+            ("MAP_FIXED_NOREPLACE", 0x100000),
+        ],
+    },
+    FlagSet {
+        // the low byte is the signal sent to the parent on exit
+        name: "clone_flags",
+        mask: 0xff,
+        values: &[
+            ("CLONE_VM", 0x100),
+            ("CLONE_FS", 0x200),
+            ("CLONE_FILES", 0x400),
+            ("CLONE_SIGHAND", 0x800),
+            ("CLONE_PIDFD", 0x1000),
+            ("CLONE_PTRACE", 0x2000),
+            ("CLONE_VFORK", 0x4000),
+            ("CLONE_PARENT", 0x8000),
+            ("CLONE_THREAD", 0x10000),
+            ("CLONE_NEWNS", 0x20000),
+            ("CLONE_SYSVSEM", 0x40000),
+            ("CLONE_SETTLS", 0x80000),
+            ("CLONE_PARENT_SETTID", 0x100000),
+            ("CLONE_CHILD_CLEARTID", 0x200000),
+            ("CLONE_DETACHED", 0x400000),
+            ("CLONE_UNTRACED", 0x800000),
+            ("CLONE_CHILD_SETTID", 0x1000000),
+            ("CLONE_NEWCGROUP", 0x2000000),
+            ("CLONE_NEWUTS", 0x4000000),
+            ("CLONE_NEWIPC", 0x8000000),
+            ("CLONE_NEWUSER", 0x10000000),
+            ("CLONE_NEWPID", 0x20000000),
+            ("CLONE_NEWNET", 0x40000000),
+            ("CLONE_IO", 0x80000000),
+        ],
+    },
+];
+
+// #defines from the uapi headers that don't make it into vmlinux.h, the ones
+// people filter on the most
+pub const UAPI_CONSTANTS: &[(&str, i64)] = &[
+    // include/linux/socket.h
+    ("AF_UNSPEC", 0),
+    ("AF_UNIX", 1),
+    ("AF_LOCAL", 1),
+    ("AF_INET", 2),
+    ("AF_AX25", 3),
+    ("AF_IPX", 4),
+    ("AF_APPLETALK", 5),
+    ("AF_NETROM", 6),
+    ("AF_BRIDGE", 7),
+    ("AF_INET6", 10),
+    ("AF_NETLINK", 16),
+    ("AF_PACKET", 17),
+    ("AF_CAN", 29),
+    ("AF_BLUETOOTH", 31),
+    ("AF_VSOCK", 40),
+    ("AF_XDP", 44),
+    // include/linux/net.h
+    ("SOCK_STREAM", 1),
+    ("SOCK_DGRAM", 2),
+    ("SOCK_RAW", 3),
+    ("SOCK_RDM", 4),
+    ("SOCK_SEQPACKET", 5),
+    ("SOCK_DCCP", 6),
+    ("SOCK_PACKET", 10),
+    ("SOCK_NONBLOCK", 0o4000),
+    ("SOCK_CLOEXEC", 0o2000000),
+    // include/uapi/linux/stat.h
+    ("S_IFMT", 0o170000),
+    ("S_IFSOCK", 0o140000),
+    ("S_IFLNK", 0o120000),
+    ("S_IFREG", 0o100000),
+    ("S_IFBLK", 0o60000),
+    ("S_IFDIR", 0o40000),
+    ("S_IFCHR", 0o20000),
+    ("S_IFIFO", 0o10000),
+    // include/uapi/linux/fcntl.h
+    ("AT_FDCWD", -100),
+    ("AT_SYMLINK_NOFOLLOW", 0x100),
+    ("AT_REMOVEDIR", 0x200),
+    ("AT_SYMLINK_FOLLOW", 0x400),
+    ("AT_EMPTY_PATH", 0x1000),
+    // arch/x86/include/uapi/asm/signal.h
+    ("SIGHUP", 1),
+    ("SIGINT", 2),
+    ("SIGQUIT", 3),
+    ("SIGILL", 4),
+    ("SIGTRAP", 5),
+    ("SIGABRT", 6),
+    ("SIGBUS", 7),
+    ("SIGFPE", 8),
+    ("SIGKILL", 9),
+    ("SIGUSR1", 10),
+    ("SIGSEGV", 11),
+    ("SIGUSR2", 12),
+    ("SIGPIPE", 13),
+    ("SIGALRM", 14),
+    ("SIGTERM", 15),
+    ("SIGSTKFLT", 16),
+    ("SIGCHLD", 17),
+    ("SIGCONT", 18),
+    ("SIGSTOP", 19),
+    ("SIGTSTP", 20),
+    ("SIGTTIN", 21),
+    ("SIGTTOU", 22),
+    ("SIGURG", 23),
+    ("SIGXCPU", 24),
+    ("SIGXFSZ", 25),
+    ("SIGVTALRM", 26),
+    ("SIGPROF", 27),
+    ("SIGWINCH", 28),
+    ("SIGIO", 29),
+    ("SIGPWR", 30),
+    ("SIGSYS", 31),
+];
+
+pub fn flag_set(name: &str) -> Option<&'static FlagSet> {
+    FLAG_SETS.iter().find(|s| s.name == name)
+}
+
+/// Every named constant as (name, value, where it came from), the uapi ones
+/// first so they win over an enum value with the same name.
+pub fn constants() -> Vec<(&'static str, i64, String)> {
+    let mut constants = UAPI_CONSTANTS
+        .iter()
+        .map(|(n, v)| (*n, *v, "uapi".to_string()))
+        .collect::<Vec<_>>();
+    for set in FLAG_SETS {
+        constants.extend(
+            set.values
+                .iter()
+                .map(|(n, v)| (*n, *v, set.name.to_string())),
+        );
+    }
+    constants.extend(ERRNOS.iter().map(|(n, v)| (*n, *v, "errno".to_string())));
+    for e in vmlinux::enums() {
+        let source = match &e.name {
+            Some(name) => format!("enum {}", name),
+            None => "enum".to_string(),
+        };
+        constants.extend(
+            e.values
+                .iter()
+                .map(|(n, v)| (n.as_str(), *v, source.clone())),
+        );
+    }
+    constants
+}
+
+/// The value of a constant by name, e.g. O_CREAT, AF_INET or TCP_ESTABLISHED.
+pub fn constant(name: &str) -> Option<i64> {
+    UAPI_CONSTANTS
+        .iter()
+        .chain(FLAG_SETS.iter().flat_map(|s| s.values.iter()))
+        .chain(ERRNOS.iter())
+        .find(|(n, _)| *n == name)
+        .map(|(_, v)| *v)
+        .or_else(|| {
+            vmlinux::enums()
+                .iter()
+                .flat_map(|e| e.values.iter())
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| *v)
+        })
+}
+
+/// Names the bits that are set, e.g. 0o1101 as open flags is
+/// O_WRONLY|O_CREAT|O_TRUNC. Bits without a name are left as hex.
+pub fn decode_flags(value: i64, set: &FlagSet) -> String {
+    let mut names = Vec::new();
+    let mut rest = value;
+    if set.mask != 0 || value == 0 {
+        let exact = set
+            .values
+            .iter()
+            .find(|(_, v)| *v & !set.mask == 0 && *v == value & set.mask);
+        if let Some((name, _)) = exact {
+            names.push(name.to_string());
+            rest &= !set.mask;
+        }
+    }
+    for (name, v) in set.values {
+        if *v != 0 && *v & set.mask == 0 && rest & *v == *v {
+            names.push(name.to_string());
+            rest &= !*v;
+        }
+    }
+    if rest != 0 || names.is_empty() {
+        names.push(format!("{:#x}", rest));
+    }
+    names.join("|")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_flags() {
+        let open_flags = flag_set("open_flags").unwrap();
+        assert_eq!(decode_flags(0o1101, open_flags), "O_WRONLY|O_CREAT|O_TRUNC");
+        assert_eq!(decode_flags(0, open_flags), "O_RDONLY");
+        // bits nobody named are kept, as hex
+        assert_eq!(decode_flags(0o100 | 0x40000000, open_flags), "O_RDONLY|O_CREAT|0x40000000");
+        let prot = flag_set("mmap_prot").unwrap();
+        assert_eq!(decode_flags(0, prot), "PROT_NONE");
+        assert_eq!(decode_flags(0x3, prot), "PROT_READ|PROT_WRITE");
+        assert_eq!(decode_flags(0x9, prot), "PROT_READ|0x8");
+        assert!(flag_set("no_such_flags").is_none());
+    }
+
+    #[test]
+    fn errnos_and_constants() {
+        assert_eq!(errno_name(2), Some("ENOENT"));
+        assert_eq!(errno_name(530), Some("ERECALLCONFLICT"));
+        assert_eq!(errno_name(0), None);
+        assert_eq!(errno_name(-2), None);
+        assert_eq!(constant("O_CREAT"), Some(0o100));
+        assert_eq!(constant("AT_FDCWD"), Some(-100));
+        assert_eq!(constant("EPERM"), Some(1));
+        assert_eq!(constant("NOT_A_CONSTANT"), None);
+    }
+}
@@ -0,0 +1,410 @@
+use crate::catalog::Arg;
+use gimli::{AttributeValue, EndianSlice, RunTimeEndian, Unit, UnitOffset};
+use object::{Endianness, Object, ObjectSection, ObjectSymbol, SymbolKind};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::Read;
+
+type Reader<'a> = EndianSlice<'a, RunTimeEndian>;
+
+/// A function that a uprobe can attach to.
+#[derive(Debug, Clone)]
+pub struct ElfFunction {
+    pub name: String,
+    /// Only known when the binary has DWARF.
+    pub args: Option<Vec<Arg>>,
+    pub return_type: Option<String>,
+}
+
+/// A USDT probe compiled into a binary, from its `.note.stapsdt` section.
+#[derive(Debug, Clone, Serialize)]
+pub struct UsdtProbe {
+    pub provider: String,
+    pub name: String,
+    /// The argument specs, e.g. `-4@%edi 8@%rsi`, one per argument.
+    pub arguments: String,
+    /// The address of the counter a probe is guarded by, if it has one. The
+    /// probe only fires while it's above zero, bpftrace takes care of that.
+    pub semaphore: Option<u64>,
+}
+
+impl UsdtProbe {
+    pub fn arg_count(&self) -> usize {
+        self.arguments.split_whitespace().count()
+    }
+}
+
+/// Everything in a binary that can be probed.
+#[derive(Debug, Clone)]
+pub struct Binary {
+    pub functions: Vec<ElfFunction>,
+    pub usdt_probes: Vec<UsdtProbe>,
+}
+
+// nobody should need more than this to name a type, stops cycles too
+const MAX_TYPE_DEPTH: usize = 16;
+
+fn die_name(
+    dwarf: &gimli::Dwarf<Reader>,
+    unit: &Unit<Reader>,
+    offset: UnitOffset,
+) -> Option<String> {
+    let entry = unit.entry(offset).ok()?;
+    let attr = entry.attr_value(gimli::DW_AT_name).ok()??;
+    let name = dwarf.attr_string(unit, attr).ok()?;
+    Some(name.to_string_lossy().into_owned())
+}
+
+fn type_ref(entry: &gimli::DebuggingInformationEntry<Reader>) -> Option<UnitOffset> {
+    match entry.attr_value(gimli::DW_AT_type) {
+        Ok(Some(AttributeValue::UnitRef(offset))) => Some(offset),
+        _ => None,
+    }
+}
+
+/// Spells out a DWARF type the way it'd be written in C, e.g. `const char *`.
+fn type_name(
+    dwarf: &gimli::Dwarf<Reader>,
+    unit: &Unit<Reader>,
+    offset: Option<UnitOffset>,
+    depth: usize,
+) -> String {
+    let offset = match offset {
+        Some(o) if depth < MAX_TYPE_DEPTH => o,
+        _ => return "void".to_string(),
+    };
+    let entry = match unit.entry(offset) {
+        Ok(e) => e,
+        Err(_) => return "void".to_string(),
+    };
+    let inner = || type_name(dwarf, unit, type_ref(&entry), depth + 1);
+    let name = || die_name(dwarf, unit, offset).unwrap_or_else(|| "<anonymous>".to_string());
+    match entry.tag() {
+        // arrays decay to pointers when they're passed around
+        gimli::DW_TAG_pointer_type | gimli::DW_TAG_array_type => format!("{} *", inner()),
+        gimli::DW_TAG_const_type => format!("const {}", inner()),
+        gimli::DW_TAG_volatile_type | gimli::DW_TAG_restrict_type | gimli::DW_TAG_atomic_type => {
+            inner()
+        }
+        gimli::DW_TAG_structure_type | gimli::DW_TAG_class_type => format!("struct {}", name()),
+        gimli::DW_TAG_union_type => format!("union {}", name()),
+        gimli::DW_TAG_enumeration_type => format!("enum {}", name()),
+        // function pointers end up as void *
+        gimli::DW_TAG_subroutine_type => "void".to_string(),
+        _ => name(),
+    }
+}
+
+fn read_subprogram(
+    dwarf: &gimli::Dwarf<Reader>,
+    unit: &Unit<Reader>,
+    node: gimli::EntriesTreeNode<Reader>,
+) -> gimli::Result<Option<ElfFunction>> {
+    let entry = node.entry();
+    if entry.attr_value(gimli::DW_AT_declaration)?.is_some() {
+        return Ok(None);
+    }
+    let name = match die_name(dwarf, unit, entry.offset()) {
+        Some(n) => n,
+        None => return Ok(None),
+    };
+    let return_type = type_name(dwarf, unit, type_ref(entry), 0);
+
+    let mut args = Vec::new();
+    let mut children = node.children();
+    while let Some(child) = children.next()? {
+        let param = child.entry();
+        if param.tag() != gimli::DW_TAG_formal_parameter {
+            continue;
+        }
+        args.push(Arg {
+            name: die_name(dwarf, unit, param.offset()).unwrap_or(format!("arg{}", args.len())),
+            c_type: type_name(dwarf, unit, type_ref(param), 0),
+        });
+    }
+
+    Ok(Some(ElfFunction {
+        name,
+        args: Some(args),
+        return_type: Some(return_type),
+    }))
+}
+
+fn walk(
+    dwarf: &gimli::Dwarf<Reader>,
+    unit: &Unit<Reader>,
+    node: gimli::EntriesTreeNode<Reader>,
+    functions: &mut BTreeMap<String, ElfFunction>,
+) -> gimli::Result<()> {
+    if node.entry().tag() == gimli::DW_TAG_subprogram {
+        if let Some(f) = read_subprogram(dwarf, unit, node)? {
+            // only the functions that actually made it into the symbol table
+            if let Some(existing) = functions.get_mut(&f.name) {
+                if existing.args.is_none() {
+                    *existing = f;
+                }
+            }
+        }
+        return Ok(());
+    }
+    // functions can hide inside namespaces and the like
+    let mut children = node.children();
+    while let Some(child) = children.next()? {
+        walk(dwarf, unit, child, functions)?;
+    }
+    Ok(())
+}
+
+fn read_dwarf(
+    file: &object::File,
+    functions: &mut BTreeMap<String, ElfFunction>,
+) -> gimli::Result<()> {
+    let endian = if file.is_little_endian() {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+    let load_section = |id: gimli::SectionId| -> gimli::Result<Cow<[u8]>> {
+        Ok(file
+            .section_by_name(id.name())
+            .and_then(|s| s.uncompressed_data().ok())
+            .unwrap_or(Cow::Borrowed(&[])))
+    };
+    let sections = gimli::DwarfSections::load(load_section)?;
+    let dwarf = sections.borrow(|section| EndianSlice::new(section, endian));
+
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut tree = unit.entries_tree(None)?;
+        walk(&dwarf, &unit, tree.root()?, functions)?;
+    }
+    Ok(())
+}
+
+fn read_u32(data: &[u8], offset: usize, endian: Endianness) -> Option<u32> {
+    let bytes: [u8; 4] = data.get(offset..offset + 4)?.try_into().ok()?;
+    Some(match endian {
+        Endianness::Little => u32::from_le_bytes(bytes),
+        Endianness::Big => u32::from_be_bytes(bytes),
+    })
+}
+
+fn read_address(data: &[u8], offset: usize, endian: Endianness, address_size: usize) -> Option<u64> {
+    if address_size == 4 {
+        return read_u32(data, offset, endian).map(u64::from);
+    }
+    let bytes: [u8; 8] = data.get(offset..offset + 8)?.try_into().ok()?;
+    Some(match endian {
+        Endianness::Little => u64::from_le_bytes(bytes),
+        Endianness::Big => u64::from_be_bytes(bytes),
+    })
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+// pulls the next nul terminated string off the front of data
+fn take_cstr(data: &mut &[u8]) -> Option<String> {
+    let end = data.iter().position(|b| *b == 0)?;
+    let s = String::from_utf8_lossy(&data[..end]).into_owned();
+    *data = &data[end + 1..];
+    Some(s)
+}
+
+// See https://sourceware.org/systemtap/wiki/UserSpaceProbeImplementation for
+// the layout. Each note's description is the probe's address, the base
+// address and the semaphore address, then provider, name and arguments as
+// nul terminated strings. The addresses are as they were when the binary was
+// linked, if .stapsdt.base (at stapsdt_base) has moved since, e.g. with
+// prelink, they've moved as much.
+fn parse_stapsdt(
+    data: &[u8],
+    endian: Endianness,
+    address_size: usize,
+    stapsdt_base: Option<u64>,
+) -> Vec<UsdtProbe> {
+    let mut probes = Vec::new();
+    let mut offset = 0;
+    while let (Some(namesz), Some(descsz), Some(note_type)) = (
+        read_u32(data, offset, endian),
+        read_u32(data, offset + 4, endian),
+        read_u32(data, offset + 8, endian),
+    ) {
+        let name_start = offset + 12;
+        let desc_start = name_start + align4(namesz as usize);
+        let desc_end = desc_start + descsz as usize;
+        offset = align4(desc_end);
+        // type 3 notes owned by stapsdt are the probes
+        if note_type != 3 || data.get(name_start..name_start + 7) != Some(b"stapsdt") {
+            continue;
+        }
+        let address = |i: usize| read_address(data, desc_start + i * address_size, endian, address_size);
+        let (base, semaphore) = match (address(1), address(2)) {
+            (Some(base), Some(semaphore)) => (base, semaphore),
+            _ => break,
+        };
+        let mut desc = match data.get(desc_start + 3 * address_size..desc_end) {
+            Some(d) => d,
+            None => break,
+        };
+        let semaphore = match semaphore {
+            0 => None,
+            s => Some(s.wrapping_add(stapsdt_base.unwrap_or(base).wrapping_sub(base))),
+        };
+        if let (Some(provider), Some(name), Some(arguments)) = (
+            take_cstr(&mut desc),
+            take_cstr(&mut desc),
+            take_cstr(&mut desc),
+        ) {
+            probes.push(UsdtProbe {
+                provider,
+                name,
+                arguments,
+                semaphore,
+            });
+        }
+    }
+    probes
+}
+
+fn read_usdt_notes(file: &object::File) -> Vec<UsdtProbe> {
+    let address_size = if file.is_64() { 8 } else { 4 };
+    let stapsdt_base = file.section_by_name(".stapsdt.base").map(|s| s.address());
+    let mut probes = Vec::new();
+    for section in file.sections() {
+        if section.name() != Ok(".note.stapsdt") {
+            continue;
+        }
+        if let Ok(data) = section.data() {
+            probes.extend(parse_stapsdt(data, file.endianness(), address_size, stapsdt_base));
+        }
+    }
+    probes
+}
+
+// bigger than any binary worth probing, paths come from users so anything
+// can be asked for
+const MAX_BINARY_SIZE: u64 = 1 << 30;
+
+/// Every function defined in an ELF binary or shared library, along with the
+/// argument names and types when the binary was built with debug info, and
+/// any USDT probes it ships with.
+pub fn read_binary(path: &str) -> Result<Binary, String> {
+    let f = std::fs::File::open(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    let metadata = f.metadata().map_err(|e| format!("Could not read {}: {}", path, e))?;
+    if !metadata.is_file() {
+        return Err(format!("{} isn't a file", path));
+    }
+    if metadata.len() > MAX_BINARY_SIZE {
+        return Err(format!("{} is too big to read, over {} bytes", path, MAX_BINARY_SIZE));
+    }
+    // taken so a file that grows after the check still stops there
+    let mut data = Vec::with_capacity(metadata.len() as usize);
+    f.take(MAX_BINARY_SIZE)
+        .read_to_end(&mut data)
+        .map_err(|e| format!("Could not read {}: {}", path, e))?;
+    let file =
+        object::File::parse(&*data).map_err(|e| format!("Could not parse {}: {}", path, e))?;
+
+    let mut functions = BTreeMap::new();
+    for symbol in file.symbols().chain(file.dynamic_symbols()) {
+        if symbol.kind() != SymbolKind::Text || !symbol.is_definition() {
+            continue;
+        }
+        let name = match symbol.name() {
+            Ok(n) if !n.is_empty() => n.to_string(),
+            _ => continue,
+        };
+        functions.entry(name.clone()).or_insert(ElfFunction {
+            name,
+            args: None,
+            return_type: None,
+        });
+    }
+
+    // no debug info just means no argument names, the symbols still work
+    if let Err(e) = read_dwarf(&file, &mut functions) {
+        println!("Error reading DWARF from {}: {}", path, e);
+    }
+
+    Ok(Binary {
+        functions: functions.into_values().collect(),
+        usdt_probes: read_usdt_notes(&file),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a .note.stapsdt note as a little endian 64 bit binary has them
+    fn note(note_type: u32, addresses: [u64; 3], strings: &[&str]) -> Vec<u8> {
+        let mut desc = addresses.iter().flat_map(|a| a.to_le_bytes()).collect::<Vec<u8>>();
+        for s in strings {
+            desc.extend(s.as_bytes());
+            desc.push(0);
+        }
+        let mut note = vec![];
+        note.extend(8u32.to_le_bytes());
+        note.extend((desc.len() as u32).to_le_bytes());
+        note.extend(note_type.to_le_bytes());
+        note.extend(b"stapsdt\0");
+        note.extend(&desc);
+        note.resize(align4(note.len()), 0);
+        note
+    }
+
+    #[test]
+    fn reads_stapsdt_notes() {
+        let mut section = note(3, [0x1234, 0x1000, 0x2000], &["libc", "setjmp", "8@%rdi -4@%esi 8@%rdx"]);
+        section.extend(note(1, [0, 0, 0], &["not", "a", "probe"]));
+        section.extend(note(3, [0x1240, 0x1000, 0], &["python", "function__entry", ""]));
+
+        let probes = parse_stapsdt(&section, Endianness::Little, 8, Some(0x1000));
+        assert_eq!(probes.len(), 2);
+        assert_eq!((probes[0].provider.as_str(), probes[0].name.as_str()), ("libc", "setjmp"));
+        assert_eq!(probes[0].arguments, "8@%rdi -4@%esi 8@%rdx");
+        assert_eq!(probes[0].arg_count(), 3);
+        assert_eq!(probes[0].semaphore, Some(0x2000));
+        assert_eq!((probes[1].provider.as_str(), probes[1].name.as_str()), ("python", "function__entry"));
+        assert_eq!(probes[1].arg_count(), 0);
+        assert_eq!(probes[1].semaphore, None);
+
+        // .stapsdt.base moved 0x100 since linking, so did the semaphore
+        let probes = parse_stapsdt(&section, Endianness::Little, 8, Some(0x1100));
+        assert_eq!(probes[0].semaphore, Some(0x2100));
+        assert_eq!(probes[1].semaphore, None);
+
+        // a note cut short ends the section rather than reading past it
+        assert_eq!(parse_stapsdt(&section[..40], Endianness::Little, 8, None).len(), 0);
+    }
+
+    // something with known arguments for the test binary's own DWARF to have
+    #[no_mangle]
+    #[inline(never)]
+    extern "C" fn bpfquery_elf_test(count: u32, name: *const u8) -> i64 {
+        count as i64 + name.is_null() as i64
+    }
+
+    #[test]
+    fn reads_functions_and_arguments() {
+        assert_eq!(bpfquery_elf_test(1, std::ptr::null()), 2);
+        let binary = read_binary("/proc/self/exe").unwrap();
+        let f = binary.functions.iter().find(|f| f.name == "bpfquery_elf_test").unwrap();
+        let args = f.args.clone().unwrap();
+        let args = args.iter().map(|a| (a.name.as_str(), a.c_type.as_str())).collect::<Vec<_>>();
+        assert_eq!(args, vec![("count", "u32"), ("name", "u8 *")]);
+        assert_eq!(f.return_type.as_deref(), Some("i64"));
+    }
+
+    #[test]
+    fn only_reads_binaries() {
+        assert_eq!(read_binary("/proc/self").unwrap_err(), "/proc/self isn't a file");
+        assert!(read_binary("/no/such/binary").unwrap_err().starts_with("Could not read"));
+        let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        assert!(read_binary(manifest).unwrap_err().starts_with("Could not parse"));
+    }
+}
@@ -1,141 +1,1108 @@
-use openssh::{KnownHosts,  SessionBuilder, Stdio};
+use crate::aggregate::Groups;
+use crate::bpftrace_compiler::{Arch, CompiledQuery, Definition, Program, Target, Version};
+use crate::bpftrace_output::{Event, Hist};
+use crate::hosts::{with_sudo, HostConfig};
+use crate::system;
+use crate::transform::Transform;
+use futures_util::future::{BoxFuture, FutureExt};
+use openssh::{Session, Stdio};
+use serde::Serialize;
 use serde_json::Value;
-use std::collections::HashMap;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::sync::broadcast;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader, Lines};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
 
-pub async fn execute_bpf(
-    hostname: String,
-    headers: Vec<String>,
+/// Somewhere bpftrace can be run: a host over ssh, this machine, or a
+/// recording for when there's no kernel to trace.
+pub trait Executor: Send + Sync {
+    /// What's being traced, for messages.
+    fn name(&self) -> String;
+    /// Runs a command until it exits.
+    fn output(&self, program: String, args: Vec<String>) -> BoxFuture<'_, Result<CommandOutput, String>>;
+    /// Starts a command whose output is read as it's printed.
+    fn spawn(&self, program: String, args: Vec<String>) -> BoxFuture<'_, Result<Process, String>>;
+}
+
+pub struct CommandOutput {
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// A running command, dropping it stops the command.
+pub struct Process {
+    pub stdout: Pin<Box<dyn AsyncRead + Send>>,
+    pub stderr: Pin<Box<dyn AsyncRead + Send>>,
+    // whatever has to stay alive while it runs, e.g. the ssh session
+    _child: Box<dyn Send>,
+}
+
+/// Runs commands on a host over ssh. Sessions are multiplexed and kept
+/// around, so a query starts on a connection that's already open rather than
+/// a new one every keystroke.
+pub struct SshExecutor {
+    pub hostname: String,
+    pub config: HostConfig,
+    pool: tokio::sync::Mutex<SessionPool>,
+}
+
+#[derive(Default)]
+struct SessionPool {
+    sessions: Vec<Arc<Session>>,
+    // whether commands need sudo, when the config leaves it to be worked out
+    sudo: Option<bool>,
+    // connects that have failed in a row, and when to try again
+    failures: u32,
+    retry_at: Option<Instant>,
+    // a session dropped, whatever ran on it may still be running
+    lost: bool,
+}
+
+// sshd allows 10 channels on a connection by default (MaxSessions), a couple
+// are left for the pkills that stop queries
+const CHANNELS_PER_SESSION: usize = 8;
+const KEEPALIVE: Duration = Duration::from_secs(15);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+impl SshExecutor {
+    pub fn new(hostname: String, config: HostConfig) -> SshExecutor {
+        SshExecutor {
+            hostname,
+            config,
+            pool: Default::default(),
+        }
+    }
+
+    async fn connect(&self) -> Result<Session, String> {
+        let mut s = self.config.session_builder();
+        s.server_alive_interval(KEEPALIVE);
+        let destination = self.config.destination.as_ref().unwrap_or(&self.hostname);
+        s.connect(destination)
+            .await
+            .map_err(|e| format!("Error connecting to {}: {}", self.hostname, e))
+    }
+
+    /// A session with a channel free, opening one if they're all busy or
+    /// have dropped, and whether commands on it need sudo. bpftrace that was
+    /// running over a dropped session gets cleaned up once it's back.
+    async fn session(&self) -> Result<(Arc<Session>, bool), String> {
+        let (session, sudo, reconnected) = self.pooled_session().await?;
+        if reconnected {
+            sweep_orphans(self).await;
+        }
+        Ok((session, sudo))
+    }
+
+    async fn pooled_session(&self) -> Result<(Arc<Session>, bool, bool), String> {
+        let mut pool = self.pool.lock().await;
+        // every running command holds a clone of its session
+        while let Some(i) = pool
+            .sessions
+            .iter()
+            .position(|s| Arc::strong_count(s) <= CHANNELS_PER_SESSION)
+        {
+            let session = pool.sessions[i].clone();
+            if session.check().await.is_ok() {
+                return Ok((session, pool.sudo.unwrap_or(false), false));
+            }
+            pool.sessions.remove(i);
+            pool.lost = true;
+        }
+
+        if let Some(retry_at) = pool.retry_at {
+            let now = Instant::now();
+            if now < retry_at {
+                return Err(format!(
+                    "Can't connect to {}, trying again in {}s",
+                    self.hostname,
+                    (retry_at - now).as_secs() + 1
+                ));
+            }
+        }
+        match self.connect().await {
+            Ok(session) => {
+                pool.failures = 0;
+                pool.retry_at = None;
+                if pool.sudo.is_none() {
+                    pool.sudo = Some(match self.config.sudo {
+                        Some(sudo) => sudo,
+                        None => !is_root(&session).await,
+                    });
+                }
+                let session = Arc::new(session);
+                pool.sessions.push(session.clone());
+                let reconnected = std::mem::take(&mut pool.lost);
+                Ok((session, pool.sudo.unwrap_or(false), reconnected))
+            }
+            Err(e) => {
+                pool.failures += 1;
+                pool.retry_at = Some(Instant::now() + backoff(pool.failures));
+                Err(e)
+            }
+        }
+    }
+}
+
+// how long to wait before connecting again after failures in a row: 1s, 2s,
+// 4s... up to MAX_BACKOFF
+fn backoff(failures: u32) -> Duration {
+    match failures {
+        0 => Duration::ZERO,
+        n => Duration::from_secs(1 << (n - 1).min(5)).min(MAX_BACKOFF),
+    }
+}
+
+async fn is_root(session: &Session) -> bool {
+    match session.command("id").arg("-u").output().await {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).trim() == "0",
+        Err(_) => false,
+    }
+}
+
+impl Executor for SshExecutor {
+    fn name(&self) -> String {
+        self.hostname.clone()
+    }
+
+    fn output(&self, program: String, args: Vec<String>) -> BoxFuture<'_, Result<CommandOutput, String>> {
+        async move {
+            let (session, sudo) = self.session().await?;
+            let (program, args) = with_sudo(sudo, program, args);
+            let output = session
+                .command(program)
+                .args(args)
+                .output()
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(CommandOutput {
+                success: output.status.success(),
+                stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+                stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+            })
+        }
+        .boxed()
+    }
+
+    fn spawn(&self, program: String, args: Vec<String>) -> BoxFuture<'_, Result<Process, String>> {
+        async move {
+            let (session, sudo) = self.session().await?;
+            let (program, args) = with_sudo(sudo, program, args);
+            let mut child = session
+                .arc_command(program)
+                .args(args)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .await
+                .map_err(|e| e.to_string())?;
+            let stdout = child.stdout().take().ok_or("no stdout")?;
+            let stderr = child.stderr().take().ok_or("no stderr")?;
+            Ok(Process {
+                stdout: Box::pin(stdout),
+                stderr: Box::pin(stderr),
+                _child: Box::new(child),
+            })
+        }
+        .boxed()
+    }
+}
+
+/// Runs commands on this machine, with `sudo -n` in front when bpfquery isn't
+/// running as root.
+pub struct LocalExecutor {
+    pub sudo: bool,
+}
+
+impl LocalExecutor {
+    fn command(&self, program: String, args: Vec<String>) -> tokio::process::Command {
+        let (program, args) = with_sudo(self.sudo, program, args);
+        let mut command = tokio::process::Command::new(program);
+        command.args(args);
+        command
+    }
+}
+
+impl Executor for LocalExecutor {
+    fn name(&self) -> String {
+        "localhost".to_string()
+    }
+
+    fn output(&self, program: String, args: Vec<String>) -> BoxFuture<'_, Result<CommandOutput, String>> {
+        async move {
+            let output = self
+                .command(program.clone(), args)
+                .output()
+                .await
+                .map_err(|e| format!("Error running {}: {}", program, e))?;
+            Ok(CommandOutput {
+                success: output.status.success(),
+                stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+                stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+            })
+        }
+        .boxed()
+    }
+
+    fn spawn(&self, program: String, args: Vec<String>) -> BoxFuture<'_, Result<Process, String>> {
+        async move {
+            let mut child = self
+                .command(program.clone(), args)
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .map_err(|e| format!("Error running {}: {}", program, e))?;
+            let stdout = child.stdout.take().ok_or("no stdout")?;
+            let stderr = child.stderr.take().ok_or("no stderr")?;
+            Ok(Process {
+                stdout: Box::pin(stdout),
+                stderr: Box::pin(stderr),
+                _child: Box::new(child),
+            })
+        }
+        .boxed()
+    }
+}
+
+/// Plays back what bpftrace printed once, so everything past the compiler can
+/// run without root or a kernel. Every query gets the same recording.
+pub struct FakeExecutor {
+    /// The output of `bpftrace -f json`, one json object per line.
+    pub recording: String,
+    /// What other commands print, by command line, e.g. "bpftrace -l".
+    /// Commands that aren't here fail.
+    pub outputs: HashMap<String, String>,
+}
+
+impl FakeExecutor {
+    pub fn from_file(path: &str) -> Result<FakeExecutor, String> {
+        let recording = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(FakeExecutor {
+            recording,
+            outputs: HashMap::new(),
+        })
+    }
+}
+
+impl Executor for FakeExecutor {
+    fn name(&self) -> String {
+        "a recording".to_string()
+    }
+
+    fn output(&self, program: String, args: Vec<String>) -> BoxFuture<'_, Result<CommandOutput, String>> {
+        let command = std::iter::once(program).chain(args).collect::<Vec<String>>().join(" ");
+        let output = match self.outputs.get(&command) {
+            Some(stdout) => CommandOutput {
+                success: true,
+                stdout: stdout.clone(),
+                stderr: String::new(),
+            },
+            None => CommandOutput {
+                success: false,
+                stdout: String::new(),
+                stderr: String::new(),
+            },
+        };
+        async move { Ok(output) }.boxed()
+    }
+
+    fn spawn(&self, _program: String, _args: Vec<String>) -> BoxFuture<'_, Result<Process, String>> {
+        let stdout = std::io::Cursor::new(self.recording.clone().into_bytes());
+        async move {
+            Ok(Process {
+                stdout: Box::pin(stdout),
+                stderr: Box::pin(tokio::io::empty()),
+                _child: Box::new(()),
+            })
+        }
+        .boxed()
+    }
+}
+
+/// Figures out what the host can run so the compiler can make use of it, an
+/// error means the host couldn't be reached.
+pub async fn detect_target(executor: &dyn Executor) -> Result<Target, String> {
+    // the kernel exposes its BTF here when it was built with it
+    let btf = executor
+        .output("test".to_string(), vec!["-e".to_string(), "/sys/kernel/btf/vmlinux".to_string()])
+        .await?
+        .success;
+
+    let version = executor
+        .output("bpftrace".to_string(), vec!["--version".to_string()])
+        .await
+        .ok()
+        .and_then(|o| Version::parse(&o.stdout));
+
+    // older bpftrace call fentry kfunc, either way it's listed under the
+    // probe types as e.g. "  fentry: yes"
+    let info = executor
+        .output("bpftrace".to_string(), vec!["--info".to_string()])
+        .await
+        .map(|o| format!("{}{}", o.stdout, o.stderr))
+        .unwrap_or_default();
+    let listed = info
+        .lines()
+        .filter_map(|l| l.trim().split_once(':'))
+        .find(|(k, _)| matches!(k.trim(), "fentry" | "kfunc"));
+    let fentry = listed.map_or(btf, |(_, v)| v.trim() == "yes");
+    let kfunc = listed.is_some_and(|(k, _)| k.trim() == "kfunc");
+
+    let arch = executor
+        .output("uname".to_string(), vec!["-m".to_string()])
+        .await
+        .ok()
+        .filter(|o| o.success)
+        .map(|o| Arch::from_uname(&o.stdout));
+
+    // newer than anything --info or the version says, so just try them
+    let (strlen, substr) = futures_util::join!(
+        compiles(executor, "BEGIN { $n = strlen(\"ab\"); }"),
+        compiles(executor, "BEGIN { $s = substr(\"ab\", 0, 1); }"),
+    );
+
+    Ok(Target {
+        btf,
+        fentry,
+        kfunc,
+        version,
+        arch,
+        strlen,
+        substr,
+    })
+}
+
+// whether bpftrace on the host takes the program, without running it
+async fn compiles(executor: &dyn Executor, program: &str) -> bool {
+    let args = ["--dry-run", "-e", program].map(|a| a.to_string()).to_vec();
+    executor
+        .output("bpftrace".to_string(), args)
+        .await
+        .is_ok_and(|o| o.success)
+}
+
+/// Everything `bpftrace -l` can see on the host.
+pub async fn list_probes(executor: &dyn Executor) -> Vec<String> {
+    match executor.output("bpftrace".to_string(), vec!["-l".to_string()]).await {
+        Ok(output) => output
+            .stdout
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect(),
+        Err(e) => {
+            println!("Error listing probes on {}: {}", executor.name(), e);
+            vec![]
+        }
+    }
+}
+
+// Every bpftrace program starts with a `// [bpfquery:<machine>:<pid>:<n>]`
+// comment naming the bpfquery that started it, so it can be found with pkill
+// on the host later.
+static NEXT_PROGRAM: AtomicUsize = AtomicUsize::new(1);
+
+// the tags of the bpftrace this bpfquery is running, anything else with our
+// pid in its tag was left behind, e.g. by a dropped ssh session
+static RUNNING: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+// keeps a tag in RUNNING for as long as it's held
+struct Running(String);
+
+impl Running {
+    fn new(tag: &str) -> Running {
+        RUNNING.lock().unwrap_or_else(|e| e.into_inner()).insert(tag.to_string());
+        Running(tag.to_string())
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.0);
+    }
+}
+
+// how long bpftrace gets to run its END blocks and exit after SIGINT before
+// it gets SIGKILL
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// this machine, as it goes in the tags
+fn machine() -> String {
+    whoami::devicename()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+fn new_tag() -> String {
+    format!(
+        "bpfquery:{}:{}:{}",
+        machine(),
+        std::process::id(),
+        NEXT_PROGRAM.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+// a pkill -f pattern for bpftrace tagged with a tag starting with prefix.
+// Anchored on argv[0] so a sudo or timeout running bpftrace isn't signalled
+// too, and [[] is a literal [ that, unlike \[, doesn't match itself when the
+// pattern turns up in a command line, e.g. the shell ssh runs pkill with.
+fn tag_pattern(prefix: &str) -> String {
+    format!("^[^ ]*bpftrace .*[[]{}", prefix)
+}
+
+async fn signal(executor: &dyn Executor, signal: &str, pattern: String) {
+    let args = vec![format!("-{}", signal), "-f".to_string(), pattern];
+    if let Err(e) = executor.output("pkill".to_string(), args).await {
+        println!("Error sending SIG{} on {}: {}", signal, executor.name(), e);
+    }
+}
+
+// SIGINT so END blocks run and probes get detached, then SIGKILL if it's
+// still going after STOP_TIMEOUT
+async fn stop_bpftrace<R: AsyncBufReadExt + Unpin>(executor: &dyn Executor, tag: &str, lines: &mut Lines<R>) {
+    signal(executor, "INT", tag_pattern(&format!("{}]", tag))).await;
+    // stdout closes once it has exited
+    let exited = tokio::time::timeout(STOP_TIMEOUT, async {
+        while let Ok(Some(_)) = lines.next_line().await {}
+    })
+    .await
+    .is_ok();
+    if !exited {
+        println!("bpftrace {} didn't stop after SIGINT, killing it", tag);
+        signal(executor, "KILL", tag_pattern(&format!("{}]", tag))).await;
+    }
+}
+
+/// Kills bpftrace left running by an earlier bpfquery on this machine, e.g.
+/// one that crashed or lost its connection before it could stop them, and
+/// any of this one's that outlived their query. Ones whose bpfquery is still
+/// running them are left alone, there can be more than one bpfquery on a
+/// machine.
+pub async fn sweep_orphans(executor: &dyn Executor) {
+    let prefix = format!("bpfquery:{}:", machine());
+    let args = vec!["-af".to_string(), tag_pattern(&prefix)];
+    let running = match executor.output("pgrep".to_string(), args).await {
+        Ok(output) => output.stdout,
+        Err(e) => {
+            println!("Error looking for orphaned bpftrace on {}: {}", executor.name(), e);
+            return;
+        }
+    };
+    let ours = RUNNING.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let alive = |pid: u32| Path::new(&format!("/proc/{}", pid)).exists();
+    for orphans in orphans(&running, &prefix, &ours, alive) {
+        println!("Killing bpftrace on {} left behind by {}", executor.name(), orphans);
+        signal(executor, "KILL", tag_pattern(&orphans)).await;
+    }
+}
+
+// The tag prefixes to kill out of what pgrep found: everything of a bpfquery
+// that's gone, and this one's that aren't running a query any more.
+fn orphans(pgrep: &str, prefix: &str, running: &BTreeSet<String>, alive: impl Fn(u32) -> bool) -> Vec<String> {
+    let tags = pgrep
+        .split(&format!("[{}", prefix))
+        .skip(1)
+        .filter_map(|tag| Some(format!("{}{}", prefix, tag.split(']').next()?)))
+        .collect::<BTreeSet<String>>();
+    let mut orphans = BTreeSet::new();
+    for tag in tags {
+        // the pid in a tag is the bpfquery's, on this machine
+        let pid = match tag[prefix.len()..].split(':').next().and_then(|p| p.parse::<u32>().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if pid == std::process::id() {
+            if !running.contains(&tag) {
+                orphans.insert(format!("{}]", tag));
+            }
+        } else if !alive(pid) {
+            orphans.insert(format!("{}{}:", prefix, pid));
+        }
+    }
+    orphans.into_iter().collect()
+}
+
+// the system tables are answered right here, no bpftrace needed
+fn execute_catalog(sql: &str, results_sender: broadcast::Sender<QueryEvent>) {
+    match system::query(sql) {
+        Ok(rows) => {
+            for (i, values) in rows.into_iter().enumerate() {
+                let mut row = vec![Value::Number(serde_json::Number::from(i + 1))];
+                row.extend(values);
+                if results_sender.send(QueryEvent::Row(row)).is_err() {
+                    return;
+                }
+            }
+        }
+        Err(e) => {
+            let _ = results_sender.send(QueryEvent::Error(e));
+        }
+    }
+    let _ = results_sender.send(QueryEvent::Done);
+}
+
+// CREATE VIEW and the like, answered with a row saying what happened
+fn execute_definition(definition: &Definition, results_sender: broadcast::Sender<QueryEvent>) {
+    let _ = results_sender.send(match definition.run() {
+        Ok(m) => QueryEvent::Row(vec![Value::from(1), Value::String(m)]),
+        Err(e) => QueryEvent::Error(e),
+    });
+    let _ = results_sender.send(QueryEvent::Done);
+}
+
+/// What a running query sends on, rows are only ever results.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryEvent {
+    /// A row of results, its id first.
+    Row(Vec<Value>),
+    /// How one of the hosts is getting on.
+    Status(HostStatus),
+    /// The hist() and lhist() maps one of the hosts printed.
+    Hist(HostHists),
+    /// Something went wrong that isn't down to any one host.
+    Error(String),
+    /// Every host has stopped, nothing else is coming.
+    Done,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HostStatus {
+    pub host: String,
+    pub state: HostState,
+    /// Why it failed, or something it said along the way, e.g. that it lost
+    /// events.
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HostHists {
+    pub host: String,
+    pub hists: BTreeMap<String, Hist>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostState {
+    Connecting,
+    Running,
+    Failed,
+    /// bpftrace exited on its own and printed its maps, which it does when
+    /// the program calls exit().
+    Done,
+}
+
+// what one host's bpftrace says, execute_bpf merges them
+enum HostEvent {
+    /// The printed columns, transformed and with the host filled in.
+    Row(Vec<Value>),
+    Status(HostState, Option<String>),
+    Hist(BTreeMap<String, Hist>),
+}
+
+// runs bpf on one host and passes on what it prints until it's done or told
+// to stop, then makes sure it's stopped
+async fn run_on_host(
+    host: String,
+    executor: Arc<dyn Executor>,
     bpf: String,
-    results_sender: broadcast::Sender<Vec<Value>>,
+    transforms: Vec<Option<Transform>>,
     demo: bool,
+    mut stop: watch::Receiver<bool>,
+    events: mpsc::UnboundedSender<(String, HostEvent)>,
 ) {
-    let mut h = hostname.clone();
-    let mut s = SessionBuilder::default();
-    if hostname == "bpftrace_machine" {
-        h = std::env::var("BPFTRACE_MACHINE").unwrap();
-        let user = "root".to_string();
-        h = format!("{}@{}", user, h);
-        s.keyfile("/app/bpftrace_machine");
+    let send = |event| events.send((host.clone(), event));
+    let tag = new_tag();
+    // before it's started, so a sweep can't mistake it for an orphan
+    let _running = Running::new(&tag);
+    let bpf = format!("// [{}]\n{}", tag, bpf);
+    let mut args = vec!["-f", "json", "-e", &bpf]
+        .into_iter()
+        .map(|a| a.to_string())
+        .collect::<Vec<String>>();
+    let mut program = "bpftrace".to_string();
+    // only run this command for thirty seconds
+    if demo {
+        args.splice(0..0, ["30s".to_string(), program]);
+        program = "timeout".to_string();
     }
-    s.known_hosts_check(KnownHosts::Accept);
 
-    let session = s.connect(h).await.unwrap();
+    let _ = send(HostEvent::Status(HostState::Connecting, None));
+    let process = match executor.spawn(program, args).await {
+        Ok(p) => p,
+        Err(e) => {
+            let _ = send(HostEvent::Status(HostState::Failed, Some(e)));
+            return;
+        }
+    };
+    let _ = send(HostEvent::Status(HostState::Running, None));
 
-    let mut remote_cmd;
-    // only run this command for five seconds
-    if demo {
-        remote_cmd = session.command("timeout");
-        remote_cmd.arg("30s");
-        remote_cmd.arg("bpftrace");
-        remote_cmd.arg("-f");
-        remote_cmd.arg("json");
-        remote_cmd.arg("-e");
-        remote_cmd.arg(bpf);
-    } else {
-        remote_cmd = session.command("bpftrace");
-        remote_cmd.arg("-f");
-        remote_cmd.arg("json");
-        remote_cmd.arg("-e");
-        remote_cmd.arg(bpf);
-    }
-
-    remote_cmd.stdout(Stdio::piped());
-    remote_cmd.stderr(Stdio::piped());
-
-    let mut handle = remote_cmd.spawn().await.unwrap();
-    let stdout = handle.stdout().take().unwrap();
-    let stdout_reader = BufReader::new(stdout);
-
-    let stderr = handle.stderr().as_mut().unwrap();
-    let stderr_reader = BufReader::new(stderr);
-
-    let mut lines = stdout_reader.lines();
-    let mut errors = stderr_reader.lines();
+    let mut lines = BufReader::new(process.stdout).lines();
+    let mut errors = BufReader::new(process.stderr).lines();
+    let mut errors_done = false;
+    let mut maps = false;
 
     loop {
         tokio::select! {
-        error = errors.next_line() => match error {
+        // a dropped sender means stop too
+        _ = stop.changed() => break,
+        error = errors.next_line(), if !errors_done => match error {
             Ok(Some(line)) => {
-                    let row = [Value::String(line)].to_vec();
-                    results_sender.send(row.clone()).unwrap();
+                    let _ = send(HostEvent::Status(HostState::Failed, Some(line)));
                     break;
             }
-            Ok(None) => break, // End of stream
+            // stdout can still have rows to read
+            Ok(None) => errors_done = true,
             Err(e) => {
-                println!("Error reading line: {:?}", e);
+                println!("Error reading line from {}: {:?}", host, e);
+                let _ = send(HostEvent::Status(HostState::Failed, Some(e.to_string())));
                 break;
             }
         },
         line = lines.next_line() => {
             match line {
                 Ok(Some(line)) => {
-                    //parse json
-                    if line.is_empty() {
+                    if line.trim().is_empty() {
                         continue;
                     }
-                    let v: serde_json::Value = serde_json::from_str(&line).unwrap();
-                    if v["type"] == "lost_events" {
-                        continue;
-                    }
-                    if v["type"] == "attached_probes" {
-                        continue;
-                    }
-                    if v["type"] == "map" {
-                        //for now, this indicates the end of the query
-                        let row = [Value::String("DONE".to_string())].to_vec();
-                        results_sender.send(row).unwrap();
-                        break;
-                    }
-                    //convert array of key value pairs to dict
-
-                    let mut d = HashMap::new();
-                    let mut id = 0;
-
-                    for kv in v["data"].as_array().unwrap() {
-                        let k = &kv[0];
-                        let v = &kv[1];
-                        match k {
-                            serde_json::Value::Number(_) => {
-                                let i = k.as_u64().unwrap();
-                                let h = headers[i as usize].clone();
-                                d.insert(h.to_string(), v.clone());
-                            }
-                            serde_json::Value::String(_) => {
-                                id = v.as_u64().unwrap();
-                            }
-                            _ => {
-                                println!("unexpected key type");
-                            }
+                    let event = match Event::parse(&line) {
+                        Ok(Event::Row(columns)) => row(&host, &transforms, columns).map(HostEvent::Row),
+                        Ok(Event::Map(_) | Event::Stats(_)) => {
+                            maps = true;
+                            continue;
                         }
-                    }
-
-                    let mut row = Vec::new();
-                    //put the id in
-                    row.push(Value::Number(serde_json::Number::from(id)));
-                    for header in headers.clone() {
-                        let value = &d[header.as_str()];
-                        row.push(value.clone());
-                    }
-                    let result = results_sender.send(row.clone());
-                    match result {
-                        Ok(_) => {}
-                        Err(e) => {
-                            println!("Error sending results: {:#?}", e);
-                            break;
+                        Ok(Event::Hist(hists)) => {
+                            maps = true;
+                            Ok(HostEvent::Hist(hists))
                         }
+                        Ok(Event::AttachedProbes(n)) => Ok(HostEvent::Status(
+                            HostState::Running,
+                            Some(format!("attached {} probe{}", n, if n == 1 { "" } else { "s" })),
+                        )),
+                        Ok(Event::LostEvents(n)) => Err(format!(
+                            "bpftrace lost {} events, they came faster than they could be read",
+                            n
+                        )),
+                        Ok(Event::Error(e)) => Err(e),
+                        // nothing we print, or not used yet
+                        Ok(_) => continue,
+                        Err(e) => Err(e),
+                    };
+                    // it carries on after these
+                    let event = event.unwrap_or_else(|e| HostEvent::Status(HostState::Running, Some(e)));
+                    if send(event).is_err() {
+                        break;
                     }
                 }
                 Ok(None) => {
-                    results_sender.send([Value::String("Done".to_string())].to_vec()).unwrap();
+                    let _ = send(match maps {
+                        true => HostEvent::Status(HostState::Done, None),
+                        false => HostEvent::Status(HostState::Failed, Some("bpftrace exited".to_string())),
+                    });
+                    break;
                 }, // End of stream
                 Err(e) => {
-                    println!("Error reading line: {:?}", e);
+                    println!("Error reading line from {}: {:?}", host, e);
+                    let _ = send(HostEvent::Status(HostState::Failed, Some(e.to_string())));
                     break;
                 }
             }
         }
         }
     }
-}
\ No newline at end of file
+
+    // it's stopped here however the loop ended, so nothing is left attached
+    stop_bpftrace(executor.as_ref(), &tag, &mut lines).await;
+}
+
+// puts a row's columns in order, through their transforms
+fn row(host: &str, transforms: &[Option<Transform>], columns: Vec<(usize, Value)>) -> Result<Vec<Value>, String> {
+    let mut values = vec![Value::Null; transforms.len()];
+    for (i, v) in columns {
+        let transform = transforms
+            .get(i)
+            .ok_or_else(|| format!("bpftrace printed column {}, there are only {}", i, transforms.len()))?;
+        values[i] = match transform {
+            Some(Transform::Host) => Value::String(host.to_string()),
+            Some(t) => t.apply(v),
+            None => v,
+        };
+    }
+    Ok(values)
+}
+
+/// Runs the query on every host at once and sends on their rows as one
+/// stream, with how each host is getting on alongside. GROUP BY queries are
+/// folded together across hosts, and when one host fails the rest carry on.
+pub async fn execute_bpf(
+    hosts: Vec<(String, Arc<dyn Executor>)>,
+    query: CompiledQuery,
+    results_sender: broadcast::Sender<QueryEvent>,
+    demo: bool,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let bpf = match &query.program {
+        Program::Bpftrace(bpf) => bpf,
+        Program::Catalog(sql) => return execute_catalog(sql, results_sender),
+        Program::Define(d) => return execute_definition(d, results_sender),
+    };
+
+    let (stop_hosts, stopped) = watch::channel(false);
+    let (events_sender, mut events) = mpsc::unbounded_channel();
+    let mut running = vec![];
+    for (host, executor) in hosts.iter().cloned() {
+        running.push(tokio::task::spawn(run_on_host(
+            host,
+            executor,
+            bpf.clone(),
+            query.transforms.clone(),
+            demo,
+            stopped.clone(),
+            events_sender.clone(),
+        )));
+    }
+    drop(events_sender);
+
+    // GROUP BY rows are folded in here and the groups that changed are sent
+    // a few times a second rather than on every event
+    let mut groups = query.group_by.clone().map(Groups::new);
+    let mut flush = tokio::time::interval(std::time::Duration::from_millis(250));
+    let mut next_id = 1u64;
+
+    'read: loop {
+        tokio::select! {
+        _ = &mut stop => break,
+        _ = flush.tick(), if groups.is_some() => {
+            if let Some(groups) = groups.as_mut() {
+                for row in groups.changed_rows() {
+                    if results_sender.send(QueryEvent::Row(row)).is_err() {
+                        break 'read;
+                    }
+                }
+            }
+        },
+        event = events.recv() => match event {
+            Some((_, HostEvent::Row(values))) => {
+                if let Some(groups) = groups.as_mut() {
+                    groups.add(&values);
+                    continue;
+                }
+                let mut row = vec![Value::from(next_id)];
+                next_id += 1;
+                row.extend(values);
+                if let Err(e) = results_sender.send(QueryEvent::Row(row)) {
+                    println!("Error sending results: {:#?}", e);
+                    break;
+                }
+            }
+            Some((host, HostEvent::Status(state, message))) => {
+                let _ = results_sender.send(QueryEvent::Status(HostStatus { host, state, message }));
+            }
+            Some((host, HostEvent::Hist(hists))) => {
+                let _ = results_sender.send(QueryEvent::Hist(HostHists { host, hists }));
+            }
+            // every host is done
+            None => {
+                if let Some(groups) = groups.as_mut() {
+                    for row in groups.changed_rows() {
+                        let _ = results_sender.send(QueryEvent::Row(row));
+                    }
+                }
+                let _ = results_sender.send(QueryEvent::Done);
+                break;
+            }
+        },
+        }
+    }
+
+    // wait for every host to stop so nothing is left running once this returns
+    let _ = stop_hosts.send(true);
+    for host in running {
+        let _ = host.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bpftrace_compiler::compile_ast_to_bpftrace;
+    use crate::parser::parse_bpfquery_sql;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+    use crate::bpftrace_output::Bucket;
+
+    fn replay(recording: &str) -> Arc<dyn Executor> {
+        Arc::new(FakeExecutor {
+            recording: recording.to_string(),
+            outputs: HashMap::new(),
+        })
+    }
+
+    // everything execute_bpf sends for sql on hosts
+    async fn run(sql: &str, hosts: Vec<(String, Arc<dyn Executor>)>) -> Vec<QueryEvent> {
+        let query = compile_ast_to_bpftrace(parse_bpfquery_sql(sql).unwrap(), Target::default()).unwrap();
+        let (sender, mut receiver) = broadcast::channel(100);
+        let (_stop, stop) = oneshot::channel();
+        execute_bpf(hosts, query, sender, false, stop).await;
+        let mut results = vec![];
+        while let Ok(event) = receiver.try_recv() {
+            results.push(event);
+        }
+        results
+    }
+
+    fn status(host: &str, state: HostState, message: Option<&str>) -> QueryEvent {
+        QueryEvent::Status(HostStatus {
+            host: host.to_string(),
+            state,
+            message: message.map(|m| m.to_string()),
+        })
+    }
+
+    #[tokio::test]
+    async fn replays_rows() {
+        let recording = r#"{"type": "attached_probes", "data": {"probes": 1}}
+{"type": "value", "data": [["id", 1], [0, "bash"], [1, 3]]}
+{"type": "lost_events", "data": {"events": 2}}
+{"type": "value", "data": [["id", 2], [0, "sshd"], [1, 4]]}
+{"type": "map", "data": {"@q1_id": {"id": 2}}}
+"#;
+        let results = run("select upper(comm), pid from kprobe.vfs_read", vec![("replay".to_string(), replay(recording))]).await;
+        assert_eq!(
+            results,
+            vec![
+                status("replay", HostState::Connecting, None),
+                status("replay", HostState::Running, None),
+                status("replay", HostState::Running, Some("attached 1 probe")),
+                QueryEvent::Row(vec![json!(1), json!("BASH"), json!(3)]),
+                status(
+                    "replay",
+                    HostState::Running,
+                    Some("bpftrace lost 2 events, they came faster than they could be read")
+                ),
+                QueryEvent::Row(vec![json!(2), json!("SSHD"), json!(4)]),
+                status("replay", HostState::Done, None),
+                QueryEvent::Done,
+            ]
+        );
+    }
+
+    #[test]
+    fn tag_pattern_only_matches_bpftrace() {
+        let pattern = tag_pattern("bpfquery:m:1:2]");
+        assert_eq!(pattern, "^[^ ]*bpftrace .*[[]bpfquery:m:1:2]");
+        // the same extended regex pkill -f uses, on made up command lines
+        let lines = [
+            "bpftrace -f json -e // [bpfquery:m:1:2]",
+            "/usr/bin/bpftrace -f json -e // [bpfquery:m:1:2]",
+            "bpftrace -f json -e // [bpfquery:m:1:23]",
+            "sudo -n bpftrace -f json -e // [bpfquery:m:1:2]",
+            &format!("bash -c pkill -f {}", pattern),
+        ];
+        let grep = std::process::Command::new("grep")
+            .args(["-E", &pattern])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        std::io::Write::write_all(&mut grep.stdin.as_ref().unwrap(), lines.join("\n").as_bytes()).unwrap();
+        let output = grep.wait_with_output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout).lines().collect::<Vec<&str>>(), &lines[..2]);
+    }
+
+    #[test]
+    fn finds_orphans() {
+        let me = std::process::id();
+        let pgrep = format!(
+            "10 bpftrace -f json -e // [bpfquery:m:{me}:1]\nkprobe:vfs_read {{}}\n\
+             11 bpftrace -f json -e // [bpfquery:m:{me}:2]\n\
+             12 bpftrace -f json -e // [bpfquery:m:7:1]\n\
+             13 bpftrace -f json -e // [bpfquery:m:7:2]\n\
+             14 bpftrace -f json -e // [bpfquery:m:8:1]\n"
+        );
+        let running = BTreeSet::from([format!("bpfquery:m:{}:2", me)]);
+        // 7 is gone, 8 is another bpfquery that's still going
+        let found = orphans(&pgrep, "bpfquery:m:", &running, |pid| pid == 8);
+        assert_eq!(
+            found.into_iter().collect::<BTreeSet<String>>(),
+            BTreeSet::from(["bpfquery:m:7:".to_string(), format!("bpfquery:m:{}:1]", me)])
+        );
+        assert!(orphans("", "bpfquery:m:", &running, |_| false).is_empty());
+    }
+
+    #[test]
+    fn backs_off() {
+        let schedule = (0..8).map(|n| backoff(n).as_secs()).collect::<Vec<u64>>();
+        assert_eq!(schedule, vec![0, 1, 2, 4, 8, 16, 30, 30]);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn local_commands() {
+        let command = LocalExecutor { sudo: true }.command("bpftrace".to_string(), vec!["-l".to_string()]);
+        let command = command.as_std();
+        assert_eq!(command.get_program(), "sudo");
+        assert_eq!(command.get_args().collect::<Vec<_>>(), vec!["-n", "bpftrace", "-l"]);
+        let command = LocalExecutor { sudo: false }.command("bpftrace".to_string(), vec!["-l".to_string()]);
+        assert_eq!(command.as_std().get_program(), "bpftrace");
+    }
+
+    #[tokio::test]
+    async fn runs_local_commands() {
+        let local = LocalExecutor { sudo: false };
+        let output = local.output("echo".to_string(), vec!["hi".to_string()]).await.unwrap();
+        assert!(output.success);
+        assert_eq!(output.stdout, "hi\n");
+        let missing = local.output("bpfquery-no-such-command".to_string(), vec![]).await;
+        assert!(missing.err().unwrap().starts_with("Error running bpfquery-no-such-command"));
+    }
+
+    #[tokio::test]
+    async fn groups_across_hosts() {
+        let recording = r#"{"type": "value", "data": [["id", 1], [0, 0], [1, "bash"]]}
+{"type": "value", "data": [["id", 2], [0, 0], [1, "sshd"]]}
+{"type": "value", "data": [["id", 3], [0, 0], [1, "bash"]]}
+{"type": "map", "data": {"@q1_id": {"id": 3}}}
+"#;
+        let hosts = vec![("a".to_string(), replay(recording)), ("b".to_string(), replay(recording))];
+        let results = run("select host, comm, count(*) from kprobe.vfs_read group by host, comm", hosts).await;
+        assert_eq!(results.last(), Some(&QueryEvent::Done));
+        for host in ["a", "b"] {
+            assert!(results.contains(&status(host, HostState::Done, None)));
+        }
+
+        // groups can be sent more than once as they change, the last one counts
+        let mut groups = BTreeMap::new();
+        for event in &results {
+            if let QueryEvent::Row(row) = event {
+                groups.insert(row[0].as_u64().unwrap(), row[1..].to_vec());
+            }
+        }
+        let mut groups = groups.into_values().collect::<Vec<Vec<Value>>>();
+        groups.sort_by_key(|g| g.iter().map(|v| v.to_string()).collect::<Vec<String>>());
+        assert_eq!(
+            groups,
+            vec![
+                vec![json!("a"), json!("bash"), json!(2)],
+                vec![json!("a"), json!("sshd"), json!(1)],
+                vec![json!("b"), json!("bash"), json!(2)],
+                vec![json!("b"), json!("sshd"), json!(1)],
+            ]
+        );
+    }
+
+
+    // a host ssh can't get to
+    struct Unreachable;
+
+    impl Executor for Unreachable {
+        fn name(&self) -> String {
+            "down".to_string()
+        }
+
+        fn output(&self, _program: String, _args: Vec<String>) -> BoxFuture<'_, Result<CommandOutput, String>> {
+            async { Err("Error connecting to down".to_string()) }.boxed()
+        }
+
+        fn spawn(&self, _program: String, _args: Vec<String>) -> BoxFuture<'_, Result<Process, String>> {
+            async { Err("Error connecting to down".to_string()) }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn statuses_per_host() {
+        let recording = r#"{"type": "value", "data": [["id", 1], [0, "bash"]]}
+"#;
+        let hosts = vec![
+            ("up".to_string(), replay(recording)),
+            ("down".to_string(), Arc::new(Unreachable) as Arc<dyn Executor>),
+        ];
+        let results = run("select comm from kprobe.vfs_read", hosts).await;
+        let statuses = |host: &str| {
+            results
+                .iter()
+                .filter(|e| matches!(e, QueryEvent::Status(s) if s.host == host))
+                .cloned()
+                .collect::<Vec<QueryEvent>>()
+        };
+        assert_eq!(
+            statuses("down"),
+            vec![
+                status("down", HostState::Connecting, None),
+                status("down", HostState::Failed, Some("Error connecting to down")),
+            ]
+        );
+        // no maps at the end, so it didn't finish on its own
+        assert_eq!(
+            statuses("up"),
+            vec![
+                status("up", HostState::Connecting, None),
+                status("up", HostState::Running, None),
+                status("up", HostState::Failed, Some("bpftrace exited")),
+            ]
+        );
+        // the one that could carry on did, and only data came as rows
+        let rows = results.iter().filter(|e| matches!(e, QueryEvent::Row(_))).collect::<Vec<_>>();
+        assert_eq!(rows, vec![&QueryEvent::Row(vec![json!(1), json!("bash")])]);
+        assert_eq!(results.last(), Some(&QueryEvent::Done));
+    }
+
+    #[tokio::test]
+    async fn detects_kfunc() {
+        let host = |info: &str| FakeExecutor {
+            recording: String::new(),
+            outputs: HashMap::from([
+                ("test -e /sys/kernel/btf/vmlinux".to_string(), String::new()),
+                ("bpftrace --info".to_string(), info.to_string()),
+            ]),
+        };
+        let target = detect_target(&host("Attach:\n  kfunc: yes\n")).await.unwrap();
+        assert!(target.fentry && target.kfunc);
+        let target = detect_target(&host("Attach:\n  fentry: yes\n")).await.unwrap();
+        assert!(target.fentry && !target.kfunc);
+        let target = detect_target(&host("Attach:\n  kfunc: no\n")).await.unwrap();
+        assert!(!target.fentry);
+    }
+
+    #[tokio::test]
+    async fn bad_output_is_an_error_row() {
+        let recording = r#"{"type": "value", "data": [["id", 1], [0, "bash"]]}
+Attaching 1 probe...
+{"type": "value", "data": [["id", 2], [5, "sshd"]]}
+"#;
+        let results = run("select comm from kprobe.vfs_read", vec![("replay".to_string(), replay(recording))]).await;
+        assert_eq!(results.len(), 7, "{:?}", results);
+        assert_eq!(results[2], QueryEvent::Row(vec![json!(1), json!("bash")]));
+        // it carries on past lines it can't read
+        match &results[3] {
+            QueryEvent::Status(HostStatus { state: HostState::Running, message: Some(m), .. }) => {
+                assert!(m.starts_with("Unexpected output from bpftrace"), "{}", m)
+            }
+            e => panic!("expected a status, got {:?}", e),
+        }
+        assert_eq!(
+            results[4],
+            status("replay", HostState::Running, Some("bpftrace printed column 5, there are only 1"))
+        );
+        // it ended without printing its maps, so it didn't finish on its own
+        assert_eq!(results[5], status("replay", HostState::Failed, Some("bpftrace exited")));
+        assert_eq!(results[6], QueryEvent::Done);
+    }
+
+    #[tokio::test]
+    async fn hists_are_sent_on() {
+        let recording = r#"{"type": "hist", "data": {"@h": [{"min": 0, "max": 1, "count": 2}]}}
+"#;
+        let results = run("select comm from kprobe.vfs_read", vec![("replay".to_string(), replay(recording))]).await;
+        let bucket = Bucket { min: Some(0), max: Some(1), count: 2 };
+        let hists = BTreeMap::from([("@h".to_string(), Hist::Buckets(vec![bucket]))]);
+        assert_eq!(results[2], QueryEvent::Hist(HostHists { host: "replay".to_string(), hists }));
+        // a hist is one of the maps printed at the end
+        assert_eq!(results[3], status("replay", HostState::Done, None));
+    }
+}
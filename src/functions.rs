@@ -0,0 +1,188 @@
+// Every function a query can call: bpftrace's own builtins, sql functions
+// that get rewritten into bpftrace or worked out in userspace on the rows it
+// sends back, and the ones made with CREATE FUNCTION, kept in bpfquery.db
+// next to the user's views. Those are sql macros: every call is swapped for
+// the body with the arguments filled in before the query is compiled.
+use crate::aggregate::Aggregate;
+use crate::catalog;
+use crate::system;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FunctionKind {
+    /// A bpftrace builtin, called as it is.
+    Bpftrace,
+    /// A sql function that's written differently in bpftrace, e.g.
+    /// starts_with(s, 'p') is strncmp(s, "p", 1) == 0.
+    Rewritten,
+    /// Not something bpftrace can do, worked out on what it sends back so it
+    /// can only be a whole column.
+    Userspace,
+}
+
+#[derive(Debug, Clone)]
+pub struct BuiltinFunction {
+    pub name: &'static str,
+    pub args: &'static str,
+    pub kind: FunctionKind,
+    pub description: &'static str,
+}
+
+const fn builtin(
+    name: &'static str,
+    args: &'static str,
+    kind: FunctionKind,
+    description: &'static str,
+) -> BuiltinFunction {
+    BuiltinFunction {
+        name,
+        args,
+        kind,
+        description,
+    }
+}
+
+use FunctionKind::*;
+
+pub const BUILTIN_FUNCTIONS: &[BuiltinFunction] = &[
+    builtin("str", "ptr [, length]", Bpftrace, "The string a char * points at"),
+    builtin("buf", "ptr, length", Bpftrace, "Bytes at a pointer, printed as hex"),
+    builtin("ntop", "[family, ] address", Bpftrace, "An IPv4 or IPv6 address as text"),
+    builtin("pton", "address", Bpftrace, "Text IP address as bytes"),
+    builtin("macaddr", "ptr", Bpftrace, "A MAC address as text"),
+    builtin("ksym", "address", Bpftrace, "The kernel symbol at an address"),
+    builtin("usym", "address", Bpftrace, "The user symbol at an address"),
+    builtin("kaddr", "'symbol'", Bpftrace, "The address of a kernel symbol"),
+    builtin("uaddr", "'symbol'", Bpftrace, "The address of a user symbol"),
+    builtin("kptr", "ptr", Bpftrace, "Marks a pointer as kernel memory"),
+    builtin("uptr", "ptr", Bpftrace, "Marks a pointer as user memory"),
+    builtin("kstack", "[mode, ] [limit]", Bpftrace, "The kernel stack"),
+    builtin("ustack", "[mode, ] [limit]", Bpftrace, "The user stack"),
+    builtin("reg", "'name'", Bpftrace, "The value of a register"),
+    builtin("path", "struct path *", Bpftrace, "The full path of a struct path"),
+    builtin("cgroupid", "'path'", Bpftrace, "The id of a cgroup"),
+    builtin("cgroup_path", "id", Bpftrace, "The path of a cgroup"),
+    builtin("strftime", "'format', nsecs", Bpftrace, "A timestamp as text"),
+    builtin("strerror", "errno", Bpftrace, "The message for an errno"),
+    builtin("strncmp", "a, b, n", Bpftrace, "0 when the first n bytes are the same"),
+    builtin("strcontains", "haystack, needle", Bpftrace, "1 when needle is in haystack"),
+    builtin("sizeof", "type or expression", Bpftrace, "Size in bytes"),
+    builtin("offsetof", "struct, member", Bpftrace, "Offset of a member in bytes"),
+    builtin("bswap", "integer", Bpftrace, "Reverses the bytes"),
+    builtin("percpu_kaddr", "'symbol' [, cpu]", Bpftrace, "The address of a per cpu kernel variable"),
+    builtin("printf", "'format', ...", Bpftrace, "Prints formatted text"),
+    builtin("print", "value", Bpftrace, "Prints a value or map"),
+    builtin("time", "['format']", Bpftrace, "Prints the current time"),
+    builtin("join", "char *argv[] [, 'separator']", Bpftrace, "Prints an array of strings joined together"),
+    builtin("cat", "'path'", Bpftrace, "Prints a file"),
+    builtin("system", "'command'", Bpftrace, "Runs a command, needs --unsafe"),
+    builtin("signal", "'name' or number", Bpftrace, "Sends a signal to the current process, needs --unsafe"),
+    builtin("override", "retval", Bpftrace, "Changes what a kprobed function returns, needs --unsafe"),
+    builtin("exit", "", Bpftrace, "Stops bpftrace"),
+    builtin("unwatch", "address", Bpftrace, "Stops watching an address"),
+    builtin("skboutput", "'path', skb, length, offset", Bpftrace, "Writes a packet to a pcap file"),
+    builtin("debugf", "'format', ...", Bpftrace, "Prints to the kernel's trace pipe"),
+    builtin("hist", "n", Bpftrace, "A power of two histogram, assigned to a map"),
+    builtin("lhist", "n, min, max, step", Bpftrace, "A linear histogram, assigned to a map"),
+    builtin("stats", "n", Bpftrace, "Count, average and total, assigned to a map"),
+    builtin("has_key", "@map, key", Bpftrace, "Whether a map has a key"),
+    builtin("len", "@map or stack", Bpftrace, "How many elements a map or stack has"),
+    builtin("delete", "@map, key", Bpftrace, "Removes a key from a map"),
+    builtin("clear", "@map", Bpftrace, "Removes everything from a map"),
+    builtin("zero", "@map", Bpftrace, "Sets every value in a map to zero"),
+    builtin("starts_with", "s, 'prefix'", Rewritten, "Whether s starts with prefix, strncmp in bpftrace"),
+    builtin("contains", "s, 'needle'", Rewritten, "Whether needle is in s, strcontains in bpftrace"),
+    builtin("lower", "s", Userspace, "s in lower case"),
+    builtin("upper", "s", Userspace, "s in upper case"),
+    builtin("length", "s", Userspace, "How many characters are in s, strlen in bpftrace when it has it"),
+    builtin("substr", "s, start [, length]", Userspace, "Part of s, start counts from 1, substr in bpftrace when it has it"),
+    builtin("concat", "a, b, ...", Userspace, "Everything joined together as text"),
+    builtin("errno_name", "retval", Userspace, "The name of a negative errno, e.g. ENOENT"),
+    builtin("decode_flags", "flags, 'set'", Userspace, "The names of the bits set, see system.constants"),
+];
+
+pub fn builtin_function(name: &str) -> Option<&'static BuiltinFunction> {
+    BUILTIN_FUNCTIONS.iter().find(|f| f.name == name)
+}
+
+pub fn is_userspace(name: &str) -> bool {
+    builtin_function(name).is_some_and(|f| f.kind == Userspace)
+}
+
+// how many single character edits it takes to get from a to b
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<char>>();
+    let mut row = (0..=b.len()).collect::<Vec<usize>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substituted = previous + (ca != *cb) as usize;
+            previous = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The function that was probably meant, for typos.
+pub fn suggestion(name: &str) -> Option<String> {
+    let aggregates = ["count", "sum", "min", "max", "avg"];
+    BUILTIN_FUNCTIONS
+        .iter()
+        .map(|f| f.name.to_string())
+        .chain(aggregates.iter().map(|a| a.to_string()))
+        .chain(user_functions().into_iter().map(|f| f.name))
+        .map(|f| (edit_distance(name, &f), f))
+        .filter(|(d, _)| *d <= 2)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, f)| f)
+}
+
+/// A function made with CREATE FUNCTION, e.g. `kb(x) AS x / 1024`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserFunction {
+    pub name: String,
+    pub args: Vec<String>,
+    /// The sql expression it stands for.
+    pub body: String,
+}
+
+pub fn user_functions() -> Vec<UserFunction> {
+    catalog::user_functions()
+        .into_iter()
+        .map(|(name, args, body)| UserFunction { name, args, body })
+        .collect()
+}
+
+/// Whether a function called name can be made, built-in ones can't be
+/// redefined.
+pub fn check_new_function(name: &str, or_replace: bool) -> Result<(), String> {
+    if builtin_function(name).is_some() || Aggregate::from_name(name).is_some() {
+        return Err(format!("{} is a built-in function", name));
+    }
+    if !or_replace && user_functions().iter().any(|f| f.name == name) {
+        return Err(format!(
+            "Function {} already exists, use CREATE OR REPLACE FUNCTION to change it",
+            name
+        ));
+    }
+    Ok(())
+}
+
+pub fn create_function(f: &UserFunction, or_replace: bool) -> Result<String, String> {
+    check_new_function(&f.name, or_replace)?;
+    catalog::save_user_function(&f.name, &f.args, &f.body, or_replace)?;
+    system::reset();
+    Ok(format!("Created function {}", f.name))
+}
+
+pub fn drop_function(name: &str, if_exists: bool) -> Result<String, String> {
+    match catalog::delete_user_function(name)? {
+        true => {
+            system::reset();
+            Ok(format!("Dropped function {}", name))
+        }
+        false if if_exists => Ok(format!("There was no function {}", name)),
+        false => Err(format!("Unknown function {}", name)),
+    }
+}
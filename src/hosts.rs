@@ -0,0 +1,431 @@
+// How to reach the hosts bpftrace runs on. Most of it is left to ssh itself,
+// which reads ~/.ssh/config for Host aliases, HostName, Port, User,
+// IdentityFile and ProxyJump, anything set here is on top of that.
+//
+// Hosts and groups of them can be named in bpfquery.toml, e.g.
+//
+//   [hosts.db1]
+//   destination = "10.0.0.5"
+//   user = "ops"
+//   labels = { role = "db" }
+//
+//   [groups.db]
+//   hosts = ["db1", "db2"]
+//
+// and a query picks where it runs with `USE db; SELECT ...`, otherwise it
+// runs on the host given on the command line.
+use crate::bpftrace_compiler::Target;
+use crate::executor::{detect_target, list_probes, sweep_orphans, Executor, LocalExecutor, SshExecutor};
+use crate::system;
+use clap::ValueEnum;
+use openssh::{KnownHosts, SessionBuilder};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// What to do with a host key that isn't in known_hosts.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostKeyCheck {
+    /// Refuse to connect.
+    #[default]
+    Strict,
+    /// Add it to known_hosts, but still refuse ones that changed.
+    Add,
+    /// Connect to anything, for throwaway machines only.
+    Accept,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HostConfig {
+    /// What ssh connects to, the name the host goes by otherwise, e.g. an
+    /// address for a Host alias.
+    pub destination: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub identity: Option<String>,
+    /// Hosts to go through first, like ProxyJump.
+    pub jump_hosts: Vec<String>,
+    /// An ssh config to use instead of ~/.ssh/config.
+    pub ssh_config: Option<String>,
+    pub host_key_check: HostKeyCheck,
+    /// Whether to run bpftrace with `sudo -n`, None works it out from
+    /// whether the user is root.
+    pub sudo: Option<bool>,
+    /// Anything worth knowing about the host, e.g. role = "db", shown in
+    /// system.hosts.
+    pub labels: BTreeMap<String, String>,
+}
+
+impl HostConfig {
+    /// The demo at bpfquery.com, a root login on BPFTRACE_MACHINE with the
+    /// key that ships in the container.
+    pub fn demo_machine() -> Result<HostConfig, String> {
+        let destination =
+            std::env::var("BPFTRACE_MACHINE").map_err(|e| format!("BPFTRACE_MACHINE: {}", e))?;
+        Ok(HostConfig {
+            destination: Some(destination),
+            user: Some("root".to_string()),
+            identity: Some("/app/bpftrace_machine".to_string()),
+            host_key_check: HostKeyCheck::Accept,
+            ..Default::default()
+        })
+    }
+
+    pub fn session_builder(&self) -> SessionBuilder {
+        let mut s = SessionBuilder::default();
+        if let Some(port) = self.port {
+            s.port(port);
+        }
+        if let Some(user) = &self.user {
+            s.user(user.clone());
+        }
+        if let Some(identity) = &self.identity {
+            s.keyfile(identity);
+        }
+        if !self.jump_hosts.is_empty() {
+            s.jump_hosts(&self.jump_hosts);
+        }
+        if let Some(config) = &self.ssh_config {
+            s.config_file(config);
+        }
+        s.known_hosts_check(match self.host_key_check {
+            HostKeyCheck::Strict => KnownHosts::Strict,
+            HostKeyCheck::Add => KnownHosts::Add,
+            HostKeyCheck::Accept => KnownHosts::Accept,
+        });
+        s
+    }
+}
+
+/// The command to run for program, with `sudo -n` in front if it's needed.
+/// -n fails rather than waiting on a password prompt nobody can see.
+pub fn with_sudo(sudo: bool, program: String, args: Vec<String>) -> (String, Vec<String>) {
+    if !sudo {
+        return (program, args);
+    }
+    let mut sudo_args = vec!["-n".to_string(), program];
+    sudo_args.extend(args);
+    ("sudo".to_string(), sudo_args)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Group {
+    pub hosts: Vec<String>,
+}
+
+/// What's in bpfquery.toml.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Inventory {
+    pub hosts: BTreeMap<String, HostConfig>,
+    pub groups: BTreeMap<String, Group>,
+}
+
+pub const INVENTORY: &str = "bpfquery.toml";
+
+impl Inventory {
+    /// Reads path, a missing bpfquery.toml is an empty inventory but any
+    /// other missing file is an error.
+    pub fn load(path: Option<&str>) -> Result<Inventory, String> {
+        let text = match (path, std::fs::read_to_string(path.unwrap_or(INVENTORY))) {
+            (_, Ok(text)) => text,
+            (None, Err(e)) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Inventory::default()),
+            (p, Err(e)) => return Err(format!("{}: {}", p.unwrap_or(INVENTORY), e)),
+        };
+        let inventory: Inventory =
+            toml::from_str(&text).map_err(|e| format!("{}: {}", path.unwrap_or(INVENTORY), e))?;
+        for (name, group) in &inventory.groups {
+            if inventory.hosts.contains_key(name) {
+                return Err(format!("{} is both a host and a group", name));
+            }
+            if group.hosts.is_empty() {
+                return Err(format!("Group {} has no hosts", name));
+            }
+        }
+        Ok(inventory)
+    }
+}
+
+/// Every host queries can run on and how to run things there. Connections
+/// are made the first time a host is used and shared by everyone after.
+pub struct Fleet {
+    pub inventory: Inventory,
+    /// Where queries run when they don't USE somewhere else.
+    pub default_target: String,
+    // for hosts that aren't in the inventory, from the command line
+    defaults: HostConfig,
+    local_sudo: bool,
+    // every host gets this instead when there is one, e.g. a recording
+    replay: Option<Arc<dyn Executor>>,
+    executors: Mutex<HashMap<String, Arc<dyn Executor>>>,
+    // one per host so working out a slow host's doesn't hold up the rest
+    targets: Mutex<HashMap<String, Arc<tokio::sync::Mutex<Option<Detected>>>>>,
+}
+
+// a host that couldn't be reached is asked again after this, not on every query
+const RETRY_DETECT: Duration = Duration::from_secs(30);
+
+enum Detected {
+    Target(Target),
+    Failed(String, Instant),
+}
+
+impl Fleet {
+    pub fn new(
+        inventory: Inventory,
+        default_target: String,
+        defaults: HostConfig,
+        replay: Option<Arc<dyn Executor>>,
+    ) -> Fleet {
+        let local_sudo = defaults.sudo.unwrap_or(whoami::username() != "root");
+        Fleet {
+            inventory,
+            default_target,
+            defaults,
+            local_sudo,
+            replay,
+            executors: Default::default(),
+            targets: Default::default(),
+        }
+    }
+
+    /// The hosts a USE names, or the default ones.
+    pub fn resolve(&self, name: Option<&str>) -> Result<Vec<String>, String> {
+        let name = name.unwrap_or(&self.default_target);
+        if let Some(group) = self.inventory.groups.get(name) {
+            return Ok(group.hosts.clone());
+        }
+        // the default is whatever was asked for on the command line, anything
+        // else has to be written down so half typed names don't get connected to
+        if self.inventory.hosts.contains_key(name) || name == self.default_target {
+            return Ok(vec![name.to_string()]);
+        }
+        Err(format!("Unknown host or group {}, see {}", name, INVENTORY))
+    }
+
+    fn config(&self, host: &str) -> Result<HostConfig, String> {
+        match self.inventory.hosts.get(host) {
+            Some(config) => Ok(config.clone()),
+            None if host == "bpftrace_machine" => HostConfig::demo_machine(),
+            None => Ok(self.defaults.clone()),
+        }
+    }
+
+    pub fn executor(&self, host: &str) -> Result<Arc<dyn Executor>, String> {
+        if let Some(replay) = &self.replay {
+            return Ok(replay.clone());
+        }
+        let mut executors = self.executors.lock().unwrap();
+        if let Some(executor) = executors.get(host) {
+            return Ok(executor.clone());
+        }
+        let executor: Arc<dyn Executor> = if host == "localhost" {
+            Arc::new(LocalExecutor { sudo: self.local_sudo })
+        } else {
+            Arc::new(SshExecutor::new(host.to_string(), self.config(host)?))
+        };
+        executors.insert(host.to_string(), executor.clone());
+        Ok(executor)
+    }
+
+    /// What the host can run, worked out the first time it's reached and kept
+    /// after. That's also when bpftrace left behind by an earlier bpfquery
+    /// gets cleaned up, before anything of ours is running there. Failing to
+    /// reach it is kept for a while too.
+    pub async fn target(&self, host: &str) -> Result<Target, String> {
+        let cell = self.targets.lock().unwrap().entry(host.to_string()).or_default().clone();
+        let mut detected = cell.lock().await;
+        match &*detected {
+            Some(Detected::Target(target)) => return Ok(*target),
+            Some(Detected::Failed(e, at)) if at.elapsed() < RETRY_DETECT => return Err(e.clone()),
+            _ => {}
+        }
+        let result: Result<Target, String> = async {
+            let executor = self.executor(host)?;
+            let target = detect_target(executor.as_ref()).await?;
+            sweep_orphans(executor.as_ref()).await;
+            Ok(target)
+        }
+        .await;
+        *detected = Some(match &result {
+            Ok(target) => Detected::Target(*target),
+            Err(e) => Detected::Failed(e.clone(), Instant::now()),
+        });
+        result
+    }
+
+    /// What every one of the hosts can run, so one program works on all of
+    /// them. Hosts that can't be reached are left out, they say so again when
+    /// the query runs.
+    pub async fn common_target(&self, hosts: &[String]) -> Result<Target, String> {
+        let mut common: Option<Target> = None;
+        let mut error = None;
+        for host in hosts {
+            match self.target(host).await {
+                Ok(target) => common = Some(common.map_or(target, |c| c.common(target))),
+                Err(e) if hosts.len() > 1 => error = Some(format!("{}: {}", host, e)),
+                Err(e) => error = Some(e),
+            }
+        }
+        common.ok_or_else(|| error.unwrap_or_else(|| "No hosts to run on".to_string()))
+    }
+
+    /// Fills in system.probes from the default host and system.hosts from
+    /// the inventory.
+    pub async fn load_system_tables(&self) {
+        let mut rows = vec![];
+        for (name, config) in &self.inventory.hosts {
+            let groups = self
+                .inventory
+                .groups
+                .iter()
+                .filter(|(_, g)| g.hosts.contains(name))
+                .map(|(group, _)| group.clone())
+                .collect::<Vec<String>>();
+            let labels = serde_json::to_string(&config.labels).unwrap_or_default();
+            rows.push((name.clone(), config.destination.clone(), groups.join(", "), labels));
+        }
+        system::set_hosts(rows);
+        if let Some(host) = self.resolve(None).ok().and_then(|h| h.into_iter().next()) {
+            if let Ok(executor) = self.executor(&host) {
+                system::set_host_probes(list_probes(executor.as_ref()).await);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::{CommandOutput, Process};
+    use futures_util::future::{BoxFuture, FutureExt};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn sudo_goes_in_front() {
+        let args = vec!["-l".to_string()];
+        assert_eq!(with_sudo(false, "bpftrace".to_string(), args.clone()), ("bpftrace".to_string(), args.clone()));
+        assert_eq!(
+            with_sudo(true, "bpftrace".to_string(), args),
+            ("sudo".to_string(), vec!["-n".to_string(), "bpftrace".to_string(), "-l".to_string()])
+        );
+    }
+
+    #[test]
+    fn host_config() {
+        let config: HostConfig = toml::from_str(
+            r#"
+            destination = "10.0.0.5"
+            port = 2222
+            user = "ops"
+            identity = "~/.ssh/ops"
+            jump_hosts = ["bastion", "ops@bastion2:2200"]
+            host_key_check = "add"
+            sudo = false
+            labels = { role = "db" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.destination.as_deref(), Some("10.0.0.5"));
+        assert_eq!(config.port, Some(2222));
+        assert_eq!(config.user.as_deref(), Some("ops"));
+        assert_eq!(config.identity.as_deref(), Some("~/.ssh/ops"));
+        assert_eq!(config.jump_hosts, vec!["bastion", "ops@bastion2:2200"]);
+        assert_eq!(config.host_key_check, HostKeyCheck::Add);
+        assert_eq!(config.sudo, Some(false));
+        assert_eq!(config.labels.get("role").map(|r| r.as_str()), Some("db"));
+
+        // everything is optional, ssh fills in the rest from its own config
+        let config: HostConfig = toml::from_str("").unwrap();
+        assert_eq!(config.host_key_check, HostKeyCheck::Strict);
+        assert_eq!(config.sudo, None);
+        assert!(toml::from_str::<HostConfig>("hostname = \"db1\"").is_err());
+        assert!(toml::from_str::<HostConfig>("host_key_check = \"sometimes\"").is_err());
+    }
+
+    // an inventory written out to a file of its own, as if it was bpfquery.toml
+    fn load(name: &str, toml: &str) -> Result<Inventory, String> {
+        let path = std::env::temp_dir().join(format!("bpfquery-{}-{}.toml", std::process::id(), name));
+        std::fs::write(&path, toml).unwrap();
+        let inventory = Inventory::load(path.to_str());
+        let _ = std::fs::remove_file(&path);
+        inventory
+    }
+
+    #[test]
+    fn loads_inventories() {
+        let inventory = load(
+            "inventory",
+            r#"
+            [hosts.db1]
+            destination = "10.0.0.5"
+            [hosts.db2]
+            labels = { role = "db" }
+            [groups.db]
+            hosts = ["db1", "db2"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(inventory.hosts.keys().collect::<Vec<_>>(), vec!["db1", "db2"]);
+        assert_eq!(inventory.hosts["db1"].destination.as_deref(), Some("10.0.0.5"));
+        assert_eq!(inventory.groups["db"].hosts, vec!["db1", "db2"]);
+
+        let both = "[hosts.db]\n[groups.db]\nhosts = [\"db1\"]\n";
+        assert_eq!(load("both", both).err().unwrap(), "db is both a host and a group");
+        assert_eq!(load("empty", "[groups.db]\nhosts = []\n").err().unwrap(), "Group db has no hosts");
+        assert!(load("twice", "[hosts.db1]\n[hosts.db1]\n").is_err());
+        assert!(load("typo", "[host.db1]\n").is_err());
+        assert!(Inventory::load(Some("/nonexistent/bpfquery.toml")).err().unwrap().starts_with("/nonexistent/bpfquery.toml: "));
+    }
+
+    #[test]
+    fn resolves_hosts() {
+        let inventory = load("resolve", "[hosts.db1]\n[hosts.db2]\n[groups.db]\nhosts = [\"db1\", \"db2\"]\n").unwrap();
+        let fleet = Fleet::new(inventory, "web1".to_string(), HostConfig::default(), None);
+        assert_eq!(fleet.resolve(None), Ok(vec!["web1".to_string()]));
+        assert_eq!(fleet.resolve(Some("web1")), Ok(vec!["web1".to_string()]));
+        assert_eq!(fleet.resolve(Some("db1")), Ok(vec!["db1".to_string()]));
+        assert_eq!(fleet.resolve(Some("db")), Ok(vec!["db1".to_string(), "db2".to_string()]));
+        assert_eq!(fleet.resolve(Some("db3")), Err("Unknown host or group db3, see bpfquery.toml".to_string()));
+    }
+
+    // a host that can't be reached, counting how often it was tried
+    struct Down(AtomicUsize);
+
+    impl Executor for Down {
+        fn name(&self) -> String {
+            "down".to_string()
+        }
+
+        fn output(&self, _program: String, _args: Vec<String>) -> BoxFuture<'_, Result<CommandOutput, String>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            async { Err("Error connecting to down".to_string()) }.boxed()
+        }
+
+        fn spawn(&self, _program: String, _args: Vec<String>) -> BoxFuture<'_, Result<Process, String>> {
+            async { Err("Error connecting to down".to_string()) }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn failures_are_kept_for_a_while() {
+        let fleet = Fleet::new(Inventory::default(), "down".to_string(), HostConfig::default(), None);
+        let down = Arc::new(Down(AtomicUsize::new(0)));
+        fleet.executors.lock().unwrap().insert("down".to_string(), down.clone());
+        assert_eq!(fleet.target("down").await.unwrap_err(), "Error connecting to down");
+        assert_eq!(fleet.target("down").await.unwrap_err(), "Error connecting to down");
+        assert_eq!(down.0.load(Ordering::SeqCst), 1);
+
+        // once it's old it's tried again
+        let cell = fleet.targets.lock().unwrap()["down"].clone();
+        if let Some(Detected::Failed(_, at)) = cell.lock().await.as_mut() {
+            *at -= RETRY_DETECT;
+        }
+        assert!(fleet.target("down").await.is_err());
+        assert_eq!(down.0.load(Ordering::SeqCst), 2);
+    }
+}
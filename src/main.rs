@@ -1,10 +1,25 @@
+mod aggregate;
 mod bpftrace_compiler;
+mod bpftrace_output;
+mod catalog;
+mod constants;
+mod elf;
 mod executor;
+mod functions;
+mod hosts;
 mod parser;
+mod probe;
+mod system;
+mod transform;
+mod views;
+mod vmlinux;
 mod web;
 
+use executor::{Executor, FakeExecutor};
+use hosts::{Fleet, HostConfig, HostKeyCheck, Inventory};
 use web::start_server;
 use dotenv::dotenv;
+use std::sync::Arc;
 
 use clap::Parser;
 
@@ -17,9 +32,53 @@ use clap::Parser;
     about = "An experiment with sql and bpf."
 )]
 struct Args {
-    hostname: String,
+    /// The host to trace over ssh, or a host or group from bpfquery.toml.
+    /// Queries run here unless they start with `USE <host or group>;`.
+    hostname: Option<String>,
+    /// The hosts and groups queries can USE, bpfquery.toml by default.
+    #[arg(long, value_name = "FILE")]
+    inventory: Option<String>,
     #[arg(short, long)]
     demo: bool,
+    /// Run bpftrace on this machine instead of over ssh, same as giving
+    /// localhost as the hostname.
+    #[arg(long)]
+    local: bool,
+    /// Always run bpftrace with sudo -n.
+    #[arg(long, conflicts_with = "no_sudo")]
+    sudo: bool,
+    /// Never use sudo, it's used when bpftrace would run as a user that
+    /// isn't root.
+    #[arg(long)]
+    no_sudo: bool,
+    /// The ssh port, if it isn't the one in ~/.ssh/config or 22.
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// Who to log in as over ssh.
+    #[arg(short, long)]
+    user: Option<String>,
+    /// The private key to log in with over ssh.
+    #[arg(short, long, value_name = "FILE")]
+    identity: Option<String>,
+    /// Hosts to ssh through first, like ProxyJump, can be given more than
+    /// once.
+    #[arg(short = 'J', long = "jump", value_name = "HOST")]
+    jump_hosts: Vec<String>,
+    /// An ssh config to use instead of ~/.ssh/config.
+    #[arg(long, value_name = "FILE")]
+    ssh_config: Option<String>,
+    /// What to do with host keys that aren't in known_hosts.
+    #[arg(long, value_enum, default_value_t = HostKeyCheck::Strict)]
+    host_key_check: HostKeyCheck,
+    /// Let anyone who can reach the web server list the functions and USDT
+    /// probes of any binary on this machine, for autocompletion. Otherwise
+    /// only binaries a query has used are listed.
+    #[arg(long)]
+    browse_binaries: bool,
+    /// Replay a file of recorded `bpftrace -f json` output for every query
+    /// instead of running bpftrace.
+    #[arg(long, value_name = "FILE")]
+    replay: Option<String>,
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
@@ -27,6 +86,52 @@ async fn main() -> std::io::Result<()> {
     let args = Args::parse();
     dotenv().ok();
 
-    start_server(args.hostname, args.demo).await;
+    let sudo = match (args.sudo, args.no_sudo) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    };
+    let config = HostConfig {
+        port: args.port,
+        user: args.user,
+        identity: args.identity,
+        jump_hosts: args.jump_hosts,
+        ssh_config: args.ssh_config,
+        host_key_check: args.host_key_check,
+        sudo,
+        ..Default::default()
+    };
+    let inventory = match Inventory::load(args.inventory.as_deref()) {
+        Ok(inventory) => inventory,
+        Err(e) => {
+            println!("Error reading inventory {}", e);
+            std::process::exit(1);
+        }
+    };
+    let replay: Option<Arc<dyn Executor>> = match &args.replay {
+        Some(path) => match FakeExecutor::from_file(path) {
+            Ok(e) => Some(Arc::new(e)),
+            Err(e) => {
+                println!("Error reading recording {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let default_target = match (&replay, args.local, args.hostname) {
+        (Some(_), _, _) => "replay".to_string(),
+        (None, true, _) => "localhost".to_string(),
+        (None, false, Some(hostname)) => hostname,
+        (None, false, None) => {
+            println!("Expected a hostname, --local or --replay <FILE>");
+            std::process::exit(1);
+        }
+    };
+    for missing in catalog::missing_kernel_definitions() {
+        println!("{}", missing);
+    }
+    let fleet = Fleet::new(inventory, default_target, config, replay);
+
+    start_server(Arc::new(fleet), args.demo, args.browse_binaries).await;
     return Ok(());
 }
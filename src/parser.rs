@@ -1,8 +1,238 @@
-use sqlparser::parser::Parser;
+use sqlparser::ast::Statement;
 use sqlparser::dialect::GenericDialect;
+use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::{Token, Tokenizer};
 
-pub fn parse_bpfquery_sql(sql: &str) -> Result<Vec<sqlparser::ast::Statement>, sqlparser::parser::ParserError> {
-    //eventually this will probably be more complicated 
-    let dialect = GenericDialect {};  
-    Parser::parse_sql(&dialect, sql)
+// SHOW PROBES [LIKE 'pattern'] and SHOW STRUCT <name> aren't sql that sqlparser
+// knows about (it drops the LIKE pattern), so they get rewritten into queries
+// against the system tables before parsing.
+fn rewrite_show(sql: &str) -> Result<Option<String>, ParserError> {
+    let dialect = GenericDialect {};
+    let tokens = Tokenizer::new(&dialect, sql)
+        .with_unescape(false)
+        .tokenize()
+        .map_err(|e| ParserError::TokenizerError(e.to_string()))?;
+    let tokens = tokens
+        .into_iter()
+        .filter(|t| !matches!(t, Token::Whitespace(_) | Token::SemiColon))
+        .collect::<Vec<Token>>();
+
+    let word = |i: usize| match tokens.get(i) {
+        Some(Token::Word(w)) => Some(w.value.to_lowercase()),
+        _ => None,
+    };
+    if word(0).as_deref() != Some("show") {
+        return Ok(None);
+    }
+
+    match word(1).as_deref() {
+        Some("probes") => {
+            let filter = match (word(2).as_deref(), tokens.get(3)) {
+                (None, None) => String::new(),
+                (Some("like"), Some(Token::SingleQuotedString(pattern))) => {
+                    let pattern = pattern.replace('\'', "''");
+                    format!(" WHERE name LIKE '{0}' OR probe LIKE '{0}'", pattern)
+                }
+                _ => {
+                    return Err(ParserError::ParserError(
+                        "Expected SHOW PROBES [LIKE 'pattern']".to_string(),
+                    ))
+                }
+            };
+            Ok(Some(format!(
+                "SELECT probe, type, name FROM system.probes{} ORDER BY probe",
+                filter
+            )))
+        }
+        Some(kind @ ("struct" | "union")) => {
+            let name = match tokens.get(2) {
+                Some(Token::Word(w)) => w.value.clone(),
+                Some(Token::SingleQuotedString(s)) => s.clone(),
+                _ => {
+                    return Err(ParserError::ParserError(format!(
+                        "Expected SHOW {} <name>",
+                        kind.to_uppercase()
+                    )))
+                }
+            };
+            // unions are stored with their keyword since they share a namespace
+            let name = match kind {
+                "union" => format!("union {}", name),
+                _ => name,
+            };
+            Ok(Some(format!(
+                "SELECT member, c_type, sql_type FROM system.structs WHERE name = '{}' ORDER BY position",
+                name.replace('\'', "''")
+            )))
+        }
+        _ => Ok(None),
+    }
+}
+
+// CREATE FUNCTION kb(x) AS x / 1024 is what sqlparser calls a macro, it
+// wants types and a quoted body for functions.
+fn rewrite_create_function(sql: &str) -> Result<Option<String>, ParserError> {
+    let dialect = GenericDialect {};
+    // left escaped so the tokens join back into the same sql
+    let mut tokens = Tokenizer::new(&dialect, sql)
+        .with_unescape(false)
+        .tokenize()
+        .map_err(|e| ParserError::TokenizerError(e.to_string()))?;
+    let words = tokens
+        .iter()
+        .enumerate()
+        .filter(|(_, t)| !matches!(t, Token::Whitespace(_)))
+        .map(|(i, t)| match t {
+            Token::Word(w) => (i, w.value.to_lowercase()),
+            _ => (i, String::new()),
+        })
+        .take(4)
+        .collect::<Vec<(usize, String)>>();
+    let function = match words.as_slice() {
+        [(_, create), (i, function), ..] if create == "create" && function == "function" => *i,
+        [(_, create), (_, or), (_, replace), (i, function)]
+            if create == "create" && or == "or" && replace == "replace" && function == "function" =>
+        {
+            *i
+        }
+        _ => return Ok(None),
+    };
+    tokens[function] = Token::make_keyword("MACRO");
+    Ok(Some(tokens.iter().map(|t| t.to_string()).collect()))
+}
+
+// The tokenizer reads the .99 in profile.hz.99 as a number, so it gets quoted
+// into profile.hz."99" to keep it part of the name.
+fn quote_trailing_numbers(sql: &str) -> Result<Option<String>, ParserError> {
+    let dialect = GenericDialect {};
+    let tokens = Tokenizer::new(&dialect, sql)
+        .with_unescape(false)
+        .tokenize()
+        .map_err(|e| ParserError::TokenizerError(e.to_string()))?;
+    let mut rewritten = false;
+    let mut out = String::new();
+    for (i, t) in tokens.iter().enumerate() {
+        match (i.checked_sub(1).map(|p| &tokens[p]), t) {
+            (Some(Token::Word(_)), Token::Number(n, _)) if n.starts_with('.') && n.len() > 1 => {
+                out.push_str(&format!(".\"{}\"", &n[1..]));
+                rewritten = true;
+            }
+            _ => out.push_str(&t.to_string()),
+        }
+    }
+    Ok(rewritten.then_some(out))
+}
+
+pub fn parse_bpfquery_sql(
+    sql: &str,
+) -> Result<Vec<sqlparser::ast::Statement>, sqlparser::parser::ParserError> {
+    //eventually this will probably be more complicated
+    let dialect = GenericDialect {};
+    if let Some(rewritten) = rewrite_show(sql)? {
+        return Parser::parse_sql(&dialect, &rewritten);
+    }
+    let quoted = quote_trailing_numbers(sql)?;
+    let sql = quoted.as_deref().unwrap_or(sql);
+    match rewrite_create_function(sql)? {
+        Some(rewritten) => Parser::parse_sql(&dialect, &rewritten),
+        None => Parser::parse_sql(&dialect, sql),
+    }
+}
+
+/// Takes a `USE <host or group>;` off the front of a query.
+pub fn split_use(mut ast: Vec<Statement>) -> Result<(Option<String>, Vec<Statement>), String> {
+    match ast.first() {
+        Some(Statement::Use { db_name }) => {
+            let name = db_name.value.clone();
+            ast.remove(0);
+            if ast.is_empty() {
+                return Err(format!("Expected a query after USE {}", name));
+            }
+            Ok((Some(name), ast))
+        }
+        _ => Ok((None, ast)),
+    }
+}
+
+/// Parses a single expression, e.g. the body of a function.
+pub fn parse_bpfquery_expr(sql: &str) -> Result<sqlparser::ast::Expr, ParserError> {
+    let dialect = GenericDialect {};
+    Parser::new(&dialect).try_with_sql(sql)?.parse_expr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(sql: &str) -> String {
+        parse_bpfquery_sql(sql).unwrap()[0].to_string()
+    }
+
+    #[test]
+    fn show_probes() {
+        assert_eq!(parsed("SHOW PROBES"), "SELECT probe, type, name FROM system.probes ORDER BY probe");
+        assert_eq!(
+            parsed("show probes like 'vfs_%';"),
+            "SELECT probe, type, name FROM system.probes WHERE name LIKE 'vfs_%' OR probe LIKE 'vfs_%' ORDER BY probe"
+        );
+        assert!(parse_bpfquery_sql("SHOW PROBES vfs_read").is_err());
+    }
+
+    #[test]
+    fn show_structs() {
+        assert_eq!(
+            parsed("SHOW STRUCT task_struct"),
+            "SELECT member, c_type, sql_type FROM system.structs WHERE name = 'task_struct' ORDER BY position"
+        );
+        assert_eq!(
+            parsed("show union 'bpf_attr'"),
+            "SELECT member, c_type, sql_type FROM system.structs WHERE name = 'union bpf_attr' ORDER BY position"
+        );
+        assert!(parse_bpfquery_sql("SHOW STRUCT").is_err());
+    }
+
+    #[test]
+    fn create_function() {
+        let ast = parse_bpfquery_sql("CREATE FUNCTION kb(x) AS x / 1024").unwrap();
+        assert!(matches!(ast[0], Statement::CreateMacro { or_replace: false, .. }));
+        let ast = parse_bpfquery_sql("create or replace function kb(x) as x / 1024").unwrap();
+        assert!(matches!(ast[0], Statement::CreateMacro { or_replace: true, .. }));
+        // only the keyword after CREATE is rewritten
+        assert!(matches!(
+            parse_bpfquery_sql("CREATE VIEW function AS SELECT 1").unwrap()[0],
+            Statement::CreateView { .. }
+        ));
+        // strings come through the rewrite still escaped
+        assert_eq!(
+            parsed("CREATE FUNCTION f(x) AS concat(x, 'it''s', '', \"a\"\"b\")"),
+            "CREATE MACRO f(x) AS concat(x, 'it''s', '', \"a\"\"b\")"
+        );
+        assert_eq!(
+            parsed("SELECT 'it''s', '' FROM profile.hz.99"),
+            "SELECT 'it''s', '' FROM profile.hz.\"99\""
+        );
+    }
+
+    #[test]
+    fn expressions() {
+        assert_eq!(parse_bpfquery_expr("x / 1024").unwrap().to_string(), "x / 1024");
+        assert!(parse_bpfquery_expr("x /").is_err());
+    }
+
+    #[test]
+    fn trailing_numbers() {
+        assert_eq!(parsed("SELECT pid FROM profile.hz.99"), "SELECT pid FROM profile.hz.\"99\"");
+        assert_eq!(parsed("SELECT 1.5"), "SELECT 1.5");
+    }
+
+    #[test]
+    fn use_host() {
+        let (name, ast) = split_use(parse_bpfquery_sql("USE db; SELECT pid FROM kprobe.vfs_read").unwrap()).unwrap();
+        assert_eq!(name.as_deref(), Some("db"));
+        assert_eq!(ast.len(), 1);
+        let (name, ast) = split_use(parse_bpfquery_sql("SELECT pid FROM kprobe.vfs_read").unwrap()).unwrap();
+        assert_eq!(name, None);
+        assert_eq!(ast.len(), 1);
+        assert!(split_use(parse_bpfquery_sql("USE db").unwrap()).is_err());
+    }
 }
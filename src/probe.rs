@@ -0,0 +1,213 @@
+use sqlparser::ast::ObjectName;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbeType {
+    Begin,
+    End,
+    Kprobe,
+    Kretprobe,
+    Fentry,
+    Fexit,
+    Uprobe,
+    Uretprobe,
+    Usdt,
+    Tracepoint,
+    Interval,
+    Profile,
+    /// Not a bpftrace probe, a syscall's entry and exit joined on the thread.
+    Syscalls,
+    Other(String),
+}
+
+impl ProbeType {
+    fn from_name(name: &str) -> ProbeType {
+        match name {
+            "BEGIN" => ProbeType::Begin,
+            "END" => ProbeType::End,
+            "kprobe" => ProbeType::Kprobe,
+            "kretprobe" => ProbeType::Kretprobe,
+            // kfunc and kretfunc are the old names for fentry and fexit
+            "fentry" | "kfunc" => ProbeType::Fentry,
+            "fexit" | "kretfunc" => ProbeType::Fexit,
+            "uprobe" => ProbeType::Uprobe,
+            "uretprobe" => ProbeType::Uretprobe,
+            "usdt" => ProbeType::Usdt,
+            "tracepoint" => ProbeType::Tracepoint,
+            "interval" => ProbeType::Interval,
+            "profile" => ProbeType::Profile,
+            "syscalls" => ProbeType::Syscalls,
+            other => ProbeType::Other(other.to_string()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            ProbeType::Begin => "BEGIN",
+            ProbeType::End => "END",
+            ProbeType::Kprobe => "kprobe",
+            ProbeType::Kretprobe => "kretprobe",
+            ProbeType::Fentry => "fentry",
+            ProbeType::Fexit => "fexit",
+            ProbeType::Uprobe => "uprobe",
+            ProbeType::Uretprobe => "uretprobe",
+            ProbeType::Usdt => "usdt",
+            ProbeType::Tracepoint => "tracepoint",
+            ProbeType::Interval => "interval",
+            ProbeType::Profile => "profile",
+            ProbeType::Syscalls => "syscalls",
+            ProbeType::Other(s) => s,
+        }
+    }
+}
+
+/// A bpftrace probe that a query reads from, e.g. `kprobe.vfs_open` in sql
+/// becomes `kprobe:vfs_open` in bpftrace.
+#[derive(Debug, Clone)]
+pub struct Probe {
+    pub probe_type: ProbeType,
+    // everything after the probe type, e.g. ["syscalls", "sys_enter_openat"]
+    pub parts: Vec<String>,
+}
+
+impl Probe {
+    pub fn begin() -> Probe {
+        Probe {
+            probe_type: ProbeType::Begin,
+            parts: vec![],
+        }
+    }
+
+    pub fn from_object_name(name: &ObjectName) -> Probe {
+        // STAR is how you write a * in a table name, sys_enter_STAR -> sys_enter_*
+        let idents = name
+            .0
+            .iter()
+            .map(|i| i.value.replace("STAR", "*"))
+            .collect::<Vec<String>>();
+        Probe {
+            probe_type: ProbeType::from_name(&idents[0]),
+            parts: idents[1..].to_vec(),
+        }
+    }
+
+    /// The kernel function for kprobes, kretprobes, fentry and fexit probes,
+    /// the last part of the name.
+    pub fn function_name(&self) -> Option<&str> {
+        match self.probe_type {
+            ProbeType::Kprobe | ProbeType::Kretprobe | ProbeType::Fentry | ProbeType::Fexit => {
+                self.parts.last().map(|s| s.as_str())
+            }
+            _ => None,
+        }
+    }
+
+    /// Swaps kprobes for fentry and kretprobes for fexit. These read their
+    /// arguments through BTF, so they're typed and cheaper to run, but only
+    /// work on kernels built with BTF.
+    pub fn with_btf(self) -> Probe {
+        let probe_type = match self.probe_type {
+            ProbeType::Kprobe => ProbeType::Fentry,
+            ProbeType::Kretprobe => ProbeType::Fexit,
+            p => p,
+        };
+        Probe {
+            probe_type,
+            parts: self.parts,
+        }
+    }
+
+    /// The (binary, function) pair for uprobes and uretprobes, e.g.
+    /// `uprobe."/usr/bin/bash".readline` is ("/usr/bin/bash", "readline").
+    pub fn user_function(&self) -> Option<(&str, &str)> {
+        match (&self.probe_type, self.parts.as_slice()) {
+            (ProbeType::Uprobe | ProbeType::Uretprobe, [binary, function]) => {
+                Some((binary, function))
+            }
+            _ => None,
+        }
+    }
+
+    /// The (binary, provider, name) for usdt probes, the provider is optional,
+    /// e.g. `usdt."/usr/lib/libpython3.so".python.function__entry`.
+    pub fn usdt(&self) -> Option<(&str, Option<&str>, &str)> {
+        match (&self.probe_type, self.parts.as_slice()) {
+            (ProbeType::Usdt, [binary, provider, name]) => Some((binary, Some(provider), name)),
+            (ProbeType::Usdt, [binary, name]) => Some((binary, None, name)),
+            _ => None,
+        }
+    }
+
+    /// The (category, event) pair for tracepoints.
+    pub fn tracepoint(&self) -> Option<(&str, &str)> {
+        match (&self.probe_type, self.parts.as_slice()) {
+            (ProbeType::Tracepoint, [category, event]) => Some((category, event)),
+            _ => None,
+        }
+    }
+
+    /// The (unit, count) of profile and interval probes, e.g. `profile.hz.99`
+    /// is ("hz", 99).
+    pub fn rate(&self) -> Option<(&str, u64)> {
+        match (&self.probe_type, self.parts.as_slice()) {
+            (ProbeType::Profile | ProbeType::Interval, [unit, count])
+                if matches!(unit.as_str(), "hz" | "s" | "ms" | "us") =>
+            {
+                count.parse().ok().filter(|n| *n > 0).map(|n| (unit.as_str(), n))
+            }
+            _ => None,
+        }
+    }
+
+    /// The syscall for `syscalls.<name>`, None for every syscall.
+    pub fn syscall(&self) -> Option<&str> {
+        match (&self.probe_type, self.parts.as_slice()) {
+            (ProbeType::Syscalls, [name]) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The probes that start and finish a relation that's made up of two
+    /// events, e.g. a syscall's entry and exit.
+    pub fn paired(&self) -> Option<(Probe, Probe)> {
+        let tracepoint = |category: &str, event: String| Probe {
+            probe_type: ProbeType::Tracepoint,
+            parts: vec![category.to_string(), event],
+        };
+        match self.probe_type {
+            ProbeType::Syscalls => Some(match self.syscall() {
+                Some(name) => (
+                    tracepoint("syscalls", format!("sys_enter_{}", name)),
+                    tracepoint("syscalls", format!("sys_exit_{}", name)),
+                ),
+                None => (
+                    tracepoint("raw_syscalls", "sys_enter".to_string()),
+                    tracepoint("raw_syscalls", "sys_exit".to_string()),
+                ),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The probe as bpftrace takes it, with fentry and fexit spelled kfunc
+    /// and kretfunc for a bpftrace that only knows those.
+    pub fn bpftrace(&self, kfunc: bool) -> String {
+        let probe_type = match self.probe_type {
+            ProbeType::Fentry if kfunc => "kfunc",
+            ProbeType::Fexit if kfunc => "kretfunc",
+            ref p => p.name(),
+        };
+        let mut probe = probe_type.to_string();
+        for part in &self.parts {
+            probe.push(':');
+            probe.push_str(part);
+        }
+        probe
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.bpftrace(false))
+    }
+}
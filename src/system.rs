@@ -0,0 +1,211 @@
+use crate::catalog;
+use crate::constants;
+use crate::functions;
+use crate::views;
+use crate::vmlinux;
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection};
+use serde_json::Value;
+use std::sync::Mutex;
+
+// The system.* tables, built in memory from the catalog, vmlinux.h, the
+// bundled constants, views and functions, the user's own views and functions
+// and whatever `bpftrace -l` said on the host. Queries against them are plain
+// sqlite and never go near bpftrace.
+const SCHEMA: &str = "
+CREATE TABLE probes (probe TEXT PRIMARY KEY, type TEXT, name TEXT);
+CREATE TABLE columns (probe TEXT, position INTEGER, name TEXT, c_type TEXT, sql_type TEXT);
+CREATE TABLE structs (name TEXT, position INTEGER, member TEXT, c_type TEXT, sql_type TEXT);
+CREATE TABLE constants (name TEXT, value INTEGER, source TEXT);
+CREATE TABLE views (name TEXT PRIMARY KEY, description TEXT, sql TEXT, source TEXT);
+CREATE TABLE functions (name TEXT PRIMARY KEY, args TEXT, body TEXT, description TEXT, source TEXT);
+CREATE TABLE hosts (name TEXT PRIMARY KEY, destination TEXT, groups TEXT, labels TEXT);
+CREATE VIEW information_schema_tables AS
+    SELECT type AS table_schema, replace(probe, ':', '.') AS table_name FROM probes;
+CREATE VIEW information_schema_columns AS
+    SELECT replace(probe, ':', '.') AS table_name, name AS column_name,
+           position AS ordinal_position, sql_type AS data_type FROM columns;
+";
+
+pub const TABLES: [&str; 9] = [
+    "probes",
+    "columns",
+    "structs",
+    "constants",
+    "views",
+    "functions",
+    "hosts",
+    "information_schema_tables",
+    "information_schema_columns",
+];
+
+// built the first time somebody asks, rebuilt when the host's probes come in
+// or the user's views change
+static SYSTEM: Mutex<Option<Connection>> = Mutex::new(None);
+static HOST_PROBES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+// name, destination, groups and labels of the hosts in bpfquery.toml
+type HostRow = (String, Option<String>, String, String);
+static HOSTS: Mutex<Vec<HostRow>> = Mutex::new(Vec::new());
+
+fn build() -> rusqlite::Result<Connection> {
+    let mut conn = Connection::open_in_memory()?;
+    conn.execute_batch(SCHEMA)?;
+    let tx = conn.transaction()?;
+    {
+        let mut probe = tx.prepare("INSERT OR IGNORE into probes values (?, ?, ?)")?;
+        let mut column = tx.prepare("INSERT into columns values (?, ?, ?, ?, ?)")?;
+        let mut member = tx.prepare("INSERT into structs values (?, ?, ?, ?, ?)")?;
+        let mut constant = tx.prepare("INSERT into constants values (?, ?, ?)")?;
+
+        for f in catalog::functions() {
+            let kprobe = format!("kprobe:{}", f.name);
+            probe.execute(params![kprobe, "kprobe", f.name])?;
+            for (i, arg) in f.args.iter().enumerate() {
+                column.execute(params![
+                    kprobe,
+                    i,
+                    arg.name,
+                    arg.c_type,
+                    catalog::sql_type(&arg.c_type)
+                ])?;
+            }
+            let kretprobe = format!("kretprobe:{}", f.name);
+            probe.execute(params![kretprobe, "kretprobe", f.name])?;
+            let sql_type = f.return_type.as_deref().map(catalog::sql_type);
+            column.execute(params![kretprobe, 0, "retval", f.return_type, sql_type])?;
+        }
+
+        for (category, event, fields) in catalog::tracepoints() {
+            let tracepoint = format!("tracepoint:{}:{}", category, event);
+            probe.execute(params![tracepoint, "tracepoint", event])?;
+            let fields = fields.iter().filter(|f| !f.name.starts_with("common_"));
+            for (i, field) in fields.enumerate() {
+                column.execute(params![
+                    tracepoint,
+                    i,
+                    field.name,
+                    field.c_type,
+                    catalog::sql_type(&field.c_type)
+                ])?;
+            }
+        }
+
+        for p in HOST_PROBES.lock().unwrap().iter() {
+            let probe_type = p.split(':').next().unwrap_or_default();
+            let name = p.rsplit(':').next().unwrap_or_default();
+            probe.execute(params![p, probe_type, name])?;
+        }
+
+        for s in vmlinux::structs() {
+            let name = if s.is_union {
+                format!("union {}", s.name)
+            } else {
+                s.name.clone()
+            };
+            for (i, m) in s.members.iter().enumerate() {
+                member.execute(params![
+                    name,
+                    i,
+                    m.name,
+                    m.c_type,
+                    catalog::sql_type(&m.c_type)
+                ])?;
+            }
+        }
+
+        for (name, value, source) in constants::constants() {
+            constant.execute(params![name, value, source])?;
+        }
+
+        let mut view = tx.prepare("INSERT into views values (?, ?, ?, ?)")?;
+        for v in views::BUILTIN_VIEWS {
+            view.execute(params![v.name, v.description, v.sql, "builtin"])?;
+        }
+        for (name, sql) in catalog::user_views() {
+            view.execute(params![name, None::<String>, sql, "user"])?;
+        }
+
+        let mut function = tx.prepare("INSERT into functions values (?, ?, ?, ?, ?)")?;
+        for f in functions::BUILTIN_FUNCTIONS {
+            let source = match f.kind {
+                functions::FunctionKind::Bpftrace => "bpftrace",
+                functions::FunctionKind::Rewritten => "rewritten",
+                functions::FunctionKind::Userspace => "userspace",
+            };
+            function.execute(params![f.name, f.args, None::<String>, f.description, source])?;
+        }
+        // made before a builtin of the same name came along, the builtin wins
+        let mut user_function =
+            tx.prepare("INSERT OR IGNORE into functions values (?, ?, ?, ?, ?)")?;
+        for f in functions::user_functions() {
+            user_function.execute(params![f.name, f.args.join(", "), f.body, None::<String>, "user"])?;
+        }
+
+        let mut host = tx.prepare("INSERT into hosts values (?, ?, ?, ?)")?;
+        for (name, destination, groups, labels) in HOSTS.lock().unwrap().iter() {
+            host.execute(params![name, destination, groups, labels])?;
+        }
+    }
+    tx.commit()?;
+    // compile_system_query only lets selects through, this makes sure
+    conn.pragma_update(None, "query_only", true)?;
+    Ok(conn)
+}
+
+fn with_system<T>(f: impl FnOnce(&Connection) -> rusqlite::Result<T>) -> Result<T, String> {
+    let mut system = SYSTEM.lock().unwrap();
+    if system.is_none() {
+        *system = Some(build().map_err(|e| e.to_string())?);
+    }
+    f(system.as_ref().unwrap()).map_err(|e| e.to_string())
+}
+
+/// Remembers the probes `bpftrace -l` listed on the host.
+pub fn set_host_probes(probes: Vec<String>) {
+    *HOST_PROBES.lock().unwrap() = probes;
+    reset();
+}
+
+/// Remembers the hosts in bpfquery.toml.
+pub fn set_hosts(hosts: Vec<HostRow>) {
+    *HOSTS.lock().unwrap() = hosts;
+    reset();
+}
+
+/// Rebuilds the tables the next time they're queried.
+pub fn reset() {
+    *SYSTEM.lock().unwrap() = None;
+}
+
+/// The names of the columns a query against the system tables returns.
+pub fn headers(sql: &str) -> Result<Vec<String>, String> {
+    with_system(|conn| {
+        let stmt = conn.prepare(sql)?;
+        Ok(stmt.column_names().iter().map(|c| c.to_string()).collect())
+    })
+}
+
+fn to_json(v: ValueRef) -> Value {
+    match v {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => Value::from(i),
+        ValueRef::Real(f) => Value::from(f),
+        ValueRef::Text(t) | ValueRef::Blob(t) => {
+            Value::String(String::from_utf8_lossy(t).into_owned())
+        }
+    }
+}
+
+/// Runs a query against the system tables.
+pub fn query(sql: &str) -> Result<Vec<Vec<Value>>, String> {
+    with_system(|conn| {
+        let mut stmt = conn.prepare(sql)?;
+        let n = stmt.column_count();
+        let rows = stmt.query_map([], |row| {
+            (0..n)
+                .map(|i| row.get_ref(i).map(to_json))
+                .collect::<rusqlite::Result<Vec<Value>>>()
+        })?;
+        rows.collect()
+    })
+}
@@ -0,0 +1,167 @@
+use crate::constants;
+use serde_json::Value;
+
+/// Work done on a column after bpftrace hands it back, for things that are
+/// easy in userspace and hard or impossible in bpf.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transform {
+    /// Turns a negative return value into the name of the errno, e.g. -2 into
+    /// ENOENT. Anything that isn't an error becomes null.
+    ErrnoName,
+    /// Names the bits set in a flags value using one of constants::FLAG_SETS,
+    /// e.g. 0o1101 as open_flags is O_WRONLY|O_CREAT|O_TRUNC.
+    DecodeFlags(String),
+    /// Turns a syscall number into its name, e.g. 257 into openat.
+    SyscallName,
+    Lower,
+    Upper,
+    /// The number of characters in a string.
+    Length,
+    /// Part of a string, the start counts from 1 like in sql.
+    Substr(i64, Option<i64>),
+    /// Joins a tuple from bpftrace into one string, nulls are left out.
+    Concat,
+    /// Splits a kstack or ustack, which bpftrace prints as one frame a line,
+    /// into a list of frames with the innermost first.
+    Stack,
+    /// The host a row came from, filled in as the row is read since the
+    /// same program runs everywhere.
+    Host,
+}
+
+fn text(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+impl Transform {
+    pub fn apply(&self, v: Value) -> Value {
+        match self {
+            Transform::ErrnoName => match v.as_i64() {
+                Some(n) if (-constants::MAX_ERRNO..0).contains(&n) => {
+                    match constants::errno_name(-n) {
+                        Some(name) => Value::String(name.to_string()),
+                        None => Value::String(format!("E{}", -n)),
+                    }
+                }
+                _ => Value::Null,
+            },
+            Transform::DecodeFlags(set) => match (v.as_i64(), constants::flag_set(set)) {
+                (Some(n), Some(set)) => Value::String(constants::decode_flags(n, set)),
+                _ => v,
+            },
+            Transform::SyscallName => match v.as_i64() {
+                Some(n) => match constants::syscall_name(n) {
+                    Some(name) => Value::String(name.to_string()),
+                    None => Value::String(format!("syscall_{}", n)),
+                },
+                None => v,
+            },
+            Transform::Lower => match v {
+                Value::String(s) => Value::String(s.to_lowercase()),
+                v => v,
+            },
+            Transform::Upper => match v {
+                Value::String(s) => Value::String(s.to_uppercase()),
+                v => v,
+            },
+            Transform::Length => match v {
+                Value::Null => v,
+                v => Value::from(text(&v).chars().count()),
+            },
+            Transform::Substr(start, length) => match v {
+                Value::Null => v,
+                v => {
+                    // like sql, a start before 1 eats into the length
+                    let skip = (*start - 1).max(0) as usize;
+                    let take = match length {
+                        Some(l) => (l + (*start - 1).min(0)).max(0) as usize,
+                        None => usize::MAX,
+                    };
+                    Value::String(text(&v).chars().skip(skip).take(take).collect())
+                }
+            },
+            Transform::Concat => match v {
+                Value::Array(vs) => Value::String(
+                    vs.iter()
+                        .filter(|v| !v.is_null())
+                        .map(text)
+                        .collect::<String>(),
+                ),
+                Value::Null => v,
+                v => Value::String(text(&v)),
+            },
+            Transform::Stack => match v {
+                Value::String(s) => Value::Array(
+                    s.lines()
+                        .map(|l| l.trim())
+                        .filter(|l| !l.is_empty())
+                        .map(|l| Value::String(l.to_string()))
+                        .collect(),
+                ),
+                v => v,
+            },
+            // the executor puts the host in, see executor::run_on_host
+            Transform::Host => v,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn errno_names() {
+        assert_eq!(Transform::ErrnoName.apply(json!(-2)), json!("ENOENT"));
+        assert_eq!(Transform::ErrnoName.apply(json!(3)), Value::Null);
+        assert_eq!(Transform::ErrnoName.apply(json!(0)), Value::Null);
+    }
+
+    #[test]
+    fn flags() {
+        let open_flags = Transform::DecodeFlags("open_flags".to_string());
+        assert_eq!(open_flags.apply(json!(0o1101)), json!("O_WRONLY|O_CREAT|O_TRUNC"));
+        let unknown = Transform::DecodeFlags("no_such_flags".to_string());
+        assert_eq!(unknown.apply(json!(3)), json!(3));
+    }
+
+    #[test]
+    fn syscall_names() {
+        assert_eq!(Transform::SyscallName.apply(json!(257)), json!("openat"));
+        assert_eq!(Transform::SyscallName.apply(json!(100000)), json!("syscall_100000"));
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(Transform::Lower.apply(json!("BaSh")), json!("bash"));
+        assert_eq!(Transform::Upper.apply(json!("BaSh")), json!("BASH"));
+        assert_eq!(Transform::Upper.apply(json!(1)), json!(1));
+        assert_eq!(Transform::Length.apply(json!("héllo")), json!(5));
+        assert_eq!(Transform::Length.apply(Value::Null), Value::Null);
+    }
+
+    #[test]
+    fn substr() {
+        assert_eq!(Transform::Substr(2, Some(3)).apply(json!("bpftrace")), json!("pft"));
+        assert_eq!(Transform::Substr(5, None).apply(json!("bpftrace")), json!("race"));
+        // like sql, starting at 0 takes one off the length
+        assert_eq!(Transform::Substr(0, Some(3)).apply(json!("bpftrace")), json!("bp"));
+        assert_eq!(Transform::Substr(-5, Some(3)).apply(json!("bpftrace")), json!(""));
+    }
+
+    #[test]
+    fn concat() {
+        assert_eq!(Transform::Concat.apply(json!(["a", null, 1, "b"])), json!("a1b"));
+        assert_eq!(Transform::Concat.apply(Value::Null), Value::Null);
+    }
+
+    #[test]
+    fn stacks() {
+        let stack = json!("\n\tdo_sys_open+0\n\t__x64_sys_openat+32\n");
+        assert_eq!(Transform::Stack.apply(stack), json!(["do_sys_open+0", "__x64_sys_openat+32"]));
+    }
+}
@@ -0,0 +1,119 @@
+// Views that ship with bpfquery, modeled after the bcc and bpftrace tools,
+// and the ones users make with CREATE VIEW, which are kept in bpfquery.db.
+// They're plain bpfquery sql and get inlined into whatever query uses them,
+// so `SELECT * FROM tcp_connect WHERE dport = 443` filters on the probe.
+use crate::catalog;
+use crate::system;
+
+/// A named query that can be selected from like a probe.
+#[derive(Debug, Clone)]
+pub struct View {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+pub const BUILTIN_VIEWS: &[View] = &[
+    View {
+        name: "process_exec",
+        description: "Every program that gets exec'd, like execsnoop",
+        sql: "SELECT comm, pid, uid, args.old_pid AS old_pid, str(args.filename) AS filename
+              FROM tracepoint.sched.sched_process_exec",
+    },
+    View {
+        name: "file_opens",
+        description: "Every file opened with openat, like opensnoop",
+        sql: "SELECT comm, pid, filename, flags, retval AS fd, errno, duration
+              FROM syscalls.openat",
+    },
+    View {
+        name: "tcp_connect",
+        description: "Outgoing TCP connections as they're attempted, like tcpconnect",
+        sql: "SELECT comm, pid, args.family AS family,
+                  CASE WHEN args.family = AF_INET THEN ntop(args.saddr) ELSE ntop(args.saddr_v6) END AS saddr,
+                  CASE WHEN args.family = AF_INET THEN ntop(args.daddr) ELSE ntop(args.daddr_v6) END AS daddr,
+                  args.sport AS sport, args.dport AS dport
+              FROM tracepoint.sock.inet_sock_set_state
+              WHERE args.newstate = TCP_SYN_SENT AND args.protocol = IPPROTO_TCP",
+    },
+    View {
+        name: "tcp_retransmit",
+        description: "TCP segments that had to be sent again, like tcpretrans",
+        sql: "SELECT args.family AS family,
+                  CASE WHEN args.family = AF_INET THEN ntop(args.saddr) ELSE ntop(args.saddr_v6) END AS saddr,
+                  CASE WHEN args.family = AF_INET THEN ntop(args.daddr) ELSE ntop(args.daddr_v6) END AS daddr,
+                  args.sport AS sport, args.dport AS dport, args.state AS state
+              FROM tracepoint.tcp.tcp_retransmit_skb",
+    },
+    View {
+        name: "block_io",
+        description: "Block device requests from issue to completion, like biosnoop",
+        sql: "SELECT issue.comm AS comm, issue.pid AS pid, args.dev AS dev, args.sector AS sector,
+                  args.nr_sector * 512 AS bytes, args.rwbs AS rwbs, nsecs - issue.nsecs AS latency
+              FROM tracepoint.block.block_rq_issue AS issue
+              JOIN tracepoint.block.block_rq_complete AS done
+                  ON issue.args.dev = done.args.dev AND issue.args.sector = done.args.sector",
+    },
+    View {
+        name: "oom_kills",
+        description: "Processes killed by the OOM killer, like oomkill",
+        sql: "SELECT comm, pid, args.oc -> chosen -> pid AS victim_pid,
+                  str(args.oc -> chosen -> comm) AS victim_comm,
+                  args.oc -> totalpages AS total_pages
+              FROM kprobe.oom_kill_process",
+    },
+    View {
+        name: "sched_latency",
+        description: "How long woken up tasks wait for a cpu, like runqlat",
+        sql: "SELECT args.next_pid AS pid, args.next_comm AS comm, cpu, nsecs - wake.nsecs AS latency
+              FROM tracepoint.sched.sched_wakeup AS wake
+              JOIN tracepoint.sched.sched_switch AS run ON wake.args.pid = run.args.next_pid",
+    },
+];
+
+pub fn view(name: &str) -> Option<&'static View> {
+    BUILTIN_VIEWS.iter().find(|v| v.name == name)
+}
+
+/// The sql behind a view, built-in or made with CREATE VIEW.
+pub fn view_sql(name: &str) -> Option<String> {
+    match view(name) {
+        Some(v) => Some(v.sql.to_string()),
+        None => catalog::user_view(name),
+    }
+}
+
+/// Whether a view called name can be made.
+pub fn check_new_view(name: &str, or_replace: bool) -> Result<(), String> {
+    if view(name).is_some() {
+        return Err(format!("{} is a built-in view", name));
+    }
+    if !or_replace && catalog::user_view(name).is_some() {
+        return Err(format!(
+            "View {} already exists, use CREATE OR REPLACE VIEW to change it",
+            name
+        ));
+    }
+    Ok(())
+}
+
+pub fn create_view(name: &str, sql: &str, or_replace: bool) -> Result<String, String> {
+    check_new_view(name, or_replace)?;
+    catalog::save_user_view(name, sql, or_replace)?;
+    system::reset();
+    Ok(format!("Created view {}", name))
+}
+
+pub fn drop_view(name: &str, if_exists: bool) -> Result<String, String> {
+    if view(name).is_some() {
+        return Err(format!("{} is a built-in view and can't be dropped", name));
+    }
+    match catalog::delete_user_view(name)? {
+        true => {
+            system::reset();
+            Ok(format!("Dropped view {}", name))
+        }
+        false if if_exists => Ok(format!("There was no view {}", name)),
+        false => Err(format!("Unknown view {}", name)),
+    }
+}
@@ -0,0 +1,170 @@
+use std::sync::OnceLock;
+
+// Generated with `bpftool btf dump file /sys/kernel/btf/vmlinux format c`, so
+// every definition is laid out the same way: top level definitions start at
+// the beginning of a line, members are one per line and nested anonymous
+// structs and unions get their own braces.
+const VMLINUX_H: &str = "vmlinux.h";
+
+/// A struct or union member, anonymous nested members are flattened in since
+/// that's how they get accessed.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    pub c_type: String,
+}
+
+/// A struct or union from vmlinux.h.
+#[derive(Debug, Clone)]
+pub struct Struct {
+    pub name: String,
+    pub is_union: bool,
+    pub members: Vec<Member>,
+}
+
+/// An enum from vmlinux.h, anonymous enums have no name.
+#[derive(Debug, Clone)]
+pub struct Enum {
+    pub name: Option<String>,
+    pub values: Vec<(String, i64)>,
+}
+
+fn parse_member(line: &str) -> Option<Member> {
+    let decl = line.trim().trim_end_matches(';');
+    // bitfields, `unsigned int flags: 3`
+    let decl = decl.split(':').next()?.trim();
+
+    // function pointers, `void (*func)(struct callback_head *)`
+    if let Some(start) = decl.find("(*") {
+        let end = start + decl[start..].find(')')?;
+        return Some(Member {
+            name: decl[start + 2..end].trim().to_string(),
+            c_type: format!("{} (*){}", decl[..start].trim(), &decl[end + 1..]),
+        });
+    }
+
+    // arrays, `char comm[16]`
+    let (decl, array) = match decl.find('[') {
+        Some(i) => (&decl[..i], &decl[i..]),
+        None => (decl, ""),
+    };
+    let split = decl.rfind([' ', '*']).map(|i| i + 1)?;
+    Some(Member {
+        name: decl[split..].to_string(),
+        c_type: format!("{}{}", decl[..split].trim(), array),
+    })
+}
+
+fn parse_structs(header: &str) -> Vec<Struct> {
+    let mut structs = Vec::new();
+    let mut current: Option<Struct> = None;
+    // the anonymous structs/unions we're inside of, so `} u;` can be named
+    let mut nested: Vec<&str> = Vec::new();
+
+    for line in header.lines() {
+        let Some(s) = current.as_mut() else {
+            let start = line
+                .strip_prefix("struct ")
+                .map(|r| (r, false))
+                .or(line.strip_prefix("union ").map(|r| (r, true)));
+            if let Some((rest, is_union)) = start {
+                if let Some(name) = rest.strip_suffix(" {") {
+                    current = Some(Struct {
+                        name: name.to_string(),
+                        is_union,
+                        members: vec![],
+                    });
+                }
+            }
+            continue;
+        };
+
+        let trimmed = line.trim();
+        // the closing brace of the definition is the only thing not indented,
+        // it can have attributes after it, `} __attribute__((packed));`
+        if line.starts_with('}') {
+            structs.extend(current.take());
+            nested.clear();
+        } else if trimmed.ends_with('{') {
+            nested.push(if trimmed.starts_with("union") {
+                "union"
+            } else {
+                "struct"
+            });
+        } else if let Some(rest) = trimmed.strip_prefix('}') {
+            let kind = nested.pop().unwrap_or("struct");
+            let rest = rest.trim().trim_end_matches(';');
+            let name = rest.rsplit("))").next().unwrap_or_default().trim();
+            if !name.is_empty() {
+                s.members.push(Member {
+                    name: name.to_string(),
+                    c_type: kind.to_string(),
+                });
+            }
+        } else if trimmed.ends_with(';') {
+            s.members.extend(parse_member(trimmed));
+        }
+    }
+    structs
+}
+
+// `NAME = 123,`, bpftool always writes the value out but 64 bit enums get a
+// ULL suffix
+fn parse_enum_value(line: &str) -> Option<(String, i64)> {
+    let (name, value) = line.trim().trim_end_matches(',').split_once(" = ")?;
+    let value = value.trim_end_matches(['U', 'L']);
+    let value = value
+        .parse::<i64>()
+        .ok()
+        .or_else(|| value.parse::<u64>().ok().map(|v| v as i64))?;
+    Some((name.to_string(), value))
+}
+
+fn parse_enums(header: &str) -> Vec<Enum> {
+    let mut enums = Vec::new();
+    let mut current: Option<Enum> = None;
+
+    for line in header.lines() {
+        let Some(e) = current.as_mut() else {
+            if let Some(rest) = line.strip_prefix("enum ").and_then(|r| r.strip_suffix('{')) {
+                let name = rest.trim();
+                current = Some(Enum {
+                    name: (!name.is_empty()).then(|| name.to_string()),
+                    values: vec![],
+                });
+            }
+            continue;
+        };
+        if line.starts_with('}') {
+            enums.extend(current.take());
+        } else {
+            e.values.extend(parse_enum_value(line));
+        }
+    }
+    enums
+}
+
+fn header() -> Option<&'static str> {
+    static HEADER: OnceLock<Option<String>> = OnceLock::new();
+    HEADER
+        .get_or_init(|| match std::fs::read_to_string(VMLINUX_H) {
+            Ok(header) => Some(header),
+            Err(e) => {
+                println!("Error reading {}: {}", VMLINUX_H, e);
+                None
+            }
+        })
+        .as_deref()
+}
+
+/// Every struct and union in vmlinux.h, empty if it isn't around.
+pub fn structs() -> &'static [Struct] {
+    static STRUCTS: OnceLock<Vec<Struct>> = OnceLock::new();
+    STRUCTS.get_or_init(|| header().map(parse_structs).unwrap_or_default())
+}
+
+/// Every enum in vmlinux.h, empty if it isn't around.
+pub fn enums() -> &'static [Enum] {
+    static ENUMS: OnceLock<Vec<Enum>> = OnceLock::new();
+    ENUMS.get_or_init(|| header().map(parse_enums).unwrap_or_default())
+}
@@ -5,18 +5,28 @@ use std::sync::{
     Arc,
 };
 
-use crate::bpftrace_compiler::compile_ast_to_bpftrace;
-use crate::executor::execute_bpf;
-use crate::parser::parse_bpfquery_sql;
+use crate::bpftrace_compiler::{compile_ast_to_bpftrace, needs_target, CompiledQuery, Program, Target};
+use crate::catalog;
+use crate::executor::{execute_bpf, HostHists, HostStatus, QueryEvent};
+use crate::hosts::Fleet;
+use crate::parser::{parse_bpfquery_sql, split_use};
 
 use futures_util::{SinkExt, StreamExt, TryFutureExt};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tokio::runtime::Handle;
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, oneshot, RwLock};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use warp::ws::{Message, WebSocket};
 use warp::Filter;
 
+/// Sent when the user asks for the query to be run, e.g. with ctrl+enter,
+/// everything else is the sql as it's typed.
+#[derive(Deserialize)]
+struct ExecuteMsg {
+    execute: String,
+}
+
 #[derive(Serialize, Clone)]
 struct BpftraceOutputMsg {
     output: String,
@@ -39,6 +49,8 @@ enum ResponseData {
     Output(BpftraceOutputMsg),
     Error(BpftraceErrorMsg),
     Results(BpftraceResults),
+    Status(HostStatus),
+    Hist(HostHists),
 }
 
 #[derive(Serialize, Clone)]
@@ -57,9 +69,20 @@ static NEXT_USER_ID: AtomicUsize = AtomicUsize::new(1);
 /// - Value is a sender of `warp::ws::Message`
 type Users = Arc<RwLock<HashMap<usize, mpsc::UnboundedSender<Message>>>>;
 
-pub async fn start_server(hostname: String, demo: bool) {
+pub async fn start_server(fleet: Arc<Fleet>, demo: bool, browse_binaries: bool) {
     pretty_env_logger::init();
 
+    // connect to the default host before anybody is waiting on it
+    if let Err(e) = compile(&fleet, DEFAULT_QUERY).await {
+        println!("{}", e);
+    }
+
+    // system.probes gets filled in once the host says what it has
+    let f = fleet.clone();
+    tokio::task::spawn(async move {
+        f.load_system_tables().await;
+    });
+
     // Keep track of all connected users, key is usize, value
     // is a websocket sender.
     let users = Users::default();
@@ -73,14 +96,39 @@ pub async fn start_server(hostname: String, demo: bool) {
         .and(warp::ws())
         .and(users)
         .map(move |ws: warp::ws::Ws, users| {
-            let h = hostname.clone();
+            let f = fleet.clone();
             // This will call our function if the handshake succeeds.
-            ws.on_upgrade(move |socket| user_connected(h, socket, users, demo))
+            ws.on_upgrade(move |socket| user_connected(f, socket, users, demo))
+        });
+
+    // GET /catalog/functions?path=... and /catalog/usdt?path=... -> what can
+    // be probed in a binary, for autocompletion. Anyone who can reach the
+    // server can ask, so unless --browse-binaries it only answers for
+    // binaries a query has already used
+    let catalog = warp::path!("catalog" / String)
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |kind: String, query: HashMap<String, String>| async move {
+            let path = query.get("path").cloned().unwrap_or_default();
+            // reading a binary can take a while, keep it off the runtime
+            let found = tokio::task::spawn_blocking(move || {
+                if !browse_binaries && !catalog::binary_indexed(&path) {
+                    return serde_json::Value::Array(vec![]);
+                }
+                let found = match kind.as_str() {
+                    "functions" => serde_json::to_value(catalog::binary_functions(&path)),
+                    "usdt" => serde_json::to_value(catalog::binary_usdt_probes(&path)),
+                    _ => Ok(serde_json::Value::Array(vec![])),
+                };
+                found.unwrap_or_default()
+            })
+            .await
+            .unwrap_or_default();
+            Ok::<_, warp::Rejection>(warp::reply::json(&found))
         });
 
     let static_files = warp::fs::dir("static");
 
-    let routes = static_files.or(editor);
+    let routes = static_files.or(editor).or(catalog);
 
     warp::serve(routes).run(([0, 0, 0, 0], 3030)).await;
     let metrics = Handle::current().metrics();
@@ -88,7 +136,38 @@ pub async fn start_server(hostname: String, demo: bool) {
     dbg!(metrics.num_alive_tasks());
 }
 
-async fn user_connected(hostname: String, ws: WebSocket, users: Users, demo: bool) {
+// what runs when somebody first connects, before they've typed anything
+const DEFAULT_QUERY: &str = "select
+      str(args.path -> dentry -> d_name.name) as filename
+  from
+      kprobe.vfs_open;";
+
+/// Compiles sql for the hosts it's going to run on, which are given back
+/// with it.
+async fn compile(fleet: &Fleet, sql: &str) -> Result<(CompiledQuery, Vec<String>), String> {
+    let ast = parse_bpfquery_sql(sql).map_err(|e| e.to_string())?;
+    let (name, ast) = split_use(ast)?;
+    let hosts = fleet.resolve(name.as_deref())?;
+    // only what bpftrace runs depends on the hosts, the rest works with
+    // them down
+    let target = match ast.first().is_some_and(needs_target) {
+        true => fleet.common_target(&hosts).await?,
+        false => Target::default(),
+    };
+    // a uprobe's binary gets read for its functions the first time, which
+    // can take a while for something like libc, keep it off the runtime
+    let query = tokio::task::spawn_blocking(move || compile_ast_to_bpftrace(ast, target))
+        .await
+        .map_err(|e| e.to_string())??;
+    Ok((query, hosts))
+}
+
+async fn user_connected(
+    fleet: Arc<Fleet>,
+    ws: WebSocket,
+    users: Users,
+    demo: bool,
+) {
     // Use a counter to assign a new unique ID for this user.
     let my_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
 
@@ -124,21 +203,29 @@ async fn user_connected(hostname: String, ws: WebSocket, users: Users, demo: boo
     // Return a `Future` that is basically a state machine managing
     // this specific user's connection.
 
-    let sql = "select
-      str(args.path -> dentry -> d_name.name) as filename
-  from
-      kprobe.vfs_open;"
-        .to_string();
-    let ast = parse_bpfquery_sql(&sql).unwrap();
-    let (mut output, mut headers) = compile_ast_to_bpftrace(ast).unwrap();
+    // the host may not be able to run anything, e.g. its bpftrace is too
+    // old, the user still gets told why
+    let (mut query, mut hosts) = match compile(&fleet, DEFAULT_QUERY).await {
+        Ok((query, hosts)) => (Some(query), hosts),
+        Err(e) => {
+            println!("error compiling the default query: {}", e);
+            send_error(my_id, &users, e).await;
+            (None, vec![])
+        }
+    };
     let (mut results_sender, mut results_reciver) = tokio::sync::broadcast::channel(10000);
-    let h = hostname.clone();
-    let hds = headers.clone();
-    let ot = output.clone();
-    let d = demo.clone();
+    let (mut stop, stop_receiver) = oneshot::channel();
+    let f = fleet.clone();
+    let q = query.clone();
+    let h = hosts.clone();
+    let d = demo;
     let mut t = tokio::task::spawn(async move {
-        execute_bpf(h, hds, ot, results_sender, d).await;
+        if let Some(q) = q {
+            run_query(&f, &h, q, results_sender, d, stop_receiver).await;
+        }
     });
+    // the query finished, the session carries on until the next one
+    let mut finished = false;
 
     // Every time the user sends a message, broadcast it to
     // all other users...
@@ -146,7 +233,9 @@ async fn user_connected(hostname: String, ws: WebSocket, users: Users, demo: boo
         tokio::task::yield_now().await;
         tokio::select! {
            _ = tokio::signal::ctrl_c() => {
-               t.abort();
+               // let bpftrace stop before going
+               let _ = stop.send(());
+               let _ = t.await;
                //TODO, ctrl-c does not work here at all
                std::process::exit(1);
            }
@@ -159,44 +248,62 @@ async fn user_connected(hostname: String, ws: WebSocket, users: Users, demo: boo
                           break;
                       }
                   };
-                  let response = user_message(my_id, msg, &users).await;
-
-                  if let Some(ResponseMessage {
-                      data:
-                          ResponseData::Output(BpftraceOutputMsg {
-                              output: new_output,
-                              headers: new_headers,
-                          }),
-                      msg_type: _,
-                  }) = response.clone()
-                  {
-                      //only restart task if the output or headers have changed
-                      if new_output != output || new_headers != headers {
-                          output = new_output;
-                          headers = new_headers;
+                  let new_query = user_message(my_id, msg, &users, &fleet).await;
+
+                  if let Some((new_query, new_hosts, execute)) = new_query {
+                      //only restart task if the query has changed, definitions
+                      //wait until they're asked for so half typed ones don't stick
+                      let run = match new_query.program {
+                          Program::Define(_) => execute,
+                          _ => execute || Some(&new_query) != query.as_ref() || new_hosts != hosts,
+                      };
+                      if run {
+                          query = Some(new_query.clone());
+                          hosts = new_hosts;
                           (results_sender, results_reciver) = tokio::sync::broadcast::channel(10000);
-                          t.abort();
-                          let h = hostname.clone();
-                          let hds = headers.clone();
-                          let ot = output.clone();
-                          let d = demo.clone();
+                          // the old query stops in the background, aborting
+                          // it could leave bpftrace running on the host
+                          finished = false;
+                          let (new_stop, stop_receiver) = oneshot::channel();
+                          let _ = std::mem::replace(&mut stop, new_stop).send(());
+                          let f = fleet.clone();
+                          let q = new_query;
+                          let h = hosts.clone();
+                          let d = demo;
                           t = tokio::task::spawn(async move {
-                              execute_bpf(h, hds, ot, results_sender, d).await;
+                              run_query(&f, &h, q, results_sender, d, stop_receiver).await;
                           });
                       }
                   }
+              } else {
+                  // they closed the page
+                  break;
               }
           }
-          data = results_reciver.recv() => {
-              if let Ok(data) = data {
-                  if !data.is_empty() && data[0] == "DONE" {
-                      break;
+          event = results_reciver.recv(), if !finished => {
+              let response = match event {
+                  // the query is over, not the session, wait for the next one
+                  Ok(QueryEvent::Done) | Err(RecvError::Closed) => {
+                      finished = true;
+                      None
                   }
+                  Err(RecvError::Lagged(_)) => None,
+                  Ok(QueryEvent::Row(data)) => Some(ResponseMessage {
+                      data: ResponseData::Results(BpftraceResults { results: data }),
+                      msg_type: "bpftrace_results".to_string(),
+                  }),
+                  Ok(QueryEvent::Status(status)) => Some(ResponseMessage {
+                      data: ResponseData::Status(status),
+                      msg_type: "host_status".to_string(),
+                  }),
+                  Ok(QueryEvent::Hist(hists)) => Some(ResponseMessage {
+                      data: ResponseData::Hist(hists),
+                      msg_type: "bpftrace_hist".to_string(),
+                  }),
+                  Ok(QueryEvent::Error(e)) => Some(error_message(e)),
+              };
+              if let Some(response) = response {
                   if let Some(tx) = users.read().await.get(&my_id) {
-                      let response = ResponseMessage {
-                          data: ResponseData::Results(BpftraceResults { results: data }),
-                          msg_type: "bpftrace_results".to_string(),
-                      };
                       let response_string = serde_json::to_string(&response).unwrap();
                       if let Err(_disconnected) = tx.send(Message::text(response_string.clone())) {
                           println!("error sending message to user: {}", my_id);
@@ -209,42 +316,42 @@ async fn user_connected(hostname: String, ws: WebSocket, users: Users, demo: boo
     }
     // user_ws_rx stream will keep processing as long as the user stays
     // connected. Once they disconnect, then...
-    t.abort();
+    let _ = stop.send(());
     tt.abort();
     user_disconnected(my_id, &users).await;
 }
 
-async fn user_message(my_id: usize, msg: Message, users: &Users) -> Option<ResponseMessage> {
+async fn user_message(
+    my_id: usize,
+    msg: Message,
+    users: &Users,
+    fleet: &Fleet,
+) -> Option<(CompiledQuery, Vec<String>, bool)> {
     // Skip any non-Text messages...
     let msg = if let Ok(s) = msg.to_str() {
         s
     } else {
         return None;
     };
+    let (msg, execute) = match serde_json::from_str::<ExecuteMsg>(msg) {
+        Ok(m) => (m.execute, true),
+        Err(_) => (msg.to_string(), false),
+    };
 
-    let result = parse_bpfquery_sql(msg);
-    let response = match result {
-        Ok(ast) => {
-            let result2 = compile_ast_to_bpftrace(ast);
-            match result2 {
-                Ok((output, headers)) => ResponseMessage {
-                    data: ResponseData::Output(BpftraceOutputMsg { output, headers }),
-                    msg_type: "bpftrace_output".to_string(),
-                },
-                Err(e) => ResponseMessage {
-                    data: ResponseData::Error(BpftraceErrorMsg {
-                        error_message: e.to_string(),
-                    }),
-                    msg_type: "bpftrace_error".to_string(),
-                },
-            }
+    let mut compiled = None;
+    let response = match compile(fleet, &msg).await {
+        Ok((query, hosts)) => {
+            let response = ResponseMessage {
+                data: ResponseData::Output(BpftraceOutputMsg {
+                    output: query.program.to_string(),
+                    headers: query.headers.clone(),
+                }),
+                msg_type: "bpftrace_output".to_string(),
+            };
+            compiled = Some((query, hosts));
+            response
         }
-        Err(e) => ResponseMessage {
-            data: ResponseData::Error(BpftraceErrorMsg {
-                error_message: e.to_string(),
-            }),
-            msg_type: "bpftrace_error".to_string(),
-        },
+        Err(e) => error_message(e),
     };
 
     //make it only reload on changes
@@ -260,7 +367,43 @@ async fn user_message(my_id: usize, msg: Message, users: &Users) -> Option<Respo
             //TODO abort the task
         }
     }
-    Some(response)
+    compiled.map(|(query, hosts)| (query, hosts, execute))
+}
+
+// runs the query on the hosts, or says why it can't
+async fn run_query(
+    fleet: &Fleet,
+    hosts: &[String],
+    query: CompiledQuery,
+    results_sender: tokio::sync::broadcast::Sender<QueryEvent>,
+    demo: bool,
+    stop: oneshot::Receiver<()>,
+) {
+    let executors = hosts
+        .iter()
+        .map(|h| fleet.executor(h).map(|e| (h.clone(), e)))
+        .collect::<Result<Vec<_>, String>>();
+    match executors {
+        Ok(executors) => execute_bpf(executors, query, results_sender, demo, stop).await,
+        Err(e) => {
+            let _ = results_sender.send(QueryEvent::Error(e));
+            let _ = results_sender.send(QueryEvent::Done);
+        }
+    }
+}
+
+fn error_message(e: String) -> ResponseMessage {
+    ResponseMessage {
+        data: ResponseData::Error(BpftraceErrorMsg { error_message: e }),
+        msg_type: "bpftrace_error".to_string(),
+    }
+}
+
+async fn send_error(my_id: usize, users: &Users, e: String) {
+    let response_string = serde_json::to_string(&error_message(e)).unwrap();
+    if let Some(tx) = users.read().await.get(&my_id) {
+        let _ = tx.send(Message::text(response_string));
+    }
 }
 
 async fn user_disconnected(my_id: usize, users: &Users) {
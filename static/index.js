@@ -64,6 +64,52 @@ async function reload_perspective() {
 
 let rows = [];
 
+// how each host running the query is getting on, by host
+let host_statuses = {};
+
+function show_host_statuses() {
+  let list = document.getElementById("host-status");
+  list.replaceChildren();
+  for (const [host, status] of Object.entries(host_statuses)) {
+    let item = document.createElement("li");
+    item.innerText = host + ": " + status.state + (status.message ? " - " + status.message : "");
+    if (status.state == "failed") {
+      item.classList.add("text-red-700");
+    }
+    list.appendChild(item);
+  }
+}
+
+// the hist() and lhist() maps the hosts printed, by host and map name
+let hists = {};
+
+function bucket_label(b) {
+  if (b.min == null) return "(..., " + b.max + "]";
+  if (b.max == null) return "[" + b.min + ", ...)";
+  return "[" + b.min + ", " + b.max + "]";
+}
+
+function hist_text(buckets) {
+  let most = Math.max(1, ...buckets.map((b) => b.count));
+  return buckets
+    .map((b) => bucket_label(b).padEnd(24) + String(b.count).padStart(8) + " |" + "@".repeat(Math.round((b.count / most) * 40)))
+    .join("\n");
+}
+
+function show_hists() {
+  let text = [];
+  for (const [name, hist] of Object.entries(hists)) {
+    if (Array.isArray(hist)) {
+      text.push(name + ":\n" + hist_text(hist));
+    } else {
+      for (const [key, buckets] of Object.entries(hist)) {
+        text.push(name + "[" + key + "]:\n" + hist_text(buckets));
+      }
+    }
+  }
+  document.getElementById("hists").innerText = text.join("\n\n");
+}
+
 ws.onmessage = async function (msg) {
   //check if editor has focus
   let focused = editor.hasTextFocus();
@@ -81,25 +127,42 @@ ws.onmessage = async function (msg) {
     bpfv.classList.add("bg-gray-200");
     bpfv.classList.remove("bg-red-200");
     rows = [];
+    host_statuses = {};
+    show_host_statuses();
+    hists = {};
+    show_hists();
+    reset_stacks();
     await reload_perspective();
   } else if (d.msg_type == "bpftrace_error") {
     bpfv.innerText = d.error_message;
     bpfv.classList.add("bg-red-200");
     bpfv.classList.remove("bg-gray-200");
+  } else if (d.msg_type == "host_status") {
+    host_statuses[d.host] = d;
+    show_host_statuses();
+  } else if (d.msg_type == "bpftrace_hist") {
+    for (const [name, hist] of Object.entries(d.hists)) {
+      hists[d.host + " " + name] = hist;
+    }
+    show_hists();
   } else if (d.msg_type == "bpftrace_results") {
     if (d.results.length == 0) {
       return;
     }
     // transform results into a perspective table
-    let data = {};
+    let raw = {};
     for (let i = 0; i < headers.length; i++) {
-      data[headers[i]] = d.results[i];
+      raw[headers[i]] = d.results[i];
     }
+    let data = add_stack_row(raw);
     rows.push(data);
     if (first_load) {
       table = await worker.table(rows, { index: "id" });
       elem.load(table);
       first_load = false;
+    } else {
+      // rows with an id that's already there (GROUP BY results) replace it
+      table.update([data]);
     }
     if (showing_example) {
       elem.restore(examples[example_selector.value].config);
@@ -118,13 +181,249 @@ editor.getModel().onDidChangeContent((event) => {
   sendSql();
 });
 
+// CREATE VIEW and friends only happen when asked for
+editor.addCommand(monaco.KeyMod.CtrlCmd | monaco.KeyCode.Enter, () => {
+  ws.send(JSON.stringify({ execute: editor.getValue() }));
+});
+
+// Stacks come back as lists of frames, innermost first. The table shows them
+// folded into one line, the flamegraph and the download use the frames.
+let stack_rows = new Map();
+let stack_header = null;
+let zoomed = [];
+
+function reset_stacks() {
+  stack_rows = new Map();
+  stack_header = null;
+  zoomed = [];
+  document.getElementById("stack_toolbar").classList.add("hidden");
+  show_flamegraph(false);
+}
+
+// root first and without offsets, so that the same function merges
+function folded_frames(stack) {
+  return stack
+    .slice()
+    .reverse()
+    .map((f) => f.replace(/\+(0x)?[0-9a-f]+(?= |$)/, "").replace(/;/g, ":"));
+}
+
+// what the row for perspective looks like
+function add_stack_row(raw) {
+  let data = {};
+  for (let h of headers) {
+    if (Array.isArray(raw[h])) {
+      if (stack_header === null) {
+        stack_header = h;
+        document.getElementById("stack_toolbar").classList.remove("hidden");
+      }
+      data[h] = folded_frames(raw[h]).join(";");
+    } else {
+      data[h] = raw[h];
+    }
+  }
+  if (stack_header !== null) {
+    // GROUP BY rows come again with the same id as they change
+    stack_rows.set(raw.id, raw);
+    schedule_flamegraph();
+  }
+  return data;
+}
+
+// count(*) for GROUP BY kstack, otherwise every row is one sample
+function stack_weight(row) {
+  for (let h of headers) {
+    if (h.startsWith("count") && typeof row[h] == "number") {
+      return row[h];
+    }
+  }
+  return 1;
+}
+
+function folded_stacks() {
+  let counts = new Map();
+  for (let row of stack_rows.values()) {
+    let stack = row[stack_header];
+    if (!Array.isArray(stack) || stack.length == 0) {
+      continue;
+    }
+    let key = folded_frames(stack).join(";");
+    counts.set(key, (counts.get(key) || 0) + stack_weight(row));
+  }
+  return counts;
+}
+
+// the format flamegraph.pl, speedscope and friends read
+function download_folded() {
+  let lines = [];
+  for (let [stack, n] of folded_stacks()) {
+    lines.push(stack + " " + n);
+  }
+  let blob = new Blob([lines.join("\n") + "\n"], { type: "text/plain" });
+  let a = document.createElement("a");
+  a.href = URL.createObjectURL(blob);
+  a.download = "stacks.folded";
+  a.click();
+  URL.revokeObjectURL(a.href);
+}
+
+function frame_color(name) {
+  let hash = 0;
+  for (let i = 0; i < name.length; i++) {
+    hash = (hash * 31 + name.charCodeAt(i)) | 0;
+  }
+  return "hsl(" + (Math.abs(hash) % 50) + ", 80%, 60%)";
+}
+
+function render_flamegraph() {
+  let root = { name: "all", value: 0, children: new Map() };
+  for (let [stack, n] of folded_stacks()) {
+    let node = root;
+    root.value += n;
+    for (let frame of stack.split(";")) {
+      if (!node.children.has(frame)) {
+        node.children.set(frame, { name: frame, value: 0, children: new Map() });
+      }
+      node = node.children.get(frame);
+      node.value += n;
+    }
+  }
+  // clicking a frame zooms into it, clicking the bottom one zooms back out
+  for (let frame of zoomed) {
+    if (!root.children.has(frame)) {
+      zoomed = [];
+      return render_flamegraph();
+    }
+    root = root.children.get(frame);
+  }
+
+  let container = document.getElementById("flamegraph");
+  container.innerHTML = "";
+  const row_height = 18;
+  let depth = 0;
+  let draw = (node, level, left, width, path) => {
+    if (width < 0.1) {
+      return;
+    }
+    depth = Math.max(depth, level + 1);
+    let div = document.createElement("div");
+    div.className =
+      "absolute overflow-hidden whitespace-nowrap text-xs px-1 border border-white cursor-pointer";
+    div.style.left = left + "%";
+    div.style.width = width + "%";
+    div.style.bottom = level * row_height + "px";
+    div.style.height = row_height + "px";
+    div.style.background = frame_color(node.name);
+    div.title = node.name + " (" + node.value + " samples)";
+    div.innerText = node.name;
+    div.onclick = () => {
+      zoomed = path;
+      render_flamegraph();
+    };
+    container.appendChild(div);
+    let child_left = left;
+    for (let child of node.children.values()) {
+      let child_width = (width * child.value) / node.value;
+      draw(child, level + 1, child_left, child_width, path.concat([child.name]));
+      child_left += child_width;
+    }
+  };
+  draw(root, 0, 0, 100, zoomed.slice(0, -1));
+  container.style.height = depth * row_height + "px";
+}
+
+let flamegraph_pending = false;
+function schedule_flamegraph() {
+  let view = document.getElementById("flamegraph_view");
+  if (flamegraph_pending || view.classList.contains("hidden")) {
+    return;
+  }
+  flamegraph_pending = true;
+  requestAnimationFrame(() => {
+    flamegraph_pending = false;
+    render_flamegraph();
+  });
+}
+
+function show_flamegraph(show) {
+  let view = document.getElementById("flamegraph_view");
+  let viewer = document.getElementsByTagName("perspective-viewer")[0];
+  view.classList.toggle("hidden", !show);
+  viewer.style.display = show ? "none" : "";
+  document.getElementById("flamegraph_toggle").innerText = show
+    ? "Table"
+    : "Flamegraph";
+  if (show) {
+    render_flamegraph();
+  }
+}
+
+document.getElementById("flamegraph_toggle").addEventListener("click", () => {
+  show_flamegraph(document.getElementById("flamegraph_view").classList.contains("hidden"));
+});
+document.getElementById("folded_download").addEventListener("click", download_folded);
+
+async function fetch_catalog(kind, path) {
+  let response = await fetch(
+    "/catalog/" + kind + "?path=" + encodeURIComponent(path)
+  );
+  return await response.json();
+}
+
 monaco.languages.registerCompletionItemProvider("sql", {
-  provideCompletionItems: (model, position) => {
-    // const suggestions = keywords.map(keyword => ({
-    //     label: keyword,
-    //     kind: monaco.languages.CompletionItemKind.Keyword,
-    //     insertText: keyword
-    // }));
+  triggerCharacters: ["."],
+  provideCompletionItems: async (model, position) => {
+    let before = model.getValueInRange({
+      startLineNumber: 1,
+      startColumn: 1,
+      endLineNumber: position.lineNumber,
+      endColumn: position.column,
+    });
+    let word = model.getWordUntilPosition(position);
+    let range = {
+      startLineNumber: position.lineNumber,
+      endLineNumber: position.lineNumber,
+      startColumn: word.startColumn,
+      endColumn: word.endColumn,
+    };
+    let suggestion = (label, insertText, detail) => ({
+      label: label,
+      kind: monaco.languages.CompletionItemKind.Function,
+      insertText: insertText,
+      detail: detail,
+      range: range,
+    });
+
+    // uprobe."/usr/bin/bash".<function>
+    let m = before.match(/u(?:ret)?probe\."([^"]+)"\.\w*$/);
+    if (m) {
+      let functions = await fetch_catalog("functions", m[1]);
+      return { suggestions: functions.map((f) => suggestion(f, f)) };
+    }
+
+    // usdt."/usr/lib/libpython3.so".<provider>.<name>
+    m = before.match(/usdt\."([^"]+)"\.(\w+)\.\w*$/);
+    if (m) {
+      let provider = m[2];
+      let probes = await fetch_catalog("usdt", m[1]);
+      return {
+        suggestions: probes
+          .filter((p) => p.provider == provider)
+          .map((p) => suggestion(p.name, p.name, p.arguments)),
+      };
+    }
+
+    // usdt."/usr/lib/libpython3.so".<provider.name>
+    m = before.match(/usdt\."([^"]+)"\.\w*$/);
+    if (m) {
+      let probes = await fetch_catalog("usdt", m[1]);
+      return {
+        suggestions: probes.map((p) =>
+          suggestion(p.provider + "." + p.name, p.provider + "." + p.name, p.arguments)
+        ),
+      };
+    }
+
     return { suggestions: [] };
   },
 });
@@ -213,6 +512,66 @@ from kprobe.vfs_open;`,
       aggregates: {},
     },
   },
+  tcp_connect: {
+    sql: `--tcp_connect is one of the built-in views (select * from system.views), it picks the probe, the filter and the ntop() calls for you.
+select * from tcp_connect where dport = 443;`,
+    config: {
+      version: "3.0.1",
+      plugin: "Datagrid",
+      plugin_config: { columns: {}, edit_mode: "READ_ONLY", scroll_lock: true },
+      columns_config: {},
+      settings: false,
+      theme: "Pro Light",
+      title: null,
+      group_by: [],
+      split_by: [],
+      columns: ["id", "comm", "pid", "saddr", "daddr", "dport"],
+      filter: [],
+      sort: [],
+      expressions: {},
+      aggregates: {},
+    },
+  },
+  sched_latency: {
+    sql: `--sched_latency joins sched_wakeup with sched_switch, latency is how long a task waited for a cpu in nanoseconds.
+select comm, count(*), avg(latency), max(latency) from sched_latency group by comm;`,
+    config: {
+      version: "3.0.1",
+      plugin: "Datagrid",
+      plugin_config: { columns: {}, edit_mode: "READ_ONLY", scroll_lock: true },
+      columns_config: {},
+      settings: false,
+      theme: "Pro Light",
+      title: null,
+      group_by: [],
+      split_by: [],
+      columns: ["comm", "count(*)", "avg(latency)", "max(latency)"],
+      filter: [],
+      sort: [],
+      expressions: {},
+      aggregates: {},
+    },
+  },
+  profile: {
+    sql: `--kstack and ustack are lists of frames, with GROUP BY and count(*) they make a flamegraph (the button above the table) or a folded stacks file for flamegraph.pl.
+select kstack, count(*) from profile.hz.99 group by kstack;`,
+    config: {
+      version: "3.0.1",
+      plugin: "Datagrid",
+      plugin_config: { columns: {}, edit_mode: "READ_ONLY", scroll_lock: true },
+      columns_config: {},
+      settings: false,
+      theme: "Pro Light",
+      title: null,
+      group_by: [],
+      split_by: [],
+      columns: ["count(*)", "kstack"],
+      filter: [],
+      sort: [["count(*)", "desc"]],
+      expressions: {},
+      aggregates: {},
+    },
+  },
 };
 
 var example_selector = document.getElementById("example_selector");